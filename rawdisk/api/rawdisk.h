@@ -3,6 +3,13 @@
 #include <windows.h>
 #include <stdint.h>
 
-HANDLE open_disk(char *disk);
-int64_t read_from_disk(HANDLE h, char *buf, int64_t size);
-void close_disk(HANDLE h);
\ No newline at end of file
+HANDLE open_disk(char *disk, int32_t direct, int32_t write);
+int64_t read_from_disk(HANDLE h, char *buf, int64_t size, DWORD *bytes_read);
+int64_t read_from_disk_at(HANDLE h, char *buf, int64_t size, int64_t offset, DWORD *bytes_read);
+int64_t write_to_disk_at(HANDLE h, const char *buf, int64_t size, int64_t offset, DWORD *bytes_written);
+int64_t flush_disk(HANDLE h);
+void reset_disk(HANDLE h);
+int64_t get_disk_size(HANDLE h, int64_t *size);
+int64_t get_sector_size(HANDLE h, int32_t *size);
+int32_t get_last_error();
+void close_disk(HANDLE h);
@@ -95,6 +95,11 @@ fn main() {
     let host = env::var("HOST").expect("Cargo build scripts always have HOST");
     let target_os = get_os_from_triple(target.as_str()).unwrap();
 
+    // Only the windows backend is written in c, linux is pure rust
+    if !target_os.starts_with("windows") {
+        return;
+    }
+
     let source_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
 
     let compiled_path: PathBuf = compile(source_path.as_path(), target_os);
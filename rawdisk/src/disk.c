@@ -1,19 +1,20 @@
 #define _CRT_SECURE_NO_WARNINGS
 #define WIN32_LEAN_AND_MEAN
 #include <windows.h>
+#include <winioctl.h>
 #include <stdint.h>
 #include <stdio.h>
 
-HANDLE open_disk(char *disk)
+HANDLE open_disk(char *disk, int32_t direct, int32_t write)
 {
     printf("Opening disk -%s-\n", disk);
     HANDLE handle = CreateFile(
         disk,
-        GENERIC_READ,
+        write ? GENERIC_READ | GENERIC_WRITE : GENERIC_READ,
         FILE_SHARE_READ | FILE_SHARE_WRITE,
         NULL, // No security attributes
         OPEN_EXISTING,
-        0,
+        direct ? FILE_FLAG_NO_BUFFERING : 0,
         NULL
     );
     return handle;
@@ -24,11 +25,64 @@ int64_t read_from_disk(HANDLE h, char *buf, int64_t size, DWORD *bytes_read)
     return ReadFile(h, buf, size, bytes_read, NULL);
 }
 
+int64_t read_from_disk_at(HANDLE h, char *buf, int64_t size, int64_t offset, DWORD *bytes_read)
+{
+    OVERLAPPED o = {0};
+    o.Offset = (DWORD)offset;
+    o.OffsetHigh = (DWORD)(offset >> 32);
+    return ReadFile(h, buf, size, bytes_read, &o);
+}
+
+int64_t write_to_disk_at(HANDLE h, const char *buf, int64_t size, int64_t offset, DWORD *bytes_written)
+{
+    OVERLAPPED o = {0};
+    o.Offset = (DWORD)offset;
+    o.OffsetHigh = (DWORD)(offset >> 32);
+    return WriteFile(h, buf, size, bytes_written, &o);
+}
+
+int64_t flush_disk(HANDLE h)
+{
+    return FlushFileBuffers(h);
+}
+
 void reset_disk(HANDLE h)
 {
     SetFilePointer(h, 0, 0, 0);
 }
 
+int64_t get_disk_size(HANDLE h, int64_t *size)
+{
+    GET_LENGTH_INFORMATION info;
+    DWORD returned;
+    int64_t code = DeviceIoControl(h, IOCTL_DISK_GET_LENGTH_INFO, NULL, 0, &info, sizeof(info), &returned, NULL);
+    if (code)
+    {
+        *size = info.Length.QuadPart;
+        return code;
+    }
+    // Regular files are not devices, their size is the size of the file
+    LARGE_INTEGER length;
+    code = GetFileSizeEx(h, &length);
+    if (code)
+    {
+        *size = length.QuadPart;
+    }
+    return code;
+}
+
+int64_t get_sector_size(HANDLE h, int32_t *size)
+{
+    DISK_GEOMETRY geometry;
+    DWORD returned;
+    int64_t code = DeviceIoControl(h, IOCTL_DISK_GET_DRIVE_GEOMETRY, NULL, 0, &geometry, sizeof(geometry), &returned, NULL);
+    if (code)
+    {
+        *size = geometry.BytesPerSector;
+    }
+    return code;
+}
+
 int32_t get_last_error()
 {
     return GetLastError();
@@ -0,0 +1,209 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    os::unix::{
+        fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt},
+        io::{AsRawFd, RawFd},
+    },
+    path::PathBuf,
+};
+
+pub struct DiskLoad {
+    f: File,
+    path: PathBuf,
+}
+
+impl DiskLoad {
+    /// Open a block device such as `/dev/sda`, or any other file.
+    pub fn new(p: &PathBuf) -> std::io::Result<Self> {
+        Self::open(p, false)
+    }
+
+    /// Open a disk, `direct` bypasses the page cache. Direct reads must be
+    /// aligned to the sector size.
+    pub fn open(p: &PathBuf, direct: bool) -> std::io::Result<Self> {
+        Self::open_with(p, direct, false)
+    }
+
+    /// Open a disk for reading and writing, `direct` bypasses the page cache.
+    pub fn open_writable(p: &PathBuf, direct: bool) -> std::io::Result<Self> {
+        Self::open_with(p, direct, true)
+    }
+
+    fn open_with(p: &PathBuf, direct: bool, write: bool) -> std::io::Result<Self> {
+        let mut options = std::fs::OpenOptions::new();
+        options.read(true).write(write);
+        if direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        Ok(Self {
+            f: options.open(p)?,
+            path: p.clone(),
+        })
+    }
+
+    /// Read from the current position, starting over from the beginning
+    /// once the end of the disk is reached.
+    pub fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let amount = self.f.read(buf)?;
+        if amount == 0 {
+            self.reset();
+        }
+        Ok(amount)
+    }
+
+    /// Read from the given offset in bytes.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        self.f.read_at(buf, offset)
+    }
+
+    /// Write to the given offset in bytes, the disk must have been opened writable.
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        self.f.write_at(buf, offset)
+    }
+
+    /// Wait until everything written has reached the disk. With `data_only`
+    /// metadata that is not needed to read the data back, such as the
+    /// modification time, may not be written.
+    pub fn flush(&self, data_only: bool) -> std::io::Result<()> {
+        if data_only {
+            self.f.sync_data()
+        } else {
+            self.f.sync_all()
+        }
+    }
+
+    /// Go back to the start of the disk.
+    pub fn reset(&mut self) {
+        let _e = self.f.seek(SeekFrom::Start(0));
+    }
+
+    /// The size of the disk in bytes.
+    pub fn size(&self) -> std::io::Result<u64> {
+        // Seeking works for block devices, whose metadata has a length of zero
+        let mut f = &self.f;
+        let position = f.stream_position()?;
+        let size = f.seek(SeekFrom::End(0))?;
+        f.seek(SeekFrom::Start(position))?;
+        Ok(size)
+    }
+
+    /// The logical sector size, which direct reads must be aligned to. For
+    /// a regular file this is the sector size of the disk holding it.
+    pub fn sector_size(&self) -> std::io::Result<usize> {
+        let m = self.f.metadata()?;
+        let dev = if m.file_type().is_block_device() {
+            m.rdev()
+        } else {
+            m.dev()
+        };
+        let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+        let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+        // Partitions do not have a queue of their own, it belongs to the parent disk
+        let base = PathBuf::from(format!("/sys/dev/block/{}:{}", major, minor));
+        [base.join("queue"), base.join("../queue")]
+            .iter()
+            .find_map(|q| std::fs::read_to_string(q.join("logical_block_size")).ok())
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("No sector size found for {}", self.path.display()),
+                )
+            })
+    }
+}
+
+impl AsRawFd for DiskLoad {
+    fn as_raw_fd(&self) -> RawFd {
+        self.f.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file of `size` bytes counting up from zero, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, size: usize) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("rawdisk-{}-{}", std::process::id(), name));
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            std::fs::write(&path, data).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _e = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn opens_files() {
+        let f = TempFile::new("open", 16);
+        assert!(DiskLoad::new(&f.0).is_ok());
+        assert!(DiskLoad::open_writable(&f.0, false).is_ok());
+        let missing = std::env::temp_dir().join(format!("rawdisk-{}-missing", std::process::id()));
+        assert!(DiskLoad::new(&missing).is_err());
+    }
+
+    #[test]
+    fn writes_read_back() {
+        let f = TempFile::new("round_trip", 8192);
+        let d = DiskLoad::open_writable(&f.0, false).unwrap();
+        assert_eq!(d.write_at(&[0xab; 512], 4096).unwrap(), 512);
+        d.flush(true).unwrap();
+        let mut buf = [0; 1024];
+        assert_eq!(d.read_at(&mut buf, 3584).unwrap(), 1024);
+        assert!(buf[..512].iter().enumerate().all(|(i, b)| *b == i as u8));
+        assert!(buf[512..].iter().all(|b| *b == 0xab));
+        // Reading past the end reads nothing
+        assert_eq!(d.read_at(&mut buf, 8192).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_only_can_not_write() {
+        let f = TempFile::new("read_only", 16);
+        let d = DiskLoad::new(&f.0).unwrap();
+        assert!(d.write_at(&[1], 0).is_err());
+    }
+
+    #[test]
+    fn reads_start_over_at_the_end() {
+        let f = TempFile::new("wrap", 100);
+        let mut d = DiskLoad::new(&f.0).unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(d.read(&mut buf).unwrap(), 64);
+        assert_eq!(d.read(&mut buf).unwrap(), 36);
+        assert_eq!(d.read(&mut buf).unwrap(), 0);
+        assert_eq!(d.read(&mut buf).unwrap(), 64);
+        assert_eq!(buf[0], 0);
+    }
+
+    #[test]
+    fn size_keeps_the_position() {
+        let f = TempFile::new("size", 3000);
+        let mut d = DiskLoad::new(&f.0).unwrap();
+        let mut buf = [0; 10];
+        d.read(&mut buf).unwrap();
+        assert_eq!(d.size().unwrap(), 3000);
+        d.read(&mut buf).unwrap();
+        assert_eq!(buf[0], 10);
+    }
+
+    #[test]
+    fn sector_size_of_a_regular_file() {
+        // The sector size of the disk holding the file, filesystems such as
+        // tmpfs have no disk and leave the caller to pick a size
+        let f = TempFile::new("sector", 16);
+        match DiskLoad::new(&f.0).unwrap().sector_size() {
+            Ok(s) => assert!(s >= 512 && s.is_power_of_two(), "{}", s),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::path::PathBuf;
+use windows::Win32::Foundation::HANDLE;
+
+pub struct DiskLoad
+{
+    h: HANDLE,
+}
+
+fn last_error() -> std::io::Error {
+    std::io::Error::from_raw_os_error(unsafe { get_last_error() })
+}
+
+impl DiskLoad {
+    /// Open a disk, given the path it is mounted at such as `C:\`, or a file.
+    pub fn new(p: &PathBuf) -> std::io::Result<Self> {
+        Self::open(p, false)
+    }
+
+    /// Open a disk, `direct` bypasses the os cache. Direct reads must be
+    /// aligned to the sector size.
+    pub fn open(p: &PathBuf, direct: bool) -> std::io::Result<Self> {
+        Self::open_with(p, direct, false)
+    }
+
+    /// Open a disk for reading and writing, `direct` bypasses the os cache.
+    pub fn open_writable(p: &PathBuf, direct: bool) -> std::io::Result<Self> {
+        Self::open_with(p, direct, true)
+    }
+
+    fn open_with(p: &PathBuf, direct: bool, write: bool) -> std::io::Result<Self> {
+        let path = p.as_os_str().to_string_lossy().to_string();
+        // A volume is opened through its device, such as `\\.\C:` for `C:\`,
+        // anything else is a file that is opened as it is
+        let name = match path.strip_suffix('\\').unwrap_or(path.as_str()) {
+            volume if volume.len() == 2 && volume.ends_with(':') => format!("\\\\.\\{}", volume),
+            _ => path.clone(),
+        };
+        println!("Disk name is {}", name);
+        let cstr = std::ffi::CString::new(name.as_str())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let h = unsafe { open_disk(cstr.as_c_str().as_ptr(), direct as i32, write as i32) };
+        if h != windows::Win32::Foundation::INVALID_HANDLE_VALUE {
+            Ok(Self {
+                h,
+            })
+        }
+        else {
+            Err(last_error())
+        }
+    }
+
+    /// Read from the current position, starting over from the beginning
+    /// once the end of the disk is reached.
+    pub fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let b = buf.as_mut_ptr();
+        let mut amount: i32 = 0;
+        let code = unsafe { read_from_disk(self.h, b, buf.len() as i64, &mut amount as *mut i32) };
+        if code != 0 {
+            if amount == 0 {
+                self.reset();
+            }
+            Ok(amount as usize)
+        }
+        else {
+            Err(last_error())
+        }
+    }
+
+    /// Read from the given offset in bytes.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let b = buf.as_mut_ptr();
+        let mut amount: i32 = 0;
+        let code = unsafe {
+            read_from_disk_at(self.h, b, buf.len() as i64, offset as i64, &mut amount as *mut i32)
+        };
+        if code != 0 {
+            Ok(amount as usize)
+        }
+        else {
+            Err(last_error())
+        }
+    }
+
+    /// Write to the given offset in bytes, the disk must have been opened writable.
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+        let mut amount: i32 = 0;
+        let code = unsafe {
+            write_to_disk_at(self.h, buf.as_ptr(), buf.len() as i64, offset as i64, &mut amount as *mut i32)
+        };
+        if code != 0 {
+            Ok(amount as usize)
+        }
+        else {
+            Err(last_error())
+        }
+    }
+
+    /// Wait until everything written has reached the disk. Windows always
+    /// writes the metadata as well, so `data_only` makes no difference.
+    pub fn flush(&self, _data_only: bool) -> std::io::Result<()> {
+        if unsafe { flush_disk(self.h) } != 0 {
+            Ok(())
+        }
+        else {
+            Err(last_error())
+        }
+    }
+
+    /// Go back to the start of the disk.
+    pub fn reset(&mut self) {
+        unsafe { reset_disk(self.h) };
+    }
+
+    /// The size of the disk in bytes.
+    pub fn size(&self) -> std::io::Result<u64> {
+        let mut size: i64 = 0;
+        if unsafe { get_disk_size(self.h, &mut size) } != 0 {
+            Ok(size as u64)
+        }
+        else {
+            Err(last_error())
+        }
+    }
+
+    /// The logical sector size, which direct reads must be aligned to.
+    pub fn sector_size(&self) -> std::io::Result<usize> {
+        let mut size: i32 = 0;
+        if unsafe { get_sector_size(self.h, &mut size) } != 0 {
+            Ok(size as usize)
+        }
+        else {
+            Err(last_error())
+        }
+    }
+}
+
+impl Drop for DiskLoad {
+    fn drop(&mut self) {
+        unsafe { close_disk(self.h) };
+    }
+}
+
+extern "C" {
+    pub fn open_disk(disk: *const i8, direct: i32, write: i32) -> HANDLE;
+    pub fn read_from_disk(h: HANDLE, buf: *mut u8, size: i64, amount: *mut i32) -> i64;
+    pub fn read_from_disk_at(h: HANDLE, buf: *mut u8, size: i64, offset: i64, amount: *mut i32) -> i64;
+    pub fn write_to_disk_at(h: HANDLE, buf: *const u8, size: i64, offset: i64, amount: *mut i32) -> i64;
+    pub fn flush_disk(h: HANDLE) -> i64;
+    pub fn reset_disk(h: HANDLE);
+    pub fn get_disk_size(h: HANDLE, size: *mut i64) -> i64;
+    pub fn get_sector_size(h: HANDLE, size: *mut i32) -> i64;
+    pub fn close_disk(h: HANDLE);
+    pub fn get_last_error() -> i32;
+}
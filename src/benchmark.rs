@@ -3,22 +3,41 @@
 use criterion::Criterion;
 
 mod cpu;
+mod results;
+mod workload;
 
 pub fn bench1(c: &mut Criterion) {
     let mut group = c.benchmark_group("sse2 load");
     group.throughput(criterion::Throughput::Elements(96000000));
     group.bench_function("sse2", |b| {
-        b.iter(|| cpu::load_select(1000000));
+        b.iter(|| cpu::load_select(cpu::Isa::Sse2, 1000000));
     });
 
     group.bench_function("sse2_rust", |b| {
-        b.iter(|| cpu::rust_load_select(1000000));
+        b.iter(|| cpu::rust_load_select(cpu::Isa::Sse2, 1000000));
     });
 }
 
+pub fn bench_wide(c: &mut Criterion) {
+    for isa in [cpu::Isa::Avx, cpu::Isa::Fma3, cpu::Isa::Avx512] {
+        if !isa.supported() {
+            continue;
+        }
+        let Some((flops, _)) = cpu::rust_load_select(isa, 1) else {
+            continue;
+        };
+        let mut group = c.benchmark_group(format!("{} load", isa));
+        group.throughput(criterion::Throughput::Elements(flops as u64 * 1000000));
+        group.bench_function(isa.to_string(), |b| {
+            b.iter(|| cpu::rust_load_select(isa, 1000000));
+        });
+    }
+}
+
 fn benches() {
     let mut criterion = crate::Criterion::default().configure_from_args();
     bench1(&mut criterion);
+    bench_wide(&mut criterion);
 }
 
 fn main() {
@@ -0,0 +1,465 @@
+//! Command line parsing for the benchmark binary.
+
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    cpu::Isa,
+    disk::{DiskConfig, DiskEngine, DiskPattern, DiskSync},
+    diskjob::DiskJob,
+    diskverify::VerifyConfig,
+    netload::{self, NetConfig},
+    netproto,
+    nettcp::TcpConfig,
+};
+
+pub const USAGE: &str = "Usage: benchmark [options]
+
+Without --headless the graphical interface is started and the remaining
+options are ignored.
+
+Options:
+    --headless             Run without a window, printing results to stdout
+    --cpu [all|LIST]       Load cpu cores, LIST is comma separated (0,2,4-7)
+    --isa NAME             Instruction set for the cpu load, one of auto, sse2,
+                           avx, fma3 (or avx2), avx512, neon and sve
+                           (default auto)
+    --disk PATH            Load the given disk, partition or file (may be
+                           repeated). A directory is loaded by reading a test
+                           file created in it
+    --all-disks            Load every whole disk found on the system
+    --list-disks           List the disks and partitions that can be loaded
+    --file-size SIZE       Size of the test file created in a directory
+                           (default 1G)
+    --disk-pattern NAME    Read disks sequentially (seq) or at random
+                           offsets (rand) (default seq)
+    --block-size SIZE      Size of each disk read, with an optional K, M or G
+                           suffix (default 1M)
+    --buffered             Read disks through the page cache instead of using
+                           direct I/O
+    --engine NAME          How disk reads are issued, sync or io_uring
+                           (default sync)
+    --iodepth N            Reads kept outstanding by each io_uring job
+                           (default 1)
+    --jobs N               Threads reading each disk at the same time
+                           (default 1)
+    --sync MODE            Measure the latency of syncing small writes with
+                           fsync or fdatasync instead of read throughput.
+                           --disk paths are written, use --block-size to pick
+                           the size of each write
+    --job FILE             Run the disk jobs described in a fio job file, or
+                           the same options in a .toml file (may be
+                           repeated). Jobs that write need --scratch-device
+                           for anything that already exists. The
+                           longest runtime is used when --duration is not
+                           given
+    --write-verify PATH    Write patterns to PATH and read them back, checking
+                           for corruption (may be repeated). PATH should be a
+                           dedicated test file, it is created if needed. Only
+                           supported on Linux
+    --verify-size SIZE     How much of each PATH is written (default 1G)
+    --scratch-device       Confirm that --write-verify, --sync and --job may
+                           destroy the contents of paths that already exist,
+                           such as disks
+    --net [all|NAME]       Load the given network interface (may be repeated)
+    --server HOST[:PORT]   Load the listener at HOST, for listeners that can
+                           not be found from --net such as ones across a
+                           router (may be repeated). Servers are remembered
+    --known-servers        Load every server given before
+    --port PORT            Port that listeners are bound to (default 5003)
+    --local-port PORT      Port that udp loads on interfaces send from
+                           (default 5002)
+    --bitrate RATE         Send to each listener at RATE bits per second, with
+                           an optional k, M or G suffix (default unlimited)
+    --packet-size SIZE     Size of each packet sent to a listener (default
+                           1000)
+    --tcp [DIRECTION]      Transfer over tcp streams instead of sending udp
+                           probes, DIRECTION is upload, download or both
+                           (default upload)
+    --streams N            Tcp streams opened in each direction (default 1)
+    --duration SECONDS     How long to run the load for (default 10)
+    --output FILE          Also write the results to FILE
+    --json FILE            Export the results as json to FILE
+    --csv FILE             Export the samples as csv to FILE
+    --history DIR          Where runs are stored (default $BENCHMARK_HISTORY or
+                           a benchmark directory in the user data directory)
+    --save                 Save the run to the history
+    --baseline             Save the run and mark it as the baseline
+    --compare              Compare the run against the baseline, exiting with
+                           an error if anything regressed
+    --tolerance PERCENT    Changes smaller than this are ignored (default 5)
+    --help                 Show this message";
+
+/// Which cpu cores should be loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CpuSelection {
+    None,
+    All,
+    Cores(Vec<usize>),
+}
+
+/// Which network interfaces should be loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetSelection {
+    None,
+    All,
+    Interfaces(Vec<String>),
+}
+
+pub struct Options {
+    pub headless: bool,
+    pub help: bool,
+    pub cpu: CpuSelection,
+    pub isa: Isa,
+    pub disks: Vec<PathBuf>,
+    pub all_disks: bool,
+    pub list_disks: bool,
+    pub disk_config: DiskConfig,
+    /// Disk loads read from job files
+    pub disk_jobs: Vec<DiskJob>,
+    pub verify: Vec<PathBuf>,
+    pub verify_config: VerifyConfig,
+    /// Writing to things other than regular files has been confirmed
+    pub scratch_device: bool,
+    pub net: NetSelection,
+    /// Listeners given by hand as `host[:port]`
+    pub servers: Vec<String>,
+    /// Also load the listeners given in earlier runs
+    pub known_servers: bool,
+    /// The port that listeners are bound to
+    pub port: u16,
+    /// The port that udp loads on interfaces send from
+    pub local_port: u16,
+    pub net_config: NetConfig,
+    /// Load the network with tcp streams instead of udp probes
+    pub tcp: Option<TcpConfig>,
+    pub duration: Duration,
+    pub output: Option<PathBuf>,
+    pub json: Option<PathBuf>,
+    pub csv: Option<PathBuf>,
+    pub history: Option<PathBuf>,
+    pub save: bool,
+    pub baseline: bool,
+    pub compare: bool,
+    /// Percent change allowed before a result counts as a regression or improvement
+    pub tolerance: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            headless: false,
+            help: false,
+            cpu: CpuSelection::None,
+            isa: Isa::Auto,
+            disks: vec![],
+            all_disks: false,
+            list_disks: false,
+            disk_config: DiskConfig::default(),
+            disk_jobs: vec![],
+            verify: vec![],
+            verify_config: VerifyConfig::default(),
+            scratch_device: false,
+            net: NetSelection::None,
+            servers: vec![],
+            known_servers: false,
+            port: netproto::DEFAULT_PORT,
+            local_port: netload::DEFAULT_LOCAL_PORT,
+            net_config: NetConfig::default(),
+            tcp: None,
+            duration: Duration::from_secs(10),
+            output: None,
+            json: None,
+            csv: None,
+            history: None,
+            save: false,
+            baseline: false,
+            compare: false,
+            tolerance: 5.0,
+        }
+    }
+}
+
+/// Parse a list of cores such as `0,2,4-7`.
+fn parse_cores(list: &str) -> Result<Vec<usize>, String> {
+    let mut cores = vec![];
+    for part in list.split(',').filter(|p| !p.is_empty()) {
+        if let Some((a, b)) = part.split_once('-') {
+            let a: usize = a.parse().map_err(|_| format!("Invalid core {}", a))?;
+            let b: usize = b.parse().map_err(|_| format!("Invalid core {}", b))?;
+            if b < a {
+                return Err(format!("Invalid core range {}", part));
+            }
+            cores.extend(a..=b);
+        } else {
+            cores.push(part.parse().map_err(|_| format!("Invalid core {}", part))?);
+        }
+    }
+    Ok(cores)
+}
+
+/// Parse a size in bytes such as `4096`, `4k` or `1M`.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let (number, shift) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 10),
+        Some('M') => (&size[..size.len() - 1], 20),
+        Some('G') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size {}", size))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("Invalid size {}", size))
+}
+
+/// Parse a rate in bits per second such as `500k` or `1.5G`, the suffixes are decimal.
+fn parse_bitrate(rate: &str) -> Result<u64, String> {
+    let (number, scale) = match rate.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&rate[..rate.len() - 1], 1e3),
+        Some('M') => (&rate[..rate.len() - 1], 1e6),
+        Some('G') => (&rate[..rate.len() - 1], 1e9),
+        _ => (rate, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok((n * scale) as u64),
+        _ => Err(format!("Invalid bitrate {}", rate)),
+    }
+}
+
+/// Parse a port that must not be zero.
+fn parse_port(port: &str) -> Result<u16, String> {
+    match port.parse() {
+        Ok(p) if p > 0 => Ok(p),
+        _ => Err(format!("Invalid port {}", port)),
+    }
+}
+
+/// Parse a count that must be at least one.
+fn parse_count(count: &str) -> Result<usize, String> {
+    match count.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("Invalid count {}", count)),
+    }
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut o = Options::default();
+        let mut args = args.peekable();
+        let mut duration_given = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => o.headless = true,
+                "--help" | "-h" => o.help = true,
+                "--cpu" => {
+                    let value = args.next_if(|a| !a.starts_with("--"));
+                    o.cpu = match value.as_deref() {
+                        None | Some("all") => CpuSelection::All,
+                        Some(list) => CpuSelection::Cores(parse_cores(list)?),
+                    };
+                }
+                "--isa" => {
+                    let value = args.next().ok_or("--isa requires a value")?;
+                    o.isa = value.parse()?;
+                    if !o.isa.supported() {
+                        return Err(format!("{} is not supported by this cpu", o.isa));
+                    }
+                }
+                "--disk" => {
+                    let value = args.next().ok_or("--disk requires a path")?;
+                    o.disks.push(PathBuf::from(value));
+                }
+                "--all-disks" => o.all_disks = true,
+                "--list-disks" => o.list_disks = true,
+                "--file-size" => {
+                    let value = args.next().ok_or("--file-size requires a value")?;
+                    o.disk_config.file_size = parse_size(&value)?;
+                }
+                "--disk-pattern" => {
+                    let value = args.next().ok_or("--disk-pattern requires a value")?;
+                    o.disk_config.pattern = value.parse::<DiskPattern>()?;
+                }
+                "--block-size" => {
+                    let value = args.next().ok_or("--block-size requires a value")?;
+                    let size = parse_size(&value)?;
+                    if size == 0 || size > 1 << 30 {
+                        return Err(format!("Invalid block size {}", value));
+                    }
+                    o.disk_config.block_size = size as usize;
+                    o.verify_config.block_size = size as usize;
+                }
+                "--buffered" => o.disk_config.direct = false,
+                "--engine" => {
+                    let value = args.next().ok_or("--engine requires a value")?;
+                    o.disk_config.engine = value.parse::<DiskEngine>()?;
+                }
+                "--sync" => {
+                    let value = args.next().ok_or("--sync requires a value")?;
+                    o.disk_config.sync = Some(value.parse::<DiskSync>()?);
+                }
+                "--iodepth" => {
+                    let value = args.next().ok_or("--iodepth requires a value")?;
+                    o.disk_config.queue_depth = parse_count(&value)?;
+                }
+                "--jobs" => {
+                    let value = args.next().ok_or("--jobs requires a value")?;
+                    o.disk_config.jobs = parse_count(&value)?;
+                }
+                "--job" => {
+                    let value = args.next().ok_or("--job requires a filename")?;
+                    o.disk_jobs
+                        .extend(crate::diskjob::load(std::path::Path::new(&value))?);
+                }
+                "--write-verify" => {
+                    let value = args.next().ok_or("--write-verify requires a path")?;
+                    o.verify.push(PathBuf::from(value));
+                }
+                "--verify-size" => {
+                    let value = args.next().ok_or("--verify-size requires a value")?;
+                    o.verify_config.size = parse_size(&value)?;
+                }
+                "--scratch-device" => o.scratch_device = true,
+                "--net" => {
+                    let value = args.next_if(|a| !a.starts_with("--"));
+                    match value.as_deref() {
+                        None | Some("all") => o.net = NetSelection::All,
+                        Some(name) => match &mut o.net {
+                            NetSelection::Interfaces(list) => list.push(name.to_string()),
+                            NetSelection::All => {}
+                            NetSelection::None => {
+                                o.net = NetSelection::Interfaces(vec![name.to_string()])
+                            }
+                        },
+                    }
+                }
+                "--server" => {
+                    let value = args.next().ok_or("--server requires a host")?;
+                    o.servers.push(value);
+                }
+                "--known-servers" => o.known_servers = true,
+                "--port" => {
+                    let value = args.next().ok_or("--port requires a value")?;
+                    o.port = parse_port(&value)?;
+                }
+                "--local-port" => {
+                    let value = args.next().ok_or("--local-port requires a value")?;
+                    o.local_port = parse_port(&value)?;
+                }
+                "--bitrate" => {
+                    let value = args.next().ok_or("--bitrate requires a value")?;
+                    o.net_config.bitrate = Some(parse_bitrate(&value)?);
+                }
+                "--packet-size" => {
+                    let value = args.next().ok_or("--packet-size requires a value")?;
+                    let size = parse_size(&value)?;
+                    if size < netproto::PROBE_HEADER as u64 || size > netproto::MAX_PACKET as u64 {
+                        return Err(format!(
+                            "Packet size must be between {} and {}",
+                            netproto::PROBE_HEADER,
+                            netproto::MAX_PACKET
+                        ));
+                    }
+                    o.net_config.packet_size = size as usize;
+                }
+                "--tcp" => {
+                    let value = args.next_if(|a| !a.starts_with("--"));
+                    let tcp = o.tcp.get_or_insert_with(TcpConfig::default);
+                    if let Some(direction) = value {
+                        tcp.direction = direction.parse()?;
+                    }
+                }
+                "--streams" => {
+                    let value = args.next().ok_or("--streams requires a value")?;
+                    o.tcp.get_or_insert_with(TcpConfig::default).streams = parse_count(&value)?;
+                }
+                "--duration" => {
+                    let value = args.next().ok_or("--duration requires a value")?;
+                    let secs: f64 = value
+                        .parse()
+                        .map_err(|_| format!("Invalid duration {}", value))?;
+                    if secs.is_nan() || secs <= 0.0 {
+                        return Err(format!("Invalid duration {}", value));
+                    }
+                    o.duration = Duration::from_secs_f64(secs);
+                    duration_given = true;
+                }
+                "--output" => {
+                    let value = args.next().ok_or("--output requires a filename")?;
+                    o.output = Some(PathBuf::from(value));
+                }
+                "--json" => {
+                    let value = args.next().ok_or("--json requires a filename")?;
+                    o.json = Some(PathBuf::from(value));
+                }
+                "--csv" => {
+                    let value = args.next().ok_or("--csv requires a filename")?;
+                    o.csv = Some(PathBuf::from(value));
+                }
+                "--history" => {
+                    let value = args.next().ok_or("--history requires a directory")?;
+                    o.history = Some(PathBuf::from(value));
+                }
+                "--save" => o.save = true,
+                "--baseline" => o.baseline = true,
+                "--compare" => o.compare = true,
+                "--tolerance" => {
+                    let value = args.next().ok_or("--tolerance requires a value")?;
+                    o.tolerance = value
+                        .parse()
+                        .map_err(|_| format!("Invalid tolerance {}", value))?;
+                    if o.tolerance.is_nan() || o.tolerance < 0.0 {
+                        return Err(format!("Invalid tolerance {}", value));
+                    }
+                }
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+        if !duration_given {
+            if let Some(runtime) = o.disk_jobs.iter().filter_map(|j| j.config.runtime).max() {
+                o.duration = runtime;
+            }
+        }
+        if o.verify_config.size < o.verify_config.block_size as u64 {
+            return Err("--verify-size must be at least the block size".to_string());
+        }
+        Ok(o)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size("1M"), Ok(1 << 20));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert_eq!(parse_size("0"), Ok(0));
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        for size in ["", "k", "-1", "1.5M", "4T", "4 k", "99999999999999999G"] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn bitrates() {
+        assert_eq!(parse_bitrate("100"), Ok(100));
+        assert_eq!(parse_bitrate("500k"), Ok(500_000));
+        assert_eq!(parse_bitrate("10M"), Ok(10_000_000));
+        assert_eq!(parse_bitrate("1.5G"), Ok(1_500_000_000));
+    }
+
+    #[test]
+    fn invalid_bitrates() {
+        for rate in ["", "G", "0", "-5M", "inf", "nan", "fast"] {
+            assert!(parse_bitrate(rate).is_err(), "{}", rate);
+        }
+    }
+}
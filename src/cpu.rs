@@ -1,144 +1,158 @@
 //! The code here is adapted from https://github.com/Mysticial/Flops/tree/master
 
-use std::{thread::JoinHandle, time::Duration};
-
 use hwlocality::cpu::binding::CpuBindingFlags;
 
+use crate::workload::{Workload, WorkloadControl, WorkloadKind, WorkloadThread};
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::__m128d;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::__m128d;
+
 pub struct CpuLoadThread {
-    thread: JoinHandle<()>,
-    recv: std::sync::mpsc::Receiver<MessageFromCpuLoad>,
-    pub send: std::sync::mpsc::Sender<MessageToCpuLoad>,
-    pub performance: u64,
-    #[cfg(feature = "hwlocality")]
+    thread: WorkloadThread<MessageToCpuLoad, MessageFromCpuLoad>,
+    pub core: usize,
     pub associated: bool,
-    pub running: bool,
-    pub done: bool,
+    /// The instruction set in use
+    pub isa: Isa,
+    pub error: Option<String>,
 }
 
 pub enum MessageToCpuLoad {
     #[cfg(feature = "hwlocality")]
     Associate(hwlocality::Topology, hwlocality::cpu::cpusets::CpuSet),
-    Start,
-    Stop,
-    Exit,
+    SetIsa(Isa),
 }
 
 pub enum MessageFromCpuLoad {
-    Performance(u64, f64),
     Associated(bool),
-    Running(bool),
-    Done,
+    Isa(Isa),
+    /// There is no kernel for any instruction set the cpu supports
+    Error(String),
 }
 
 impl CpuLoadThread {
-    pub fn new() -> Self {
-        let (s, r) = std::sync::mpsc::channel();
-        let (s2, r2) = std::sync::mpsc::channel();
-        let thread = std::thread::spawn(move || {
+    pub fn new(core: usize, isa: Isa) -> Self {
+        let isa = isa.resolve();
+        let thread = WorkloadThread::spawn(move |mut ctx| {
+            let mut isa = isa;
             let mut num_cycles = 1000000;
-            let mut sum = 0.0;
-            let mut running = false;
-            let mut associated = false;
+            // Without hwlocality there is nothing to associate with
+            let mut associated = !cfg!(feature = "hwlocality");
             let clock = quanta::Clock::new();
             let time = 1.0;
-            'load: loop {
-                while let Ok(message) = r.try_recv() {
-                    match message {
-                        #[cfg(feature = "hwlocality")]
-                        MessageToCpuLoad::Associate(topology, cpuset) => {
-                            let r = topology.bind_cpu(&cpuset, CpuBindingFlags::THREAD).is_ok();
-                            associated = r;
-                            if s2.send(MessageFromCpuLoad::Associated(associated)).is_err() {
-                                break 'load;
-                            }
-                        }
-                        MessageToCpuLoad::Start => {
-                            running = true;
-                            if s2.send(MessageFromCpuLoad::Running(running)).is_err() {
-                                break 'load;
-                            }
-                        }
-                        MessageToCpuLoad::Stop => {
-                            running = false;
-                            if s2.send(MessageFromCpuLoad::Running(running)).is_err() {
-                                break 'load;
-                            }
-                        }
-                        MessageToCpuLoad::Exit => {}
-                    }
+            while ctx.poll(|message| match message {
+                #[cfg(feature = "hwlocality")]
+                MessageToCpuLoad::Associate(topology, cpuset) => {
+                    associated = topology.bind_cpu(&cpuset, CpuBindingFlags::THREAD).is_ok();
+                    Some(MessageFromCpuLoad::Associated(associated))
+                }
+                MessageToCpuLoad::SetIsa(i) => {
+                    isa = i.resolve();
+                    num_cycles = 1000000;
+                    Some(MessageFromCpuLoad::Isa(isa))
                 }
-                if running && associated {
+            }) {
+                if ctx.running() && associated {
                     let start = clock.raw();
-                    let (each, r) = load_select(num_cycles);
-                    sum += r;
+                    let Some((each, r)) = load_select(isa, num_cycles) else {
+                        ctx.report(MessageFromCpuLoad::Error(format!(
+                            "There is no {} load for this cpu",
+                            isa
+                        )));
+                        break;
+                    };
+                    std::hint::black_box(r);
                     let end = clock.raw();
                     let d = clock.delta(start, end);
                     if d.as_millis() < 1 {
+                        // Too short to measure reliably
                         num_cycles *= 10;
-                    } else {
-                        let ratio = time * 1000.0 / d.as_millis() as f64;
-                        num_cycles = (num_cycles as f64 * ratio) as usize;
+                        continue;
                     }
-                    if s2
-                        .send(MessageFromCpuLoad::Performance(
-                            (num_cycles * each) as u64,
-                            sum,
-                        ))
-                        .is_err()
-                    {
-                        break 'load;
+                    let seconds = d.as_secs_f64();
+                    let gflops = (num_cycles * each) as f64 / seconds / 1e9;
+                    // Aim for each measurement to take about `time` seconds
+                    num_cycles = ((num_cycles as f64 * time / seconds) as usize).max(1);
+                    if !ctx.performance(gflops) {
+                        break;
                     }
                 } else {
-                    std::thread::sleep(Duration::from_millis(100));
+                    ctx.idle();
                 }
             }
-            let _e = s2.send(MessageFromCpuLoad::Done);
         });
         Self {
             thread,
-            recv: r2,
-            send: s,
-            performance: 0,
+            core,
             associated: false,
-            running: false,
-            done: false,
+            isa,
+            error: None,
         }
     }
 
-    pub fn process_messages(&mut self) {
-        while let Ok(message) = self.recv.try_recv() {
+    /// Change the instruction set used, falling back to the best available if it is not supported.
+    pub fn set_isa(&self, isa: Isa) {
+        self.thread.send(MessageToCpuLoad::SetIsa(isa));
+    }
+
+    #[cfg(feature = "hwlocality")]
+    pub fn associate(
+        &self,
+        topology: hwlocality::Topology,
+        cpuset: hwlocality::cpu::cpusets::CpuSet,
+    ) {
+        self.thread
+            .send(MessageToCpuLoad::Associate(topology, cpuset));
+    }
+}
+
+impl Workload for CpuLoadThread {
+    fn kind(&self) -> WorkloadKind {
+        WorkloadKind::Cpu
+    }
+
+    fn target(&self) -> String {
+        format!("core {} {}", self.core, self.isa)
+    }
+
+    fn units(&self) -> &'static str {
+        "GFLOPS"
+    }
+
+    fn control(&self) -> &dyn WorkloadControl {
+        &self.thread
+    }
+
+    fn control_mut(&mut self) -> &mut dyn WorkloadControl {
+        &mut self.thread
+    }
+
+    fn process_messages(&mut self) {
+        for message in self.thread.process_messages() {
             match message {
-                MessageFromCpuLoad::Performance(flops, _sum) => {
-                    self.performance = flops;
-                }
                 MessageFromCpuLoad::Associated(a) => {
                     self.associated = a;
                 }
-                MessageFromCpuLoad::Running(r) => {
-                    self.running = r;
+                MessageFromCpuLoad::Isa(i) => {
+                    self.isa = i;
                 }
-                MessageFromCpuLoad::Done => {
-                    self.done = true;
+                MessageFromCpuLoad::Error(e) => {
+                    println!("{}", e);
+                    self.error = Some(e);
                 }
             }
         }
     }
 
-    pub fn end_and_wait(&mut self) {
-        let _e = self.send.send(MessageToCpuLoad::Stop);
-        let _e = self.send.send(MessageToCpuLoad::Exit);
-        'waiting: loop {
-            self.process_messages();
-            if self.done {
-                break 'waiting;
-            }
-        }
+    fn error(&self) -> Option<String> {
+        self.error.clone()
     }
 }
 
-#[cfg(target_arch = "x86_64")]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[target_feature(enable = "sse2")]
-unsafe fn reduce(x: core::arch::x86_64::__m128d) -> f64 {
+unsafe fn reduce(x: __m128d) -> f64 {
     #[cfg(target_arch = "x86")]
     use std::arch::x86::*;
     #[cfg(target_arch = "x86_64")]
@@ -232,26 +246,478 @@ unsafe fn load_sse2(count: usize) -> (usize, f64) {
     return (96, reduce(d[0]));
 }
 
-pub fn rust_load_select(count: usize) -> (usize, f64) {
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    {
-        if is_x86_feature_detected!("sse2") {
-            return unsafe { load_sse2(count) };
-        } else {
-            return (1, 42.0);
+/// The instruction set used to generate the cpu load.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Isa {
+    /// The widest instruction set supported by the cpu
+    Auto,
+    Sse2,
+    Avx,
+    Fma3,
+    Avx512,
+    Neon,
+    Sve,
+}
+
+impl Isa {
+    pub const ALL: [Isa; 7] = [
+        Isa::Auto,
+        Isa::Sse2,
+        Isa::Avx,
+        Isa::Fma3,
+        Isa::Avx512,
+        Isa::Neon,
+        Isa::Sve,
+    ];
+
+    /// Is this instruction set supported by the cpu that is running.
+    pub fn supported(&self) -> bool {
+        match self {
+            Isa::Auto => true,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Isa::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx => is_x86_feature_detected!("avx"),
+            #[cfg(target_arch = "x86_64")]
+            Isa::Fma3 => is_x86_feature_detected!("avx") && is_x86_feature_detected!("fma"),
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(target_arch = "aarch64")]
+            Isa::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[cfg(target_arch = "aarch64")]
+            Isa::Sve => std::arch::is_aarch64_feature_detected!("sve"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// The instruction set that will actually be used, unsupported choices fall back to [`Isa::Auto`].
+    pub fn resolve(&self) -> Isa {
+        if *self != Isa::Auto && self.supported() {
+            return *self;
         }
+        [
+            Isa::Avx512,
+            Isa::Fma3,
+            Isa::Avx,
+            Isa::Sse2,
+            Isa::Sve,
+            Isa::Neon,
+        ]
+        .into_iter()
+        .find(|i| i.supported())
+        .unwrap_or(Isa::Auto)
     }
-    (1, 41.0)
 }
 
-pub fn load_select(count: usize) -> (usize, f64) {
+impl std::fmt::Display for Isa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Isa::Auto => "auto",
+            Isa::Sse2 => "sse2",
+            Isa::Avx => "avx",
+            Isa::Fma3 => "fma3",
+            Isa::Avx512 => "avx512",
+            Isa::Neon => "neon",
+            Isa::Sve => "sve",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::str::FromStr for Isa {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Isa::Auto),
+            "sse2" => Ok(Isa::Sse2),
+            "avx" => Ok(Isa::Avx),
+            "fma3" | "fma" | "avx2" => Ok(Isa::Fma3),
+            "avx512" | "avx512f" => Ok(Isa::Avx512),
+            "neon" | "asimd" => Ok(Isa::Neon),
+            "sve" => Ok(Isa::Sve),
+            _ => Err(format!("Unknown instruction set {}", s)),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn reduce256(x: core::arch::x86_64::__m256d) -> f64 {
+    use std::arch::x86_64::*;
+    let x = _mm_add_pd(_mm256_castpd256_pd128(x), _mm256_extractf128_pd(x, 1));
+    reduce(x)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn load_avx(count: usize) -> (usize, f64) {
+    use std::arch::x86_64::*;
+
+    let mul0: __m256d = _mm256_set1_pd(1.4142135623730950488);
+    let mul1: __m256d = _mm256_set1_pd(0.70710678118654752440);
+
+    let mut d: [__m256d; 10] = [_mm256_set1_pd(0.0); 10];
+    for d in &mut d {
+        *d = _mm256_set1_pd((_rdtsc() % 256) as f64);
+    }
+
+    for _ in 0..count {
+        d[0] = _mm256_mul_pd(d[0], mul0);
+        d[6] = _mm256_add_pd(d[6], mul0);
+        d[1] = _mm256_mul_pd(d[1], mul0);
+        d[7] = _mm256_add_pd(d[7], mul0);
+        d[2] = _mm256_mul_pd(d[2], mul0);
+        d[8] = _mm256_add_pd(d[8], mul0);
+        d[3] = _mm256_mul_pd(d[3], mul0);
+        d[9] = _mm256_add_pd(d[9], mul0);
+        d[4] = _mm256_mul_pd(d[4], mul0);
+        d[6] = _mm256_sub_pd(d[6], mul0);
+        d[5] = _mm256_mul_pd(d[5], mul0);
+        d[7] = _mm256_sub_pd(d[7], mul0);
+
+        d[0] = _mm256_mul_pd(d[0], mul1);
+        d[8] = _mm256_sub_pd(d[8], mul0);
+        d[1] = _mm256_mul_pd(d[1], mul1);
+        d[9] = _mm256_sub_pd(d[9], mul0);
+        d[2] = _mm256_mul_pd(d[2], mul1);
+        d[6] = _mm256_add_pd(d[6], mul1);
+        d[3] = _mm256_mul_pd(d[3], mul1);
+        d[7] = _mm256_add_pd(d[7], mul1);
+        d[4] = _mm256_mul_pd(d[4], mul1);
+        d[8] = _mm256_add_pd(d[8], mul1);
+        d[5] = _mm256_mul_pd(d[5], mul1);
+        d[9] = _mm256_add_pd(d[9], mul1);
+
+        d[0] = _mm256_mul_pd(d[0], mul0);
+        d[6] = _mm256_sub_pd(d[6], mul1);
+        d[1] = _mm256_mul_pd(d[1], mul0);
+        d[7] = _mm256_sub_pd(d[7], mul1);
+        d[2] = _mm256_mul_pd(d[2], mul0);
+        d[8] = _mm256_sub_pd(d[8], mul1);
+        d[3] = _mm256_mul_pd(d[3], mul0);
+        d[9] = _mm256_sub_pd(d[9], mul1);
+        d[4] = _mm256_mul_pd(d[4], mul0);
+        d[6] = _mm256_add_pd(d[6], mul0);
+        d[5] = _mm256_mul_pd(d[5], mul0);
+        d[7] = _mm256_add_pd(d[7], mul0);
+
+        d[0] = _mm256_mul_pd(d[0], mul1);
+        d[8] = _mm256_add_pd(d[8], mul0);
+        d[1] = _mm256_mul_pd(d[1], mul1);
+        d[9] = _mm256_add_pd(d[9], mul0);
+        d[2] = _mm256_mul_pd(d[2], mul1);
+        d[6] = _mm256_sub_pd(d[6], mul0);
+        d[3] = _mm256_mul_pd(d[3], mul1);
+        d[7] = _mm256_sub_pd(d[7], mul0);
+        d[4] = _mm256_mul_pd(d[4], mul1);
+        d[8] = _mm256_sub_pd(d[8], mul0);
+        d[5] = _mm256_mul_pd(d[5], mul1);
+        d[9] = _mm256_sub_pd(d[9], mul0);
+    }
+
+    d[0] = _mm256_add_pd(d[0], d[5]);
+    d[1] = _mm256_add_pd(d[1], d[6]);
+    d[2] = _mm256_add_pd(d[2], d[7]);
+    d[3] = _mm256_add_pd(d[3], d[8]);
+    d[4] = _mm256_add_pd(d[4], d[9]);
+
+    d[0] = _mm256_add_pd(d[0], d[3]);
+    d[1] = _mm256_add_pd(d[1], d[4]);
+
+    d[0] = _mm256_add_pd(d[0], d[1]);
+    d[0] = _mm256_add_pd(d[0], d[2]);
+
+    (192, reduce256(d[0]))
+}
+
+/// Each iteration does 4 fused multiply adds on each of the 12 registers.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx,fma")]
+unsafe fn load_fma3(count: usize) -> (usize, f64) {
+    use std::arch::x86_64::*;
+
+    let mul0: __m256d = _mm256_set1_pd(1.4142135623730950488);
+    let mul1: __m256d = _mm256_set1_pd(0.70710678118654752440);
+
+    let mut d: [__m256d; 12] = [_mm256_set1_pd(0.0); 12];
+    for d in &mut d {
+        *d = _mm256_set1_pd((_rdtsc() % 256) as f64);
+    }
+
+    for _ in 0..count {
+        for d in &mut d {
+            *d = _mm256_fmadd_pd(mul0, mul1, *d);
+        }
+        for d in &mut d {
+            *d = _mm256_fnmadd_pd(mul0, mul1, *d);
+        }
+        for d in &mut d {
+            *d = _mm256_fmadd_pd(mul0, mul1, *d);
+        }
+        for d in &mut d {
+            *d = _mm256_fnmadd_pd(mul0, mul1, *d);
+        }
+    }
+
+    let mut sum = _mm256_set1_pd(0.0);
+    for d in &d {
+        sum = _mm256_add_pd(sum, *d);
+    }
+
+    // 12 registers * 4 fma * 2 flops * 4 doubles
+    (384, reduce256(sum))
+}
+
+/// Each iteration does 4 fused multiply adds on each of the 24 registers.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn load_avx512(count: usize) -> (usize, f64) {
+    use std::arch::x86_64::*;
+
+    let mul0: __m512d = _mm512_set1_pd(1.4142135623730950488);
+    let mul1: __m512d = _mm512_set1_pd(0.70710678118654752440);
+
+    let mut d: [__m512d; 24] = [_mm512_set1_pd(0.0); 24];
+    for d in &mut d {
+        *d = _mm512_set1_pd((_rdtsc() % 256) as f64);
+    }
+
+    for _ in 0..count {
+        for d in &mut d {
+            *d = _mm512_fmadd_pd(mul0, mul1, *d);
+        }
+        for d in &mut d {
+            *d = _mm512_fnmadd_pd(mul0, mul1, *d);
+        }
+        for d in &mut d {
+            *d = _mm512_fmadd_pd(mul0, mul1, *d);
+        }
+        for d in &mut d {
+            *d = _mm512_fnmadd_pd(mul0, mul1, *d);
+        }
+    }
+
+    let mut sum = _mm512_set1_pd(0.0);
+    for d in &d {
+        sum = _mm512_add_pd(sum, *d);
+    }
+
+    // 24 registers * 4 fma * 2 flops * 8 doubles
+    (1536, _mm512_reduce_add_pd(sum))
+}
+
+/// Run the wider kernels, which only exist in rust.
+#[cfg(target_arch = "x86_64")]
+fn load_wide(isa: Isa, count: usize) -> Option<(usize, f64)> {
+    match isa {
+        Isa::Avx => Some(unsafe { load_avx(count) }),
+        Isa::Fma3 => Some(unsafe { load_fma3(count) }),
+        Isa::Avx512 => Some(unsafe { load_avx512(count) }),
+        _ => None,
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn load_neon(count: usize) -> (usize, f64) {
+    use std::arch::aarch64::*;
+
+    let mul0: float64x2_t = vdupq_n_f64(1.4142135623730950488);
+    let mul1: float64x2_t = vdupq_n_f64(0.70710678118654752440);
+
+    let mut d: [float64x2_t; 16] = [vdupq_n_f64(0.0); 16];
+    for d in &mut d {
+        *d = vdupq_n_f64(std::hint::black_box(1.0));
+    }
+
+    for _ in 0..count {
+        for d in &mut d {
+            *d = vfmaq_f64(*d, mul0, mul1);
+        }
+        for d in &mut d {
+            *d = vfmsq_f64(*d, mul0, mul1);
+        }
+        for d in &mut d {
+            *d = vfmaq_f64(*d, mul0, mul1);
+        }
+        for d in &mut d {
+            *d = vfmsq_f64(*d, mul0, mul1);
+        }
+    }
+
+    let mut sum = vdupq_n_f64(0.0);
+    for d in &d {
+        sum = vaddq_f64(sum, *d);
+    }
+
+    // 16 registers * 4 fma * 2 flops * 2 doubles
+    (256, vaddvq_f64(sum))
+}
+
+/// There are no stable sve intrinsics, so this kernel is written in assembly.
+/// The vector length is only known at runtime, so the flops per iteration are too.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "sve")]
+unsafe fn load_sve(count: usize) -> (usize, f64) {
+    if count == 0 {
+        return (0, 0.0);
+    }
+    let lanes: usize;
+    let result: u64;
+    std::arch::asm!(
+        ".arch_extension sve",
+        "cntd {lanes}",
+        "ptrue p0.d",
+        "fmov z30.d, #0.5",
+        "fmov z31.d, #2.0",
+        "fmov z0.d, #1.0",
+        "fmov z1.d, #1.0",
+        "fmov z2.d, #1.0",
+        "fmov z3.d, #1.0",
+        "fmov z4.d, #1.0",
+        "fmov z5.d, #1.0",
+        "fmov z6.d, #1.0",
+        "fmov z7.d, #1.0",
+        "fmov z8.d, #1.0",
+        "fmov z9.d, #1.0",
+        "fmov z10.d, #1.0",
+        "fmov z11.d, #1.0",
+        "fmov z12.d, #1.0",
+        "fmov z13.d, #1.0",
+        "fmov z14.d, #1.0",
+        "fmov z15.d, #1.0",
+        "2:",
+        "fmla z0.d, p0/m, z30.d, z31.d",
+        "fmla z1.d, p0/m, z30.d, z31.d",
+        "fmla z2.d, p0/m, z30.d, z31.d",
+        "fmla z3.d, p0/m, z30.d, z31.d",
+        "fmla z4.d, p0/m, z30.d, z31.d",
+        "fmla z5.d, p0/m, z30.d, z31.d",
+        "fmla z6.d, p0/m, z30.d, z31.d",
+        "fmla z7.d, p0/m, z30.d, z31.d",
+        "fmla z8.d, p0/m, z30.d, z31.d",
+        "fmla z9.d, p0/m, z30.d, z31.d",
+        "fmla z10.d, p0/m, z30.d, z31.d",
+        "fmla z11.d, p0/m, z30.d, z31.d",
+        "fmla z12.d, p0/m, z30.d, z31.d",
+        "fmla z13.d, p0/m, z30.d, z31.d",
+        "fmla z14.d, p0/m, z30.d, z31.d",
+        "fmla z15.d, p0/m, z30.d, z31.d",
+        "fmls z0.d, p0/m, z30.d, z31.d",
+        "fmls z1.d, p0/m, z30.d, z31.d",
+        "fmls z2.d, p0/m, z30.d, z31.d",
+        "fmls z3.d, p0/m, z30.d, z31.d",
+        "fmls z4.d, p0/m, z30.d, z31.d",
+        "fmls z5.d, p0/m, z30.d, z31.d",
+        "fmls z6.d, p0/m, z30.d, z31.d",
+        "fmls z7.d, p0/m, z30.d, z31.d",
+        "fmls z8.d, p0/m, z30.d, z31.d",
+        "fmls z9.d, p0/m, z30.d, z31.d",
+        "fmls z10.d, p0/m, z30.d, z31.d",
+        "fmls z11.d, p0/m, z30.d, z31.d",
+        "fmls z12.d, p0/m, z30.d, z31.d",
+        "fmls z13.d, p0/m, z30.d, z31.d",
+        "fmls z14.d, p0/m, z30.d, z31.d",
+        "fmls z15.d, p0/m, z30.d, z31.d",
+        "subs {count}, {count}, #1",
+        "b.ne 2b",
+        "fadd z0.d, p0/m, z0.d, z8.d",
+        "fadd z1.d, p0/m, z1.d, z9.d",
+        "fadd z2.d, p0/m, z2.d, z10.d",
+        "fadd z3.d, p0/m, z3.d, z11.d",
+        "fadd z4.d, p0/m, z4.d, z12.d",
+        "fadd z5.d, p0/m, z5.d, z13.d",
+        "fadd z6.d, p0/m, z6.d, z14.d",
+        "fadd z7.d, p0/m, z7.d, z15.d",
+        "fadd z0.d, p0/m, z0.d, z4.d",
+        "fadd z1.d, p0/m, z1.d, z5.d",
+        "fadd z2.d, p0/m, z2.d, z6.d",
+        "fadd z3.d, p0/m, z3.d, z7.d",
+        "fadd z0.d, p0/m, z0.d, z2.d",
+        "fadd z1.d, p0/m, z1.d, z3.d",
+        "fadd z0.d, p0/m, z0.d, z1.d",
+        "faddv d0, p0, z0.d",
+        "fmov {result}, d0",
+        count = inout(reg) count => _,
+        lanes = out(reg) lanes,
+        result = out(reg) result,
+        out("v0") _,
+        out("v1") _,
+        out("v2") _,
+        out("v3") _,
+        out("v4") _,
+        out("v5") _,
+        out("v6") _,
+        out("v7") _,
+        out("v8") _,
+        out("v9") _,
+        out("v10") _,
+        out("v11") _,
+        out("v12") _,
+        out("v13") _,
+        out("v14") _,
+        out("v15") _,
+        out("v30") _,
+        out("v31") _,
+        out("p0") _,
+        options(nostack),
+    );
+    // 16 registers * 2 fma * 2 flops * the number of doubles in a vector
+    (64 * lanes, f64::from_bits(result))
+}
+
+#[cfg(target_arch = "aarch64")]
+fn load_arm(isa: Isa, count: usize) -> Option<(usize, f64)> {
+    match isa {
+        Isa::Neon => Some(unsafe { load_neon(count) }),
+        Isa::Sve => Some(unsafe { load_sve(count) }),
+        _ => None,
+    }
+}
+
+/// Returns the number of floating point operations done and a result that must be used,
+/// or `None` when the cpu supports none of the instruction sets there is a kernel for.
+pub fn rust_load_select(isa: Isa, count: usize) -> Option<(usize, f64)> {
+    let isa = isa.resolve();
+    #[cfg(target_arch = "x86_64")]
+    if let Some(r) = load_wide(isa, count) {
+        return Some(r);
+    }
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    {
-        if is_x86_feature_detected!("sse2") {
-            return unsafe { (96, cpuload::sse_load(count as u32)) };
-        } else {
-            return (1, 42.0);
+    if isa == Isa::Sse2 {
+        return Some(unsafe { load_sse2(count) });
+    }
+    #[cfg(target_arch = "aarch64")]
+    if let Some(r) = load_arm(isa, count) {
+        return Some(r);
+    }
+    None
+}
+
+/// Like [`rust_load_select`], but sse2 uses the original c implementation.
+pub fn load_select(isa: Isa, count: usize) -> Option<(usize, f64)> {
+    let isa = isa.resolve();
+    #[cfg(target_arch = "x86_64")]
+    if let Some(r) = load_wide(isa, count) {
+        return Some(r);
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if isa == Isa::Sse2 {
+        // The c implementation counts in 32 bits, larger counts are run in parts
+        let mut result = 0.0;
+        let mut left = count;
+        while left > 0 {
+            let part = u32::try_from(left).unwrap_or(u32::MAX);
+            result += unsafe { cpuload::sse_load(part) };
+            left -= part as usize;
         }
+        return Some((96, result));
+    }
+    #[cfg(target_arch = "aarch64")]
+    if let Some(r) = load_arm(isa, count) {
+        return Some(r);
     }
-    (1, 41.0)
+    None
 }
@@ -1,124 +1,1011 @@
-use std::io::{BufRead, SeekFrom, Seek};
+use std::{
+    alloc::Layout,
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
 
-pub struct DiskLoad {
-    thread: std::thread::JoinHandle<()>,
-    recv: std::sync::mpsc::Receiver<MessageFromDiskLoad>,
-    pub send: std::sync::mpsc::Sender<MessageToDiskLoad>,
-    pub performance: u64,
-    pub running: bool,
-    pub done: bool,
-    pub path: std::path::PathBuf,
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+use hdrhistogram::Histogram;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    diskstats::{KernelDiskSample, KernelDiskSampler},
+    latency::{self, LatencyPercentiles},
+    results::Sample,
+    workload::{Workload, WorkloadControl, WorkloadKind, WorkloadThread},
+};
+
+/// How often the throughput is reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The order in which the disk is accessed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiskPattern {
+    Sequential,
+    Random,
+}
+
+impl std::fmt::Display for DiskPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskPattern::Sequential => f.write_str("sequential"),
+            DiskPattern::Random => f.write_str("random"),
+        }
+    }
+}
+
+impl std::str::FromStr for DiskPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "seq" | "sequential" => Ok(DiskPattern::Sequential),
+            "rand" | "random" => Ok(DiskPattern::Random),
+            _ => Err(format!("Unknown disk pattern {}", s)),
+        }
+    }
+}
+
+/// How reads and writes are issued to the disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiskEngine {
+    /// One blocking operation at a time
+    Sync,
+    /// Many outstanding operations submitted through io_uring, only supported on linux
+    IoUring,
+}
+
+impl std::fmt::Display for DiskEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskEngine::Sync => f.write_str("sync"),
+            DiskEngine::IoUring => f.write_str("io_uring"),
+        }
+    }
+}
+
+impl std::str::FromStr for DiskEngine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sync" | "psync" => Ok(DiskEngine::Sync),
+            "io_uring" | "uring" => Ok(DiskEngine::IoUring),
+            _ => Err(format!("Unknown disk engine {}", s)),
+        }
+    }
+}
+
+/// How each write is made durable when measuring sync latency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiskSync {
+    /// Flush the data and all of the metadata of the file
+    Fsync,
+    /// Flush the data and only the metadata needed to read it back
+    Fdatasync,
+}
+
+impl std::fmt::Display for DiskSync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskSync::Fsync => f.write_str("fsync"),
+            DiskSync::Fdatasync => f.write_str("fdatasync"),
+        }
+    }
+}
+
+impl std::str::FromStr for DiskSync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fsync" => Ok(DiskSync::Fsync),
+            "fdatasync" => Ok(DiskSync::Fdatasync),
+            _ => Err(format!("Unknown sync mode {}", s)),
+        }
+    }
+}
+
+/// How a disk is loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiskConfig {
+    pub pattern: DiskPattern,
+    /// The percentage of operations that are reads, the rest are writes
+    /// which destroy the contents of the target
+    pub read_percent: u8,
+    /// The size of each read or write in bytes
+    pub block_size: usize,
+    /// Bypass the page cache so that the device itself is measured, only
+    /// supported on linux
+    pub direct: bool,
+    pub engine: DiskEngine,
+    /// The number of operations each job keeps outstanding with [`DiskEngine::IoUring`]
+    pub queue_depth: usize,
+    /// The number of threads accessing the disk at the same time
+    pub jobs: usize,
+    /// The size of the test file created when the target is a directory
+    pub file_size: u64,
+    /// How much of the target is used, starting from the beginning, instead of all of it
+    pub size: Option<u64>,
+    /// Stop the load after this long, instead of running until it is stopped
+    pub runtime: Option<Duration>,
+    /// Go over `size` again and again until the load is stopped or the
+    /// runtime has passed, instead of stopping once each job has done it once
+    pub time_based: bool,
+    /// Measure sync latency instead of throughput. Every operation is a
+    /// write followed by a sync, and only the time taken by the sync is
+    /// recorded. This always uses [`DiskEngine::Sync`]
+    pub sync: Option<DiskSync>,
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        Self {
+            pattern: DiskPattern::Sequential,
+            read_percent: 100,
+            block_size: 1 << 20,
+            direct: true,
+            engine: DiskEngine::Sync,
+            queue_depth: 1,
+            jobs: 1,
+            file_size: 1 << 30,
+            size: None,
+            runtime: None,
+            time_based: true,
+            sync: None,
+        }
+    }
+}
+
+impl DiskConfig {
+    /// Whether the load writes to its target, destroying what is there.
+    pub fn writes(&self) -> bool {
+        self.read_percent < 100 || self.sync.is_some()
+    }
+}
+
+impl std::fmt::Display for DiskConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pattern)?;
+        match self.read_percent {
+            100 => {}
+            0 => f.write_str(" write")?,
+            r => write!(f, " {}% read", r)?,
+        }
+        write!(f, " {}", format_size(self.block_size as u64))?;
+        if let Some(size) = self.size {
+            write!(f, " of {}", format_size(size))?;
+        }
+        if let Some(sync) = self.sync {
+            write!(f, " {}", sync)?;
+        } else if self.engine == DiskEngine::IoUring {
+            write!(f, " {} qd{}", self.engine, self.queue_depth)?;
+        }
+        if self.jobs > 1 {
+            write!(f, " {} jobs", self.jobs)?;
+        }
+        if !self.direct {
+            f.write_str(" buffered")?;
+        }
+        Ok(())
+    }
+}
+
+/// Format a size in bytes using the largest binary unit that divides it exactly.
+pub fn format_size(size: u64) -> String {
+    for (shift, unit) in [(30, "G"), (20, "M"), (10, "K")] {
+        if size >= 1 << shift && size % (1 << shift) == 0 {
+            return format!("{}{}", size >> shift, unit);
+        }
+    }
+    format!("{}", size)
+}
+
+/// Running totals for a disk load since it was started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskStats {
+    pub total_bytes: u64,
+    /// The part of `total_bytes` that was written rather than read
+    pub written_bytes: u64,
+    /// The number of reads and writes done
+    pub total_ops: u64,
+    /// The sum of the latency of every operation
+    pub total_latency: Duration,
+    /// How long the load has been running
+    pub run_time: Duration,
+    /// Operations per second during the last interval
+    pub iops: f64,
+    /// The mean latency during the last interval
+    pub latency: Duration,
+    /// The distribution of the latency of every operation since the load was started
+    pub percentiles: LatencyPercentiles,
 }
 
 pub enum MessageToDiskLoad {
-    Start,
-    Stop,
-    Exit,
+    /// Change how the disk is loaded
+    Configure(DiskConfig),
 }
 
 pub enum MessageFromDiskLoad {
-    Performance(u64),
-    Running(bool),
-    Done,
+    Stats(DiskStats),
+    /// Whether i/o bypasses the page cache
+    Direct(bool),
+    /// The device the kernel statistics are read from
+    KernelDevice(String),
+    /// What the kernel saw on the device during the last interval
+    Kernel(KernelDiskSample),
+    /// The load could not be run, or a job stopped because of an error
+    Error(String),
 }
 
-impl DiskLoad {
-    pub fn process_messages(&mut self) {
-        while let Ok(message) = self.recv.try_recv() {
-            match message {
-                MessageFromDiskLoad::Performance(bps) => {
-                    self.performance = bps;
-                }
-                MessageFromDiskLoad::Running(r) => {
-                    self.running = r;
-                }
-                MessageFromDiskLoad::Done => {
-                    self.done = true;
+/// A zeroed buffer with the alignment required for direct I/O.
+pub struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// The buffer is uniquely owned, just like a Vec
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    pub fn new(size: usize, align: usize) -> Self {
+        // Page alignment satisfies every device, and an empty layout can not be allocated
+        let layout =
+            Layout::from_size_align(size.max(1), align.max(4096)).expect("Invalid buffer layout");
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, layout }
+    }
+}
+
+impl std::ops::Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl std::ops::DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// Open a disk for reading, or also for writing when `write` is set.
+fn open_mode(p: &PathBuf, direct: bool, write: bool) -> std::io::Result<rawdisk::DiskLoad> {
+    if write {
+        rawdisk::DiskLoad::open_writable(p, direct)
+    } else {
+        rawdisk::DiskLoad::open(p, direct)
+    }
+}
+
+/// Open a disk, using direct I/O if requested and supported.
+/// Returns the disk and whether direct I/O is being used.
+fn open_disk(p: &PathBuf, direct: bool, write: bool) -> std::io::Result<(rawdisk::DiskLoad, bool)> {
+    if direct {
+        match open_mode(p, true, write) {
+            Ok(d) => return Ok((d, true)),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                println!(
+                    "Direct I/O is not supported for {}, i/o will go through the page cache",
+                    p.display()
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((open_mode(p, false, write)?, false))
+}
+
+/// A small xorshift generator, good enough to pick random blocks.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Counters shared between the threads of a running disk load.
+struct JobCounters {
+    stop: AtomicBool,
+    /// Cleared when a job had to fall back to going through the page cache
+    direct: AtomicBool,
+    bytes: AtomicU64,
+    /// The part of `bytes` that was written
+    written: AtomicU64,
+    ops: AtomicU64,
+    latency_ns: AtomicU64,
+    /// The latency of every operation since the controller last collected them, one per job
+    histograms: Vec<Mutex<Histogram<u64>>>,
+    /// The first error that stopped a job, until the controller reports it
+    error: Mutex<Option<String>>,
+}
+
+impl JobCounters {
+    fn new(direct: bool, jobs: usize) -> Self {
+        Self {
+            stop: AtomicBool::new(false),
+            direct: AtomicBool::new(direct),
+            bytes: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            ops: AtomicU64::new(0),
+            latency_ns: AtomicU64::new(0),
+            histograms: (0..jobs)
+                .map(|_| Mutex::new(latency::new_histogram()))
+                .collect(),
+            error: Mutex::new(None),
+        }
+    }
+
+    /// Keep the error that stopped a job, unless another job failed first.
+    fn fail(&self, message: String) {
+        if let Ok(mut e) = self.error.lock() {
+            e.get_or_insert(message);
+        }
+    }
+
+    fn take_error(&self) -> Option<String> {
+        self.error.lock().ok()?.take()
+    }
+
+    fn record(&self, job: usize, bytes: u64, write: bool, latency: Duration) {
+        if bytes > 0 {
+            if let Ok(mut h) = self.histograms[job].lock() {
+                latency::record(&mut h, latency);
+            }
+            self.bytes.fetch_add(bytes, Ordering::Relaxed);
+            if write {
+                self.written.fetch_add(bytes, Ordering::Relaxed);
+            }
+            self.ops.fetch_add(1, Ordering::Relaxed);
+            self.latency_ns
+                .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+}
+
+/// Picks the offset of each operation, and whether it is a read or a write.
+struct Offsets {
+    pattern: DiskPattern,
+    read_percent: u64,
+    block_size: u64,
+    blocks: u64,
+    next: u64,
+    rng: Rng,
+    /// The operations left before the job is done, when it is not time based
+    remaining: Option<u64>,
+}
+
+impl Offsets {
+    /// Whether the job has operations left to do.
+    fn more(&self) -> bool {
+        self.remaining != Some(0)
+    }
+
+    fn next(&mut self) -> u64 {
+        if let Some(r) = &mut self.remaining {
+            *r = r.saturating_sub(1);
+        }
+        let block = match self.pattern {
+            DiskPattern::Sequential => {
+                let b = self.next;
+                self.next = (self.next + 1) % self.blocks;
+                b
+            }
+            DiskPattern::Random => self.rng.next() % self.blocks,
+        };
+        block * self.block_size
+    }
+
+    fn read(&mut self) -> bool {
+        match self.read_percent {
+            100 => true,
+            0 => false,
+            r => self.rng.next() % 100 < r,
+        }
+    }
+}
+
+/// Read or write the disk one block at a time until told to stop.
+fn sync_job(
+    disk: &rawdisk::DiskLoad,
+    buf: &mut AlignedBuf,
+    offsets: &mut Offsets,
+    counters: &JobCounters,
+    job: usize,
+    clock: &quanta::Clock,
+) -> std::io::Result<()> {
+    while !counters.stopping() && offsets.more() {
+        let offset = offsets.next();
+        let read = offsets.read();
+        let start = clock.raw();
+        let amt = if read {
+            disk.read_at(buf, offset)?
+        } else {
+            disk.write_at(buf, offset)?
+        };
+        counters.record(job, amt as u64, !read, clock.delta(start, clock.raw()));
+    }
+    Ok(())
+}
+
+/// Write the disk one block at a time, waiting for each write to be synced,
+/// until told to stop. Only the latency of the sync is recorded.
+fn sync_write_job(
+    disk: &rawdisk::DiskLoad,
+    buf: &AlignedBuf,
+    offsets: &mut Offsets,
+    counters: &JobCounters,
+    job: usize,
+    clock: &quanta::Clock,
+    sync: DiskSync,
+) -> std::io::Result<()> {
+    while !counters.stopping() && offsets.more() {
+        let amt = disk.write_at(buf, offsets.next())?;
+        let start = clock.raw();
+        disk.flush(sync == DiskSync::Fdatasync)?;
+        counters.record(job, amt as u64, true, clock.delta(start, clock.raw()));
+    }
+    Ok(())
+}
+
+/// Keep `queue_depth` operations outstanding through io_uring until told to stop.
+#[cfg(target_os = "linux")]
+fn uring_job(
+    disk: &rawdisk::DiskLoad,
+    bufs: &mut [AlignedBuf],
+    offsets: &mut Offsets,
+    counters: &JobCounters,
+    job: usize,
+    clock: &quanta::Clock,
+) -> std::io::Result<()> {
+    use io_uring::{opcode, types, IoUring};
+    let mut ring = IoUring::new(bufs.len().next_power_of_two() as u32)?;
+    let fd = types::Fd(disk.as_raw_fd());
+    let mut submitted = vec![0; bufs.len()];
+    let mut writes = vec![false; bufs.len()];
+    let mut free: Vec<usize> = (0..bufs.len()).collect();
+    let mut in_flight = 0;
+    let mut error = None;
+    loop {
+        if !counters.stopping() && error.is_none() {
+            while offsets.more() {
+                let Some(i) = free.pop() else {
+                    break;
+                };
+                let offset = offsets.next();
+                writes[i] = !offsets.read();
+                let entry = if writes[i] {
+                    opcode::Write::new(fd, bufs[i].as_ptr(), bufs[i].len() as u32)
+                        .offset(offset)
+                        .build()
+                } else {
+                    opcode::Read::new(fd, bufs[i].as_mut_ptr(), bufs[i].len() as u32)
+                        .offset(offset)
+                        .build()
+                };
+                submitted[i] = clock.raw();
+                // The queue has an entry for every buffer, so there is always room
+                unsafe { ring.submission().push(&entry.user_data(i as u64)) }
+                    .expect("io_uring submission queue is full");
+                in_flight += 1;
+            }
+        }
+        // Every operation must complete before the buffers can be given back
+        if in_flight == 0 {
+            break;
+        }
+        if let Err(e) = ring.submit_and_wait(1) {
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        let now = clock.raw();
+        for cqe in ring.completion() {
+            let i = cqe.user_data() as usize;
+            in_flight -= 1;
+            free.push(i);
+            if cqe.result() < 0 {
+                error = Some(std::io::Error::from_raw_os_error(-cqe.result()));
+            } else {
+                counters.record(
+                    job,
+                    cqe.result() as u64,
+                    writes[i],
+                    clock.delta(submitted[i], now),
+                );
+            }
+        }
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// One thread loading the disk, `index` is which of the jobs this is.
+fn run_job(
+    p: &PathBuf,
+    config: &DiskConfig,
+    index: usize,
+    size: u64,
+    logical_block_size: usize,
+    counters: &JobCounters,
+) {
+    let write = config.writes();
+    let (mut disk, mut direct) = match open_disk(p, config.direct, write) {
+        Ok(d) => d,
+        Err(e) => {
+            counters.fail(format!("Failed to open {}: {}", p.display(), e));
+            return;
+        }
+    };
+    if !direct {
+        counters.direct.store(false, Ordering::Relaxed);
+    }
+    let block_size = config.block_size as u64;
+    let blocks = config.size.unwrap_or(size).min(size) / block_size;
+    if blocks == 0 {
+        counters.fail(format!("{} is smaller than one block", p.display()));
+        return;
+    }
+    let clock = quanta::Clock::new();
+    let mut offsets = Offsets {
+        pattern: config.pattern,
+        read_percent: config.read_percent.min(100) as u64,
+        block_size,
+        blocks,
+        // Spread sequential jobs out so they are not all reading the same blocks
+        next: blocks * index as u64 / config.jobs as u64,
+        rng: Rng::new(clock.raw() ^ index as u64),
+        remaining: (!config.time_based).then_some(blocks),
+    };
+    let mut engine = match config.sync {
+        Some(_) => DiskEngine::Sync,
+        None => config.engine,
+    };
+    let depth = match engine {
+        DiskEngine::Sync => 1,
+        DiskEngine::IoUring => config.queue_depth.max(1),
+    };
+    let mut bufs: Vec<AlignedBuf> = (0..depth)
+        .map(|_| AlignedBuf::new(config.block_size, logical_block_size))
+        .collect();
+    if write {
+        // Written data that compresses or deduplicates well would flatter some devices
+        let mut rng = Rng::new(offsets.rng.next());
+        for buf in &mut bufs {
+            for chunk in buf.chunks_mut(8) {
+                chunk.copy_from_slice(&rng.next().to_le_bytes());
+            }
+        }
+    }
+    loop {
+        let result = match (engine, config.sync) {
+            (DiskEngine::Sync, Some(sync)) => {
+                sync_write_job(&disk, &bufs[0], &mut offsets, counters, index, &clock, sync)
+            }
+            (DiskEngine::Sync, None) => {
+                sync_job(&disk, &mut bufs[0], &mut offsets, counters, index, &clock)
+            }
+            #[cfg(target_os = "linux")]
+            (DiskEngine::IoUring, _) => {
+                uring_job(&disk, &mut bufs, &mut offsets, counters, index, &clock)
+            }
+            #[cfg(not(target_os = "linux"))]
+            (DiskEngine::IoUring, _) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "io_uring is only available on linux",
+            )),
+        };
+        match result {
+            Ok(()) => break,
+            // Some filesystems accept O_DIRECT when opening but not when reading
+            Err(e) if direct && e.kind() == std::io::ErrorKind::InvalidInput => {
+                println!(
+                    "Direct I/O failed for {}, i/o will go through the page cache",
+                    p.display()
+                );
+                match open_mode(p, false, write) {
+                    Ok(d) => {
+                        disk = d;
+                        direct = false;
+                        counters.direct.store(false, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        counters.fail(format!("Failed to reopen {}: {}", p.display(), e));
+                        break;
+                    }
                 }
             }
+            // Kernels without io_uring, or where seccomp or io_uring_disabled forbid it
+            Err(e)
+                if engine == DiskEngine::IoUring
+                    && matches!(
+                        e.kind(),
+                        std::io::ErrorKind::Unsupported | std::io::ErrorKind::PermissionDenied
+                    ) =>
+            {
+                println!(
+                    "io_uring is not available ({}), loading {} synchronously",
+                    e,
+                    p.display()
+                );
+                engine = DiskEngine::Sync;
+            }
+            Err(e) => {
+                counters.fail(format!("Failed to load {}: {}", p.display(), e));
+                break;
+            }
         }
     }
+}
 
-    pub fn disk_read_all_files(p: &std::path::PathBuf) -> Self {
-        let (s, r) = std::sync::mpsc::channel();
-        let (s2, r2) = std::sync::mpsc::channel();
+/// Tell every job to stop and wait for them to finish.
+fn stop_jobs(counters: &JobCounters, jobs: &mut Vec<JoinHandle<()>>) {
+    counters.stop.store(true, Ordering::Relaxed);
+    for j in jobs.drain(..) {
+        let _e = j.join();
+    }
+}
+
+pub struct DiskLoad {
+    thread: WorkloadThread<MessageToDiskLoad, MessageFromDiskLoad>,
+    pub path: PathBuf,
+    pub config: DiskConfig,
+    pub stats: DiskStats,
+    /// Why the load could not be run, or the first error that stopped one of its jobs
+    pub error: Option<String>,
+    /// Whether i/o bypasses the page cache
+    pub direct: bool,
+    /// The device the kernel statistics are read from
+    pub kernel_device: Option<String>,
+    /// What the kernel saw on the device, since the load was started
+    pub kernel: Vec<KernelDiskSample>,
+    /// When the load was started that `kernel` belongs to
+    kernel_started: Option<DateTime<Utc>>,
+}
+
+impl DiskLoad {
+    pub fn new(p: &PathBuf, config: DiskConfig) -> Self {
         let p = p.to_owned();
         let p2 = p.clone();
-        let thread = std::thread::spawn(move || {
-            let mut running = false;
-
-            let clock = quanta::Clock::new();
-            let mut buf = Box::new([0; 512000]);
-            #[cfg(target_os = "windows")]
-            let mut disk = rawdisk::DiskLoad::new(&p);
-            #[cfg(target_os = "linux")]
-            let mut disk = std::fs::File::open(&p);
-            #[cfg(target_os = "windows")]
-            while disk.is_err() {
-                std::thread::sleep(std::time::Duration::from_millis(100));
-                disk = rawdisk::DiskLoad::new(&p);
+        let config2 = config.clone();
+        let thread = WorkloadThread::spawn(move |mut ctx| {
+            let mut config = config;
+            // A directory is tested by reading a file created in it
+            let p = if p.is_dir() {
+                match crate::disktarget::prepare_test_file(&p, config.file_size) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        ctx.report(MessageFromDiskLoad::Error(format!(
+                            "Failed to create a test file in {}: {}",
+                            p.display(),
+                            e
+                        )));
+                        return;
+                    }
+                }
+            } else if !p.exists() && config.size.is_some() && config.writes() {
+                // A job that writes creates its file, as fio does
+                let size = config.size.unwrap_or(config.file_size);
+                if let Err(e) = crate::disktarget::create_test_file(&p, size) {
+                    ctx.report(MessageFromDiskLoad::Error(format!(
+                        "Failed to create {}: {}",
+                        p.display(),
+                        e
+                    )));
+                    return;
+                }
+                p
+            } else {
+                p
+            };
+            // Check that the disk can be opened as the jobs will open it, the i/o
+            // is done by separate job threads
+            let opened = open_disk(&p, config.direct, config.writes()).and_then(|(d, direct)| {
+                // Direct I/O is safe for nearly every device when aligned to 4096
+                Ok((d.size()?, d.sector_size().unwrap_or(4096), direct))
+            });
+            let (size, logical_block_size, mut direct) = match opened {
+                Ok(o) => o,
+                Err(e) => {
+                    ctx.report(MessageFromDiskLoad::Error(format!(
+                        "Failed to open {}: {}",
+                        p.display(),
+                        e
+                    )));
+                    return;
+                }
+            };
+            println!("Successfully opened {}", p.display());
+            if !ctx.report(MessageFromDiskLoad::Direct(direct)) {
+                return;
             }
-            #[cfg(target_os = "linux")]
-            while disk.is_err() {
-                std::thread::sleep(std::time::Duration::from_millis(100));
-                disk = std::fs::File::open(&p);
-            }
-            if let Ok(mut disk) = disk {
-                #[cfg(target_os = "linux")]
-                let mut br = std::io::BufReader::new(disk);
-                println!("Successfully opened {}", p.display());
-                'load: loop {
-                    while let Ok(message) = r.try_recv() {
-                        match message {
-                            MessageToDiskLoad::Start => {
-                                running = true;
-                                if s2.send(MessageFromDiskLoad::Running(running)).is_err() {
-                                    break 'load;
-                                }
-                            }
-                            MessageToDiskLoad::Stop => {
-                                running = false;
-                                if s2.send(MessageFromDiskLoad::Running(running)).is_err() {
-                                    break 'load;
-                                }
-                            }
-                            MessageToDiskLoad::Exit => {
-                                break 'load;
+            let mut sampler = KernelDiskSampler::new(&p);
+            if let Some(s) = &sampler {
+                if !ctx.report(MessageFromDiskLoad::KernelDevice(s.name().to_string())) {
+                    return;
+                }
+            }
+            let clock = quanta::Clock::new();
+            let mut counters = Arc::new(JobCounters::new(false, 0));
+            let mut histogram = latency::new_histogram();
+            let mut jobs: Vec<JoinHandle<()>> = vec![];
+            let mut stats = DiskStats::default();
+            let mut interval_start = clock.raw();
+            let mut was_running = false;
+            // Sync loads report syncs per second instead of throughput
+            let mut syncing = false;
+            // Set once the runtime of the load has passed, until it is started again
+            let mut finished = false;
+            while ctx.poll(|m| match m {
+                MessageToDiskLoad::Configure(c) => {
+                    config = c;
+                    None
+                }
+            }) {
+                if !ctx.running() {
+                    if was_running {
+                        was_running = false;
+                        finished = false;
+                        stop_jobs(&counters, &mut jobs);
+                        if let Some(e) = counters.take_error() {
+                            if !ctx.report(MessageFromDiskLoad::Error(e)) {
+                                break;
                             }
                         }
                     }
-                    if running {
-                        #[cfg(target_os = "windows")]
-                        disk.read(buf.as_mut_slice());
-                        #[cfg(target_os = "linux")]
-                        let amt = if let Ok(b) = br.fill_buf() {
-                            b.len()
-                        } else {
-                            0
-                        };
-                        #[cfg(target_os = "linux")]
-                        if amt == 0 {
-                            let _e = br.seek(SeekFrom::Start(0));
+                    ctx.idle();
+                    continue;
+                }
+                if finished {
+                    ctx.idle();
+                    continue;
+                }
+                if !was_running {
+                    was_running = true;
+                    let mut job_config = config.clone();
+                    // Direct i/o must be a multiple of the logical block size
+                    if config.direct && job_config.block_size % logical_block_size != 0 {
+                        job_config.block_size =
+                            (job_config.block_size / logical_block_size + 1) * logical_block_size;
+                        println!(
+                            "Block size for {} rounded up to {} for direct I/O",
+                            p.display(),
+                            job_config.block_size
+                        );
+                    }
+                    job_config.jobs = job_config.jobs.max(1);
+                    syncing = job_config.sync.is_some();
+                    counters = Arc::new(JobCounters::new(
+                        cfg!(target_os = "linux") && config.direct,
+                        job_config.jobs,
+                    ));
+                    for index in 0..job_config.jobs {
+                        let p = p.clone();
+                        let job_config = job_config.clone();
+                        let counters = counters.clone();
+                        jobs.push(std::thread::spawn(move || {
+                            run_job(&p, &job_config, index, size, logical_block_size, &counters)
+                        }));
+                    }
+                    stats = DiskStats::default();
+                    histogram.reset();
+                    if let Some(s) = &mut sampler {
+                        s.reset();
+                    }
+                    interval_start = clock.raw();
+                }
+                std::thread::sleep(Duration::from_millis(10));
+                let now = clock.raw();
+                let elapsed = clock.delta(interval_start, now);
+                if elapsed >= REPORT_INTERVAL {
+                    interval_start = now;
+                    // Checked first so that everything the jobs did is in this report
+                    let jobs_done = jobs.iter().all(|j| j.is_finished());
+                    let seconds = elapsed.as_secs_f64();
+                    let bytes = counters.bytes.swap(0, Ordering::Relaxed);
+                    stats.written_bytes += counters.written.swap(0, Ordering::Relaxed);
+                    let ops = counters.ops.swap(0, Ordering::Relaxed);
+                    let latency =
+                        Duration::from_nanos(counters.latency_ns.swap(0, Ordering::Relaxed));
+                    stats.total_bytes += bytes;
+                    stats.total_ops += ops;
+                    stats.total_latency += latency;
+                    stats.run_time += elapsed;
+                    stats.iops = ops as f64 / seconds;
+                    stats.latency = if ops > 0 {
+                        latency / ops as u32
+                    } else {
+                        Duration::ZERO
+                    };
+                    for h in &counters.histograms {
+                        if let Ok(mut h) = h.lock() {
+                            let _e = histogram.add(&*h);
+                            h.reset();
                         }
-                        #[cfg(target_os = "linux")]
-                        br.consume(amt);
+                    }
+                    stats.percentiles = LatencyPercentiles::from_histogram(&histogram);
+                    let d = counters.direct.load(Ordering::Relaxed);
+                    if d != direct {
+                        direct = d;
+                        if !ctx.report(MessageFromDiskLoad::Direct(direct)) {
+                            break;
+                        }
+                    }
+                    if let Some(k) = sampler.as_mut().and_then(|s| s.sample()) {
+                        if !ctx.report(MessageFromDiskLoad::Kernel(k)) {
+                            break;
+                        }
+                    }
+                    if let Some(e) = counters.take_error() {
+                        if !ctx.report(MessageFromDiskLoad::Error(e)) {
+                            break;
+                        }
+                    }
+                    let performance = if syncing {
+                        stats.iops
                     } else {
-                        std::thread::sleep(std::time::Duration::from_millis(100));
+                        bytes as f64 / seconds / 1e6
+                    };
+                    if !ctx.performance(performance)
+                        || !ctx.report(MessageFromDiskLoad::Stats(stats))
+                    {
+                        break;
+                    }
+                    if config.runtime.map_or(false, |r| stats.run_time >= r) {
+                        println!("Runtime of the load on {} has passed", p.display());
+                        stop_jobs(&counters, &mut jobs);
+                        finished = true;
+                    } else if jobs_done {
+                        println!("Every job of the load on {} is done", p.display());
+                        stop_jobs(&counters, &mut jobs);
+                        finished = true;
                     }
                 }
-            } else {
-                println!("Failed to open {}", p.display());
             }
-            let _e = s2.send(MessageFromDiskLoad::Done);
+            stop_jobs(&counters, &mut jobs);
+            if let Some(e) = counters.take_error() {
+                ctx.report(MessageFromDiskLoad::Error(e));
+            }
         });
         Self {
             thread,
-            recv: r2,
-            send: s,
-            performance: 0,
-            running: false,
-            done: false,
             path: p2,
+            config: config2,
+            stats: DiskStats::default(),
+            error: None,
+            direct: false,
+            kernel_device: None,
+            kernel: vec![],
+            kernel_started: None,
+        }
+    }
+
+    /// Change how the disk is loaded, this takes effect the next time the load is started.
+    pub fn configure(&mut self, config: DiskConfig) {
+        self.config = config.clone();
+        self.thread.send(MessageToDiskLoad::Configure(config));
+    }
+}
+
+impl Workload for DiskLoad {
+    fn kind(&self) -> WorkloadKind {
+        WorkloadKind::Disk
+    }
+
+    fn target(&self) -> String {
+        format!("{} ({})", self.path.display(), self.config)
+    }
+
+    fn units(&self) -> &'static str {
+        match self.config.sync {
+            Some(_) => "syncs/s",
+            None => "MB/s",
         }
     }
+
+    fn control(&self) -> &dyn WorkloadControl {
+        &self.thread
+    }
+
+    fn control_mut(&mut self) -> &mut dyn WorkloadControl {
+        &mut self.thread
+    }
+
+    fn process_messages(&mut self) {
+        let messages = self.thread.process_messages();
+        if self.thread.started != self.kernel_started {
+            self.kernel_started = self.thread.started;
+            self.kernel.clear();
+        }
+        for message in messages {
+            match message {
+                MessageFromDiskLoad::Stats(s) => {
+                    self.stats = s;
+                }
+                MessageFromDiskLoad::Direct(d) => {
+                    self.direct = d;
+                }
+                MessageFromDiskLoad::KernelDevice(d) => {
+                    self.kernel_device = Some(d);
+                }
+                MessageFromDiskLoad::Kernel(k) => {
+                    self.kernel.push(k);
+                }
+                MessageFromDiskLoad::Error(e) => {
+                    println!("{}", e);
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+
+    fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+
+    fn details(&self) -> BTreeMap<String, f64> {
+        let s = &self.stats;
+        let mut d = BTreeMap::new();
+        d.insert("total_bytes".to_string(), s.total_bytes as f64);
+        d.insert("total_ops".to_string(), s.total_ops as f64);
+        if s.written_bytes > 0 {
+            d.insert("written_bytes".to_string(), s.written_bytes as f64);
+        }
+        d.insert("direct".to_string(), self.direct as u8 as f64);
+        if s.total_ops > 0 {
+            let rate = match self.config.sync {
+                Some(_) => "syncs_per_second",
+                None => "iops",
+            };
+            d.insert(
+                rate.to_string(),
+                s.total_ops as f64 / s.run_time.as_secs_f64(),
+            );
+            d.insert(
+                "mean_latency_us".to_string(),
+                s.total_latency.as_secs_f64() * 1e6 / s.total_ops as f64,
+            );
+            s.percentiles.add_details(&mut d);
+        }
+        KernelDiskSample::add_details(&self.kernel, &mut d);
+        d
+    }
+
+    fn series(&self) -> BTreeMap<String, Vec<Sample>> {
+        KernelDiskSample::series(&self.kernel)
+    }
 }
@@ -0,0 +1,454 @@
+//! Disk loads described by job files, so that the tests already written for
+//! fio can be reused.
+//!
+//! Both the ini format used by fio and an equivalent TOML format are read.
+//! Every section other than `global` is a job, the options in `global`
+//! apply to every job and can be overridden by the job itself. Options are
+//! applied in the order they are given, so a later option wins over an
+//! earlier one that it conflicts with, such as `direct` and `buffered`.
+//!
+//! ```ini
+//! [global]
+//! bs=4k
+//! runtime=30
+//!
+//! [random-mix]
+//! filename=/dev/sdb
+//! rw=randrw
+//! rwmixread=70
+//! ioengine=io_uring
+//! iodepth=32
+//! numjobs=4
+//! size=8G
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    cli::parse_size,
+    disk::{DiskConfig, DiskEngine, DiskPattern, DiskSync},
+};
+
+/// Options that fio files commonly contain which do not change how the load is run.
+const IGNORED_OPTIONS: &[&str] = &["name", "group_reporting", "thread"];
+
+/// A disk load read from a job file.
+#[derive(Clone, Debug)]
+pub struct DiskJob {
+    pub name: String,
+    /// The disk, partition or file to load, or a directory to create a test file in
+    pub path: PathBuf,
+    pub config: DiskConfig,
+}
+
+/// The options of one section of a job file, in the order they were given.
+type Section = (String, Vec<(String, String)>);
+
+/// Read every job from a job file, the format is picked from the extension.
+pub fn load(path: &Path) -> Result<Vec<DiskJob>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let sections = if path.extension().map_or(false, |e| e == "toml") {
+        parse_toml(&text)
+    } else {
+        parse_ini(&text)
+    }
+    .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let global: Vec<(String, String)> = sections
+        .iter()
+        .filter(|(name, _)| name == "global")
+        .flat_map(|(_, options)| options.iter().cloned())
+        .collect();
+    let jobs = sections
+        .iter()
+        .filter(|(name, _)| name != "global")
+        .map(|(name, options)| {
+            let merged: Vec<(String, String)> =
+                global.iter().chain(options.iter()).cloned().collect();
+            job(name, &merged).map_err(|e| format!("{}: job {}: {}", path.display(), name, e))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if jobs.is_empty() {
+        return Err(format!("{} does not contain any jobs", path.display()));
+    }
+    Ok(jobs)
+}
+
+/// Parse the ini format used by fio. Options without a value, such as
+/// `time_based`, are flags that are turned on.
+fn parse_ini(text: &str) -> Result<Vec<Section>, String> {
+    let mut sections: Vec<Section> = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((name.trim().to_string(), vec![]));
+            continue;
+        }
+        let (key, value) = line.split_once('=').unwrap_or((line, "1"));
+        let (_, options) = sections
+            .last_mut()
+            .ok_or_else(|| format!("line {}: option outside of a job", number + 1))?;
+        options.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(sections)
+}
+
+/// Parse a TOML file with a table for each job, using the same option names as fio.
+/// Jobs and their options keep the order of the file, as they do in a fio job file.
+fn parse_toml(text: &str) -> Result<Vec<Section>, String> {
+    let table: toml::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut sections = vec![];
+    for (name, value) in table {
+        let toml::Value::Table(t) = value else {
+            return Err(format!("{} is not a job", name));
+        };
+        let options = t
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    toml::Value::String(s) => s,
+                    toml::Value::Boolean(b) => (b as u8).to_string(),
+                    v => v.to_string(),
+                };
+                (key, value)
+            })
+            .collect();
+        sections.push((name, options));
+    }
+    Ok(sections)
+}
+
+/// Parse a size as fio writes it, which also allows suffixes such as `KiB` or `kb`.
+fn parse_job_size(value: &str) -> Result<u64, String> {
+    let lower = value.to_ascii_lowercase();
+    let trimmed = lower
+        .strip_suffix("ib")
+        .or_else(|| lower.strip_suffix('b'))
+        .unwrap_or(&lower);
+    parse_size(trimmed)
+}
+
+/// Parse a time in seconds, or with a `ms`, `s`, `m` or `h` suffix.
+fn parse_runtime(value: &str) -> Result<Duration, String> {
+    let (number, scale) = if let Some(n) = value.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = value.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = value.strip_suffix('m') {
+        (n, 60.0)
+    } else if let Some(n) = value.strip_suffix('h') {
+        (n, 3600.0)
+    } else {
+        (value, 1.0)
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(Duration::from_secs_f64(n * scale)),
+        _ => Err(format!("Invalid runtime {}", value)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} {}", key, value))
+}
+
+/// Build a job from its options in the order they were given, anything not
+/// given keeps the default of fio.
+fn job(name: &str, options: &[(String, String)]) -> Result<DiskJob, String> {
+    let mut config = DiskConfig {
+        block_size: 4096,
+        direct: false,
+        time_based: false,
+        ..Default::default()
+    };
+    let mut filename = None;
+    let mut directory = None;
+    let mut mixed = false;
+    let mut read_percent = 50;
+    for (key, value) in options {
+        match key.as_str() {
+            "filename" => filename = Some(PathBuf::from(value)),
+            "directory" => directory = Some(PathBuf::from(value)),
+            "rw" | "readwrite" => {
+                // An optional `:N` suffix on the pattern is not supported
+                let rw = value.split(':').next().unwrap_or_default();
+                let (pattern, percent) = match rw {
+                    "read" => (DiskPattern::Sequential, Some(100)),
+                    "write" => (DiskPattern::Sequential, Some(0)),
+                    "randread" => (DiskPattern::Random, Some(100)),
+                    "randwrite" => (DiskPattern::Random, Some(0)),
+                    "rw" | "readwrite" => (DiskPattern::Sequential, None),
+                    "randrw" => (DiskPattern::Random, None),
+                    _ => return Err(format!("Unsupported rw {}", value)),
+                };
+                config.pattern = pattern;
+                mixed = percent.is_none();
+                config.read_percent = percent.unwrap_or(0);
+            }
+            "rwmixread" => read_percent = parse_number::<u8>(key, value)?.min(100),
+            "rwmixwrite" => read_percent = 100 - parse_number::<u8>(key, value)?.min(100),
+            "bs" | "blocksize" => {
+                let size = parse_job_size(value)?;
+                if size == 0 || size > 1 << 30 {
+                    return Err(format!("Invalid block size {}", value));
+                }
+                config.block_size = size as usize;
+            }
+            "iodepth" => config.queue_depth = parse_number::<usize>(key, value)?.max(1),
+            "numjobs" => config.jobs = parse_number::<usize>(key, value)?.max(1),
+            "size" => {
+                let size = parse_job_size(value)?;
+                config.size = Some(size);
+                config.file_size = size;
+            }
+            "runtime" => config.runtime = Some(parse_runtime(value)?),
+            "time_based" => config.time_based = value != "0",
+            "ioengine" => {
+                config.engine = match value.as_str() {
+                    "libaio" | "posixaio" => {
+                        println!("Job {} uses io_uring in place of {}", name, value);
+                        DiskEngine::IoUring
+                    }
+                    v => v.parse::<DiskEngine>()?,
+                }
+            }
+            "fsync" | "fdatasync" => {
+                let every = parse_number::<usize>(key, value)?;
+                if every > 1 {
+                    println!("Job {} syncs after every write, not every {}", name, every);
+                }
+                if every > 0 {
+                    config.sync = Some(key.parse::<DiskSync>()?);
+                }
+            }
+            "direct" => config.direct = value != "0",
+            "buffered" => config.direct = value == "0",
+            k if IGNORED_OPTIONS.contains(&k) => {}
+            _ => println!("Ignoring unsupported option {} in job {}", key, name),
+        }
+    }
+    if mixed {
+        config.read_percent = read_percent;
+    }
+    // As in fio, syncing only follows writes, a job that only reads never syncs
+    if config.read_percent == 100 && config.sync.take().is_some() {
+        println!("Job {} only reads, its sync option is ignored", name);
+    }
+    // As in fio, the file is looked for in the directory when both are given
+    let path = match (directory, filename) {
+        (Some(d), Some(f)) => d.join(f),
+        (None, Some(f)) => f,
+        (Some(d), None) => d,
+        (None, None) => return Err("No filename or directory given".to_string()),
+    };
+    Ok(DiskJob {
+        name: name.to_string(),
+        path,
+        config,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load `text` as a job file named `name`, whose extension picks the format.
+    fn load_text(name: &str, text: &str) -> Result<Vec<DiskJob>, String> {
+        let path = std::env::temp_dir().join(format!("benchmark-{}-{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let jobs = load(&path);
+        let _e = std::fs::remove_file(&path);
+        jobs
+    }
+
+    #[test]
+    fn ini_jobs() {
+        let jobs = load_text(
+            "ini_jobs.fio",
+            "; comment\n\
+             [global]\n\
+             bs=64k\n\
+             runtime=30\n\
+             direct=1\n\
+             \n\
+             [random-mix]\n\
+             filename=/dev/sdb\n\
+             rw=randrw\n\
+             rwmixread=70\n\
+             ioengine=io_uring\n\
+             iodepth=32\n\
+             numjobs=4\n\
+             size=8G\n\
+             time_based\n\
+             \n\
+             [seq-read]\n\
+             filename=/dev/sdc\n\
+             rw=read\n",
+        )
+        .unwrap();
+        assert_eq!(jobs.len(), 2);
+        let mix = &jobs[0];
+        assert_eq!(mix.name, "random-mix");
+        assert_eq!(mix.path, PathBuf::from("/dev/sdb"));
+        assert_eq!(mix.config.pattern, DiskPattern::Random);
+        assert_eq!(mix.config.read_percent, 70);
+        assert_eq!(mix.config.block_size, 64 << 10);
+        assert_eq!(mix.config.engine, DiskEngine::IoUring);
+        assert_eq!(mix.config.queue_depth, 32);
+        assert_eq!(mix.config.jobs, 4);
+        assert_eq!(mix.config.size, Some(8 << 30));
+        assert_eq!(mix.config.runtime, Some(Duration::from_secs(30)));
+        assert!(mix.config.direct);
+        assert!(mix.config.time_based);
+        let read = &jobs[1];
+        assert_eq!(read.config.pattern, DiskPattern::Sequential);
+        assert_eq!(read.config.read_percent, 100);
+        assert_eq!(read.config.block_size, 64 << 10);
+        assert!(!read.config.time_based);
+    }
+
+    #[test]
+    fn later_options_win() {
+        let jobs = load_text(
+            "later_options_win.fio",
+            "[global]\n\
+             rw=write\n\
+             direct=1\n\
+             fsync=1\n\
+             bs=4k\n\
+             [buffered]\n\
+             filename=a\n\
+             buffered=1\n\
+             fdatasync=1\n\
+             [direct-again]\n\
+             filename=b\n\
+             buffered=1\n\
+             direct=1\n\
+             bs=8k\n",
+        )
+        .unwrap();
+        assert!(!jobs[0].config.direct);
+        assert_eq!(jobs[0].config.sync, Some(DiskSync::Fdatasync));
+        assert_eq!(jobs[0].config.block_size, 4096);
+        assert!(jobs[1].config.direct);
+        assert_eq!(jobs[1].config.sync, Some(DiskSync::Fsync));
+        assert_eq!(jobs[1].config.block_size, 8192);
+    }
+
+    #[test]
+    fn mix_is_applied_whatever_the_order() {
+        let jobs = load_text("mix_order.fio", "[job]\nfilename=a\nrwmixwrite=30\nrw=rw\n").unwrap();
+        assert_eq!(jobs[0].config.pattern, DiskPattern::Sequential);
+        assert_eq!(jobs[0].config.read_percent, 70);
+    }
+
+    #[test]
+    fn reads_are_not_synced() {
+        let jobs = load_text(
+            "read_sync.fio",
+            "[global]\nfsync=1\n[read]\nfilename=a\nrw=randread\n[mix]\nfilename=a\nrw=randrw\n",
+        )
+        .unwrap();
+        assert_eq!(jobs[0].config.sync, None);
+        assert_eq!(jobs[0].config.read_percent, 100);
+        assert_eq!(jobs[1].config.sync, Some(DiskSync::Fsync));
+    }
+
+    #[test]
+    fn filename_is_in_directory() {
+        let jobs = load_text(
+            "directory.fio",
+            "[global]\ndirectory=/mnt/test\n[a]\nfilename=file\n[b]\n",
+        )
+        .unwrap();
+        assert_eq!(jobs[0].path, PathBuf::from("/mnt/test/file"));
+        assert_eq!(jobs[1].path, PathBuf::from("/mnt/test"));
+    }
+
+    #[test]
+    fn other_engines_use_io_uring() {
+        for engine in ["libaio", "posixaio"] {
+            let text = format!("[job]\nfilename=a\nioengine={}\n", engine);
+            let jobs = load_text("engine.fio", &text).unwrap();
+            assert_eq!(jobs[0].config.engine, DiskEngine::IoUring);
+        }
+        let jobs = load_text("engine.fio", "[job]\nfilename=a\nioengine=psync\n").unwrap();
+        assert_eq!(jobs[0].config.engine, DiskEngine::Sync);
+        assert!(load_text("engine.fio", "[job]\nfilename=a\nioengine=mmap\n").is_err());
+    }
+
+    #[test]
+    fn toml_jobs() {
+        let jobs = load_text(
+            "toml_jobs.toml",
+            "[global]\n\
+             bs = \"16k\"\n\
+             direct = true\n\
+             \n\
+             [write]\n\
+             filename = \"/tmp/file\"\n\
+             rw = \"randwrite\"\n\
+             iodepth = 8\n\
+             direct = false\n\
+             runtime = \"2m\"\n",
+        )
+        .unwrap();
+        assert_eq!(jobs.len(), 1);
+        let job = &jobs[0];
+        assert_eq!(job.name, "write");
+        assert_eq!(job.config.pattern, DiskPattern::Random);
+        assert_eq!(job.config.read_percent, 0);
+        assert_eq!(job.config.block_size, 16 << 10);
+        assert_eq!(job.config.queue_depth, 8);
+        assert!(!job.config.direct);
+        assert_eq!(job.config.runtime, Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn toml_jobs_keep_file_order() {
+        let jobs = load_text(
+            "toml_order.toml",
+            "[zeta]\nfilename = \"a\"\n[alpha]\nfilename = \"b\"\n[middle]\nfilename = \"c\"\n",
+        )
+        .unwrap();
+        let names: Vec<&str> = jobs.iter().map(|j| j.name.as_str()).collect();
+        assert_eq!(names, ["zeta", "alpha", "middle"]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let cases = [
+            ("no_jobs.fio", "[global]\nbs=4k\n"),
+            ("outside.fio", "bs=4k\n[job]\nfilename=a\n"),
+            ("no_path.fio", "[job]\nrw=read\n"),
+            ("bad_rw.fio", "[job]\nfilename=a\nrw=trim\n"),
+            ("bad_bs.fio", "[job]\nfilename=a\nbs=0\n"),
+            ("bad_toml.toml", "[job\n"),
+            ("not_a_job.toml", "bs = \"4k\"\n"),
+        ];
+        for (name, text) in cases {
+            assert!(load_text(name, text).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn sizes_and_runtimes() {
+        assert_eq!(parse_job_size("4KiB"), Ok(4096));
+        assert_eq!(parse_job_size("1mb"), Ok(1 << 20));
+        assert_eq!(parse_job_size("2g"), Ok(2 << 30));
+        assert_eq!(parse_runtime("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_runtime("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_runtime("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_runtime("1h"), Ok(Duration::from_secs(3600)));
+        for runtime in ["0", "-1", "soon", "inf"] {
+            assert!(parse_runtime(runtime).is_err(), "{}", runtime);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! The i/o statistics the kernel keeps for each block device, used to
+//! cross-check what a disk load measures and to spot other i/o on the device.
+
+use std::{collections::BTreeMap, path::Path, time::Instant};
+
+use crate::results::Sample;
+
+/// The counters from `/sys/block/<dev>/stat`, see the kernel documentation
+/// of the block layer statistics for the meaning of each field.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelDiskStats {
+    pub reads: u64,
+    pub reads_merged: u64,
+    pub sectors_read: u64,
+    /// Milliseconds spent reading
+    pub read_ticks: u64,
+    pub writes: u64,
+    pub writes_merged: u64,
+    pub sectors_written: u64,
+    /// Milliseconds spent writing
+    pub write_ticks: u64,
+    pub in_flight: u64,
+    /// Milliseconds during which the device had i/o in flight
+    pub io_ticks: u64,
+    /// Milliseconds of i/o weighted by the number in flight
+    pub time_in_queue: u64,
+}
+
+impl KernelDiskStats {
+    fn parse<'a>(fields: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let v: Vec<u64> = fields
+            .take(11)
+            .map(|f| f.parse().ok())
+            .collect::<Option<_>>()?;
+        if v.len() < 11 {
+            return None;
+        }
+        Some(Self {
+            reads: v[0],
+            reads_merged: v[1],
+            sectors_read: v[2],
+            read_ticks: v[3],
+            writes: v[4],
+            writes_merged: v[5],
+            sectors_written: v[6],
+            write_ticks: v[7],
+            in_flight: v[8],
+            io_ticks: v[9],
+            time_in_queue: v[10],
+        })
+    }
+
+    /// Read the statistics for a device or partition, such as `sda` or `nvme0n1p1`.
+    pub fn read(name: &str) -> Option<Self> {
+        if let Ok(s) = std::fs::read_to_string(format!("/sys/class/block/{}/stat", name)) {
+            return Self::parse(s.split_whitespace());
+        }
+        let s = std::fs::read_to_string("/proc/diskstats").ok()?;
+        Self::from_diskstats(&s, name)
+    }
+
+    /// Find the statistics of a device in the text of `/proc/diskstats`.
+    fn from_diskstats(text: &str, name: &str) -> Option<Self> {
+        text.lines().find_map(|l| {
+            let mut fields = l.split_whitespace();
+            // major, minor, name and then the same fields as the stat file
+            (fields.nth(2)? == name).then(|| Self::parse(fields))?
+        })
+    }
+}
+
+/// The name of the block device that holds `path`, which is the device
+/// itself for a block device and the disk or partition holding the
+/// filesystem for anything else.
+#[cfg(target_os = "linux")]
+pub fn device_name(path: &Path) -> Option<String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let m = std::fs::metadata(path).ok()?;
+    let dev = if m.file_type().is_block_device() {
+        m.rdev()
+    } else {
+        m.dev()
+    };
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let link = std::fs::read_link(format!("/sys/dev/block/{}:{}", major, minor)).ok()?;
+    Some(link.file_name()?.to_string_lossy().to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn device_name(_path: &Path) -> Option<String> {
+    None
+}
+
+/// What the kernel saw on a device between two readings.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelDiskSample {
+    /// Seconds since the load was started
+    pub time: f64,
+    pub read_iops: f64,
+    pub write_iops: f64,
+    pub read_mbps: f64,
+    pub write_mbps: f64,
+    /// Percent of the time the device was busy
+    pub utilisation: f64,
+    /// The average number of requests in the queue
+    pub queue: f64,
+    pub in_flight: u64,
+}
+
+impl KernelDiskSample {
+    /// What happened between two readings taken `seconds` apart, the later one
+    /// `time` seconds after the load was started.
+    fn between(old: &KernelDiskStats, new: &KernelDiskStats, seconds: f64, time: f64) -> Self {
+        // The counters are sectors of 512 bytes and milliseconds, whatever the device
+        let rate = |new: u64, old: u64| new.wrapping_sub(old) as f64 / seconds;
+        Self {
+            time,
+            read_iops: rate(new.reads, old.reads),
+            write_iops: rate(new.writes, old.writes),
+            read_mbps: rate(new.sectors_read, old.sectors_read) * 512.0 / 1e6,
+            write_mbps: rate(new.sectors_written, old.sectors_written) * 512.0 / 1e6,
+            utilisation: rate(new.io_ticks, old.io_ticks) / 10.0,
+            queue: rate(new.time_in_queue, old.time_in_queue) / 1000.0,
+            in_flight: new.in_flight,
+        }
+    }
+
+    /// Turn samples into one series per measurement, for the results of a run.
+    pub fn series(samples: &[Self]) -> BTreeMap<String, Vec<Sample>> {
+        let fields: [(&str, fn(&Self) -> f64); 6] = [
+            ("kernel_read_iops", |s| s.read_iops),
+            ("kernel_write_iops", |s| s.write_iops),
+            ("kernel_read_mb_s", |s| s.read_mbps),
+            ("kernel_write_mb_s", |s| s.write_mbps),
+            ("kernel_utilisation_percent", |s| s.utilisation),
+            ("kernel_queue", |s| s.queue),
+        ];
+        let mut series = BTreeMap::new();
+        if samples.is_empty() {
+            return series;
+        }
+        for (name, f) in fields {
+            let values = samples
+                .iter()
+                .map(|s| Sample {
+                    time: s.time,
+                    value: f(s),
+                })
+                .collect();
+            series.insert(name.to_string(), values);
+        }
+        series
+    }
+
+    /// Add the averages over a run to the details of a result.
+    pub fn add_details(samples: &[Self], details: &mut BTreeMap<String, f64>) {
+        if samples.is_empty() {
+            return;
+        }
+        let mean = |f: fn(&Self) -> f64| samples.iter().map(f).sum::<f64>() / samples.len() as f64;
+        details.insert("kernel_read_mb_s".to_string(), mean(|s| s.read_mbps));
+        details.insert("kernel_write_mb_s".to_string(), mean(|s| s.write_mbps));
+        details.insert(
+            "kernel_utilisation_percent".to_string(),
+            mean(|s| s.utilisation),
+        );
+        details.insert("kernel_queue".to_string(), mean(|s| s.queue));
+    }
+}
+
+impl std::fmt::Display for KernelDiskSample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "read {:.0} IOPS {:.1} MB/s, write {:.0} IOPS {:.1} MB/s, {:.0}% busy, queue {:.1}",
+            self.read_iops,
+            self.read_mbps,
+            self.write_iops,
+            self.write_mbps,
+            self.utilisation,
+            self.queue
+        )
+    }
+}
+
+/// Takes periodic readings of a device and turns them into samples.
+pub struct KernelDiskSampler {
+    name: String,
+    start: Instant,
+    last: Option<(Instant, KernelDiskStats)>,
+}
+
+impl KernelDiskSampler {
+    /// A sampler for the device holding `path`, if the kernel keeps statistics for it.
+    pub fn new(path: &Path) -> Option<Self> {
+        let name = device_name(path)?;
+        KernelDiskStats::read(&name)?;
+        Some(Self {
+            name,
+            start: Instant::now(),
+            last: None,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Start over, the times of samples are relative to this.
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.last = KernelDiskStats::read(&self.name).map(|s| (self.start, s));
+    }
+
+    /// Take a reading, returning what happened since the previous one.
+    pub fn sample(&mut self) -> Option<KernelDiskSample> {
+        let now = Instant::now();
+        let stats = KernelDiskStats::read(&self.name)?;
+        let previous = self.last.replace((now, stats));
+        let (then, old) = previous?;
+        let seconds = (now - then).as_secs_f64();
+        if seconds <= 0.0 {
+            return None;
+        }
+        Some(KernelDiskSample::between(
+            &old,
+            &stats,
+            seconds,
+            (now - self.start).as_secs_f64(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_stat_file() {
+        // Newer kernels add discard and flush counters after the first 11 fields
+        let line =
+            "  4096  12  262144  800  1024  3  65536  400  2  1000  1300  0  0  0  0  7  9\n";
+        let s = KernelDiskStats::parse(line.split_whitespace()).unwrap();
+        assert_eq!(s.reads, 4096);
+        assert_eq!(s.reads_merged, 12);
+        assert_eq!(s.sectors_read, 262144);
+        assert_eq!(s.read_ticks, 800);
+        assert_eq!(s.writes, 1024);
+        assert_eq!(s.sectors_written, 65536);
+        assert_eq!(s.in_flight, 2);
+        assert_eq!(s.io_ticks, 1000);
+        assert_eq!(s.time_in_queue, 1300);
+    }
+
+    #[test]
+    fn short_or_garbled_stat_files() {
+        assert!(KernelDiskStats::parse("1 2 3 4 5 6 7 8 9 10".split_whitespace()).is_none());
+        assert!(KernelDiskStats::parse("1 2 3 4 5 6 7 x 9 10 11".split_whitespace()).is_none());
+        assert!(KernelDiskStats::parse("".split_whitespace()).is_none());
+    }
+
+    #[test]
+    fn finds_devices_in_diskstats() {
+        let text = concat!(
+            "   8       0 sda 10 0 80 5 20 0 160 6 0 11 12\n",
+            "   8       1 sda1 1 0 8 1 2 0 16 1 0 2 3\n",
+        );
+        let sda1 = KernelDiskStats::from_diskstats(text, "sda1").unwrap();
+        assert_eq!((sda1.reads, sda1.writes, sda1.time_in_queue), (1, 2, 3));
+        assert_eq!(
+            KernelDiskStats::from_diskstats(text, "sda").unwrap().reads,
+            10
+        );
+        assert!(KernelDiskStats::from_diskstats(text, "sdb").is_none());
+    }
+
+    #[test]
+    fn rates_between_readings() {
+        let old = KernelDiskStats {
+            reads: 100,
+            sectors_read: 2048,
+            writes: 50,
+            sectors_written: 0,
+            io_ticks: 1000,
+            time_in_queue: 4000,
+            ..Default::default()
+        };
+        let new = KernelDiskStats {
+            reads: 300,
+            sectors_read: 2048 + 1953125,
+            writes: 50,
+            sectors_written: 0,
+            io_ticks: 2500,
+            time_in_queue: 10000,
+            in_flight: 4,
+            ..Default::default()
+        };
+        let s = KernelDiskSample::between(&old, &new, 2.0, 7.0);
+        assert_eq!(s.time, 7.0);
+        assert_eq!(s.read_iops, 100.0);
+        assert_eq!(s.write_iops, 0.0);
+        assert_eq!(s.read_mbps, 500.0);
+        assert_eq!(s.utilisation, 75.0);
+        assert_eq!(s.queue, 3.0);
+        assert_eq!(s.in_flight, 4);
+    }
+}
@@ -0,0 +1,159 @@
+//! Finding the things a disk load can read: whole disks, their partitions,
+//! or a test file in a directory.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::disk::Rng;
+
+/// The name of the file created when a directory is given as a disk target.
+pub const TEST_FILE_NAME: &str = "benchmark-testfile";
+
+pub struct Partition {
+    pub name: String,
+    pub path: PathBuf,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// A whole disk.
+pub struct BlockDevice {
+    pub name: String,
+    pub path: PathBuf,
+    pub model: String,
+    /// Size in bytes
+    pub size: u64,
+    /// A spinning disk rather than a solid state one
+    pub rotational: bool,
+    pub partitions: Vec<Partition>,
+}
+
+impl std::fmt::Display for BlockDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} ({:.1} GB, {})",
+            self.path.display(),
+            self.model,
+            self.size as f64 / 1e9,
+            if self.rotational { "hdd" } else { "ssd" }
+        )
+    }
+}
+
+/// Read a single value from a sysfs file.
+#[cfg(target_os = "linux")]
+fn sysfs(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// The size of a block device from its sysfs directory, which is always
+/// given in 512 byte sectors.
+#[cfg(target_os = "linux")]
+fn sysfs_size(dir: &Path) -> u64 {
+    sysfs(&dir.join("size"))
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(0)
+        * 512
+}
+
+/// Every disk in the system, leaving out virtual devices such as loop and ram disks.
+#[cfg(target_os = "linux")]
+pub fn block_devices() -> Vec<BlockDevice> {
+    let mut devices = vec![];
+    let Ok(entries) = std::fs::read_dir("/sys/block") else {
+        return devices;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let dir = entry.path();
+        let size = sysfs_size(&dir);
+        if size == 0 || ["loop", "ram", "zram"].iter().any(|p| name.starts_with(p)) {
+            continue;
+        }
+        let mut partitions = vec![];
+        if let Ok(children) = std::fs::read_dir(&dir) {
+            for child in children.flatten() {
+                let child_dir = child.path();
+                if child_dir.join("partition").exists() {
+                    let name = child.file_name().to_string_lossy().to_string();
+                    partitions.push(Partition {
+                        path: PathBuf::from("/dev").join(&name),
+                        size: sysfs_size(&child_dir),
+                        name,
+                    });
+                }
+            }
+        }
+        partitions.sort_by(|a, b| a.name.cmp(&b.name));
+        devices.push(BlockDevice {
+            path: PathBuf::from("/dev").join(&name),
+            model: sysfs(&dir.join("device/model")).unwrap_or_default(),
+            rotational: sysfs(&dir.join("queue/rotational")).as_deref() == Some("1"),
+            size,
+            partitions,
+            name,
+        });
+    }
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    devices
+}
+
+/// Every mounted volume, windows only gives raw access to volumes by their mount point.
+#[cfg(target_os = "windows")]
+pub fn block_devices() -> Vec<BlockDevice> {
+    use sysinfo::{DiskExt, SystemExt};
+    let mut sinfo = sysinfo::System::new();
+    sinfo.refresh_disks_list();
+    sinfo
+        .disks()
+        .iter()
+        .map(|disk| BlockDevice {
+            name: disk.mount_point().display().to_string(),
+            path: disk.mount_point().to_path_buf(),
+            model: disk.name().to_string_lossy().to_string(),
+            size: disk.total_space(),
+            rotational: disk.kind() == sysinfo::DiskKind::HDD,
+            partitions: vec![],
+        })
+        .collect()
+}
+
+/// Create a test file of `size` bytes in `dir`, filled with data so that
+/// reading it has to go to the disk. An existing test file of the right size
+/// is reused.
+pub fn prepare_test_file(dir: &Path, size: u64) -> std::io::Result<PathBuf> {
+    let path = dir.join(TEST_FILE_NAME);
+    if std::fs::metadata(&path)
+        .map(|m| m.len() == size)
+        .unwrap_or(false)
+    {
+        return Ok(path);
+    }
+    create_test_file(&path, size)?;
+    Ok(path)
+}
+
+/// Create a test file of `size` bytes at `path`, replacing anything already there.
+pub fn create_test_file(path: &Path, size: u64) -> std::io::Result<()> {
+    println!("Creating a {} byte test file at {}", size, path.display());
+    let mut f = std::fs::File::create(path)?;
+    let mut rng = Rng::new(size);
+    let mut buf = vec![0; 1 << 20];
+    let mut written = 0;
+    while written < size {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&rng.next().to_le_bytes());
+        }
+        let amount = (size - written).min(buf.len() as u64) as usize;
+        f.write_all(&buf[..amount])?;
+        written += amount as u64;
+    }
+    f.sync_all()?;
+    crate::diskverify::drop_cache(&f);
+    Ok(())
+}
@@ -0,0 +1,358 @@
+//! A disk load that writes known patterns and reads them back, for burning
+//! in new drives and catching bad cables or controllers.
+//!
+//! Every block written contains data generated from its offset and the pass
+//! number, so data that ends up at the wrong offset or is left over from a
+//! previous pass is also detected.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    disk::{format_size, Rng},
+    workload::{Workload, WorkloadControl, WorkloadKind, WorkloadThread},
+};
+
+/// How often the throughput is reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How a write and verify load is run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyConfig {
+    /// The size of each write in bytes
+    pub block_size: usize,
+    /// How much of the target is written on each pass, in bytes
+    pub size: u64,
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        Self {
+            block_size: 1 << 20,
+            size: 1 << 30,
+        }
+    }
+}
+
+/// Running totals for a write and verify load since it was started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VerifyStats {
+    pub bytes_written: u64,
+    pub bytes_verified: u64,
+    /// The number of blocks that did not read back what was written
+    pub corrupt_blocks: u64,
+    /// The number of times the whole area has been written and verified
+    pub passes: u64,
+}
+
+pub enum MessageFromDiskVerify {
+    Stats(VerifyStats),
+    /// A block at the given offset did not read back what was written
+    Corruption(u64),
+    /// The target could not be opened or written
+    Error(String),
+}
+
+/// Check that a path is safe to write to. Paths that do not exist yet, and the
+/// test files the benchmark creates, are always allowed. Anything else, such as
+/// a block device or a file with data of its own, is only allowed when it has
+/// been confirmed as scratch space whose contents may be destroyed.
+pub fn check_target(path: &Path, scratch_device: bool) -> Result<(), String> {
+    let test_file = path.file_name() == Some(crate::disktarget::TEST_FILE_NAME.as_ref());
+    match std::fs::metadata(path) {
+        Ok(m) if m.is_dir() => Err(format!("{} is a directory", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Ok(m) if m.is_file() && test_file => Ok(()),
+        _ if scratch_device => Ok(()),
+        Ok(m) if m.is_file() => Err(format!(
+            "{} already exists, its contents would be destroyed. Use --scratch-device to confirm",
+            path.display()
+        )),
+        _ => Err(format!(
+            "{} is not a regular file, its contents would be destroyed. Use --scratch-device to confirm",
+            path.display()
+        )),
+    }
+}
+
+/// Fill a block with the data expected at `offset` during `pass`.
+fn fill_pattern(buf: &mut [u8], offset: u64, pass: u64) {
+    let mut rng = Rng::new(offset ^ (pass + 1).wrapping_mul(0x9e3779b97f4a7c15));
+    for chunk in buf.chunks_mut(8) {
+        let v = rng.next().to_le_bytes();
+        chunk.copy_from_slice(&v[..chunk.len()]);
+    }
+}
+
+/// Ask the os to forget the cached contents of the file, so that verifying
+/// reads the data back from the device.
+#[cfg(target_os = "linux")]
+pub fn drop_cache(f: &std::fs::File) {
+    use std::os::unix::io::AsRawFd;
+    unsafe {
+        libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_cache(_f: &std::fs::File) {}
+
+/// Whether `drop_cache` works on this os. Without it, verifying would only
+/// read back what the os cached and never check the device.
+pub const CAN_DROP_CACHE: bool = cfg!(target_os = "linux");
+
+/// Open the target and work out how much of it will be written.
+fn open_target(p: &Path, config: &VerifyConfig) -> std::io::Result<(std::fs::File, u64)> {
+    let mut f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(p)?;
+    let size = if f.metadata()?.is_file() {
+        if f.metadata()?.len() < config.size {
+            f.set_len(config.size)?;
+        }
+        config.size
+    } else {
+        config.size.min(f.seek(SeekFrom::End(0))?)
+    };
+    Ok((f, size))
+}
+
+pub struct DiskVerifyLoad {
+    thread: WorkloadThread<(), MessageFromDiskVerify>,
+    pub path: PathBuf,
+    pub config: VerifyConfig,
+    pub stats: VerifyStats,
+    /// The offsets of the most recently found corrupt blocks
+    pub corruption: Vec<u64>,
+    pub error: Option<String>,
+}
+
+impl DiskVerifyLoad {
+    pub fn new(p: &Path, config: VerifyConfig) -> Self {
+        let p = p.to_owned();
+        let p2 = p.clone();
+        let config2 = config.clone();
+        let thread = WorkloadThread::spawn(move |mut ctx| {
+            let (mut f, size) = match open_target(&p, &config) {
+                Ok(t) => t,
+                Err(e) => {
+                    ctx.report(MessageFromDiskVerify::Error(format!(
+                        "Failed to open {}: {}",
+                        p.display(),
+                        e
+                    )));
+                    return;
+                }
+            };
+            let bs = config.block_size as u64;
+            let blocks = size / bs;
+            if blocks == 0 {
+                ctx.report(MessageFromDiskVerify::Error(format!(
+                    "{} is smaller than one block",
+                    p.display()
+                )));
+                return;
+            }
+            println!(
+                "Writing and verifying {} of {}",
+                format_size(blocks * bs),
+                p.display()
+            );
+            let mut buf = vec![0; config.block_size];
+            let mut expected = vec![0; config.block_size];
+            let clock = quanta::Clock::new();
+            let mut stats = VerifyStats::default();
+            let mut written = 0;
+            let mut verifying = false;
+            let mut block = 0;
+            let mut interval_start = clock.raw();
+            let mut was_running = false;
+            while ctx.poll(|_| None) {
+                if !ctx.running() {
+                    was_running = false;
+                    ctx.idle();
+                    continue;
+                }
+                if !was_running {
+                    was_running = true;
+                    stats = VerifyStats::default();
+                    written = 0;
+                    verifying = false;
+                    block = 0;
+                    interval_start = clock.raw();
+                }
+                let offset = block * bs;
+                let result = if verifying {
+                    fill_pattern(&mut expected, offset, stats.passes);
+                    f.seek(SeekFrom::Start(offset))
+                        .and_then(|_| f.read_exact(&mut buf))
+                        .map(|_| {
+                            stats.bytes_verified += bs;
+                            if buf != expected {
+                                stats.corrupt_blocks += 1;
+                                println!(
+                                    "Verification of {} failed at offset {}",
+                                    p.display(),
+                                    offset
+                                );
+                                ctx.report(MessageFromDiskVerify::Corruption(offset));
+                            }
+                        })
+                } else {
+                    fill_pattern(&mut buf, offset, stats.passes);
+                    f.seek(SeekFrom::Start(offset))
+                        .and_then(|_| f.write_all(&buf))
+                        .map(|_| {
+                            stats.bytes_written += bs;
+                            written += bs;
+                        })
+                };
+                if let Err(e) = result {
+                    ctx.report(MessageFromDiskVerify::Error(format!(
+                        "I/O error on {} at offset {}: {}",
+                        p.display(),
+                        offset,
+                        e
+                    )));
+                    return;
+                }
+                block += 1;
+                if block == blocks {
+                    block = 0;
+                    if verifying {
+                        stats.passes += 1;
+                    } else if let Err(e) = f.sync_data() {
+                        ctx.report(MessageFromDiskVerify::Error(format!(
+                            "Failed to sync {}: {}",
+                            p.display(),
+                            e
+                        )));
+                        return;
+                    } else {
+                        drop_cache(&f);
+                    }
+                    verifying = !verifying;
+                }
+                let elapsed = clock.delta(interval_start, clock.raw());
+                if elapsed >= REPORT_INTERVAL {
+                    // Only intervals spent writing say anything about the write speed
+                    if written > 0 {
+                        let mbps = written as f64 / elapsed.as_secs_f64() / 1e6;
+                        if !ctx.performance(mbps) {
+                            break;
+                        }
+                    }
+                    if !ctx.report(MessageFromDiskVerify::Stats(stats)) {
+                        break;
+                    }
+                    written = 0;
+                    interval_start = clock.raw();
+                }
+            }
+            ctx.report(MessageFromDiskVerify::Stats(stats));
+        });
+        Self {
+            thread,
+            path: p2,
+            config: config2,
+            stats: VerifyStats::default(),
+            corruption: vec![],
+            error: None,
+        }
+    }
+}
+
+impl Workload for DiskVerifyLoad {
+    fn kind(&self) -> WorkloadKind {
+        WorkloadKind::Disk
+    }
+
+    fn target(&self) -> String {
+        format!(
+            "{} (write verify {})",
+            self.path.display(),
+            format_size(self.config.block_size as u64)
+        )
+    }
+
+    fn units(&self) -> &'static str {
+        "MB/s"
+    }
+
+    fn control(&self) -> &dyn WorkloadControl {
+        &self.thread
+    }
+
+    fn control_mut(&mut self) -> &mut dyn WorkloadControl {
+        &mut self.thread
+    }
+
+    fn process_messages(&mut self) {
+        for message in self.thread.process_messages() {
+            match message {
+                MessageFromDiskVerify::Stats(s) => {
+                    self.stats = s;
+                }
+                MessageFromDiskVerify::Corruption(offset) => {
+                    if self.corruption.len() >= 100 {
+                        self.corruption.remove(0);
+                    }
+                    self.corruption.push(offset);
+                }
+                MessageFromDiskVerify::Error(e) => {
+                    println!("{}", e);
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+
+    fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+
+    fn details(&self) -> BTreeMap<String, f64> {
+        let s = &self.stats;
+        let mut d = BTreeMap::new();
+        d.insert("bytes_written".to_string(), s.bytes_written as f64);
+        d.insert("bytes_verified".to_string(), s.bytes_verified as f64);
+        d.insert("corrupt_blocks".to_string(), s.corrupt_blocks as f64);
+        d.insert("passes".to_string(), s.passes as f64);
+        d.insert("io_errors".to_string(), self.error.is_some() as u8 as f64);
+        d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn existing_files_need_confirmation() {
+        let dir = std::env::temp_dir().join(format!("benchmark-verify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = dir.join("data");
+        let test_file = dir.join(crate::disktarget::TEST_FILE_NAME);
+        std::fs::write(&data, b"data").unwrap();
+        std::fs::write(&test_file, b"test").unwrap();
+        let results = [
+            check_target(&dir.join("new"), false),
+            check_target(&test_file, false),
+            check_target(&data, false),
+            check_target(&data, true),
+            check_target(&dir, true),
+        ];
+        let _e = std::fs::remove_dir_all(&dir);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_ok());
+        assert!(results[2].is_err());
+        assert!(results[3].is_ok());
+        assert!(results[4].is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! Listeners given by hand as `host:port`, for those that hellos can not
+//! reach such as listeners behind a router or VPN.
+//!
+//! Endpoints that have been used are remembered as a json list in
+//! `<data dir>/listeners.json`, so they only need to be typed once.
+
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs},
+    path::PathBuf,
+};
+
+use network_interface::NetworkInterfaceConfig;
+
+/// Find the address of the listener at `endpoint`, which is a host name or
+/// address with an optional port. Bare IPv6 addresses and names without a
+/// port use `default_port`. Link-local IPv6 addresses are scoped to an
+/// interface by its name or index, as in `[fe80::1%eth0]:5003`.
+pub fn resolve(endpoint: &str, default_port: u16) -> Result<SocketAddr, String> {
+    let endpoint = endpoint.trim();
+    if let Ok(addr) = endpoint.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let invalid_port = || format!("Invalid port in {}", endpoint);
+    let (host, port) = if let Some(rest) = endpoint.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| format!("Missing ] in {}", endpoint))?;
+        match rest {
+            "" => (host, default_port),
+            _ => {
+                let port = rest.strip_prefix(':').ok_or_else(invalid_port)?;
+                (host, port.parse().map_err(|_| invalid_port())?)
+            }
+        }
+    } else if endpoint.matches(':').count() > 1 {
+        // Only a bare IPv6 address has more than one colon
+        (endpoint, default_port)
+    } else {
+        match endpoint.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid_port())?),
+            None => (endpoint, default_port),
+        }
+    };
+    if let Some((ip, scope)) = host.split_once('%') {
+        let ip: Ipv6Addr = ip
+            .parse()
+            .map_err(|_| format!("Only IPv6 addresses have a scope, not {}", ip))?;
+        return Ok(SocketAddr::V6(SocketAddrV6::new(
+            ip,
+            port,
+            0,
+            scope_index(scope)?,
+        )));
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Unable to resolve {}: {}", endpoint, e))?
+        .next()
+        .ok_or_else(|| format!("No address found for {}", endpoint))
+}
+
+/// The index of the interface that `scope` names, or numbers.
+fn scope_index(scope: &str) -> Result<u32, String> {
+    if let Ok(index) = scope.parse() {
+        return Ok(index);
+    }
+    network_interface::NetworkInterface::show()
+        .map_err(|e| format!("Unable to list the interfaces: {}", e))?
+        .into_iter()
+        .find(|n| n.name == scope)
+        .map(|n| n.index)
+        .ok_or_else(|| format!("No interface named {}", scope))
+}
+
+/// The listener endpoints that have been used before.
+pub struct KnownListeners {
+    path: PathBuf,
+    pub endpoints: Vec<String>,
+}
+
+impl KnownListeners {
+    /// Where the list is kept unless told otherwise.
+    pub fn default_path() -> PathBuf {
+        match crate::history::data_dir() {
+            Some(d) => d.join("listeners.json"),
+            None => PathBuf::from("benchmark-listeners.json"),
+        }
+    }
+
+    /// Read the list stored at `path`, which is empty until something is added.
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        let endpoints = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, endpoints })
+    }
+
+    /// Remember `endpoint`, unless it is already known.
+    pub fn add(&mut self, endpoint: &str) -> std::io::Result<()> {
+        let endpoint = endpoint.trim();
+        if self.endpoints.iter().any(|e| e == endpoint) {
+            return Ok(());
+        }
+        self.endpoints.push(endpoint.to_string());
+        self.save()
+    }
+
+    /// Forget `endpoint`.
+    pub fn remove(&mut self, endpoint: &str) -> std::io::Result<()> {
+        self.endpoints.retain(|e| e != endpoint);
+        self.save()
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_vec_pretty(&self.endpoints)?;
+        std::fs::write(&self.path, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        let cases = [
+            ("192.0.2.1:6000", "192.0.2.1:6000"),
+            ("192.0.2.1", "192.0.2.1:5003"),
+            (" 192.0.2.1 ", "192.0.2.1:5003"),
+            ("[2001:db8::1]:6000", "[2001:db8::1]:6000"),
+            ("[2001:db8::1]", "[2001:db8::1]:5003"),
+            ("2001:db8::1", "[2001:db8::1]:5003"),
+            ("fe80::1%3", "[fe80::1%3]:5003"),
+            ("[fe80::1%3]:6000", "[fe80::1%3]:6000"),
+        ];
+        for (endpoint, addr) in cases {
+            assert_eq!(
+                resolve(endpoint, 5003),
+                Ok(addr.parse().unwrap()),
+                "{}",
+                endpoint
+            );
+        }
+    }
+
+    #[test]
+    fn scope_by_interface_name() {
+        let interfaces = network_interface::NetworkInterface::show().unwrap();
+        let Some(interface) = interfaces.first() else {
+            return;
+        };
+        let addr = resolve(&format!("[fe80::1%{}]:6000", interface.name), 5003).unwrap();
+        let SocketAddr::V6(addr) = addr else {
+            panic!("{} is not IPv6", addr);
+        };
+        assert_eq!(addr.scope_id(), interface.index);
+        assert_eq!(addr.port(), 6000);
+    }
+
+    #[test]
+    fn host_names() {
+        let addr = resolve("localhost:6000", 5003).unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 6000);
+        assert_eq!(resolve("localhost", 5003).unwrap().port(), 5003);
+    }
+
+    #[test]
+    fn invalid_endpoints() {
+        for endpoint in [
+            "192.0.2.1:port",
+            "192.0.2.1:70000",
+            "[2001:db8::1]:x",
+            "[2001:db8::1]6000",
+            "[2001:db8::1",
+            "192.0.2.1%3",
+            "fe80::1%no-such-interface",
+        ] {
+            assert!(resolve(endpoint, 5003).is_err(), "{}", endpoint);
+        }
+    }
+
+    #[test]
+    fn known_listeners_are_remembered() {
+        let path =
+            std::env::temp_dir().join(format!("benchmark-{}-listeners.json", std::process::id()));
+        let _e = std::fs::remove_file(&path);
+        let mut known = KnownListeners::open(path.clone()).unwrap();
+        assert!(known.endpoints.is_empty());
+        known.add("192.0.2.1:6000").unwrap();
+        known.add(" 192.0.2.1:6000").unwrap();
+        known.add("listener").unwrap();
+        let mut known = KnownListeners::open(path.clone()).unwrap();
+        assert_eq!(known.endpoints, ["192.0.2.1:6000", "listener"]);
+        known.remove("192.0.2.1:6000").unwrap();
+        let known = KnownListeners::open(path.clone()).unwrap();
+        assert_eq!(known.endpoints, ["listener"]);
+        let _e = std::fs::remove_file(&path);
+    }
+}
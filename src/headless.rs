@@ -0,0 +1,161 @@
+//! Runs the selected loads without a window and reports the results.
+
+use std::{io::Write, time::Duration};
+
+use crate::{
+    cli::Options,
+    history::{self, History},
+    results::RunResult,
+    workload::Workload,
+};
+
+pub fn run(options: &Options, mut workloads: Vec<Box<dyn Workload>>) -> Result<(), String> {
+    if workloads.is_empty() {
+        return Err(
+            "Nothing to run, select at least one of --cpu, --disk, --job, --write-verify or --net"
+                .to_string(),
+        );
+    }
+
+    // Some loads, such as the network, must find a peer before they can be started
+    let discovery = std::time::Instant::now();
+    while workloads.iter().any(|w| !w.ready() && !w.done())
+        && discovery.elapsed() < Duration::from_secs(5)
+    {
+        for w in &mut workloads {
+            w.process_messages();
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    // Loads that could not be started fail the run once the others are done
+    let mut failures = vec![];
+    for w in &mut workloads {
+        w.process_messages();
+        if !w.ready() {
+            println!("Unable to start {} load on {}", w.kind(), w.target());
+            failures.push(match w.error() {
+                Some(e) => format!("Unable to start {} load on {}: {}", w.kind(), w.target(), e),
+                None => format!("Unable to start {} load on {}", w.kind(), w.target()),
+            });
+        }
+    }
+    workloads.retain(|w| w.ready());
+
+    for w in &workloads {
+        w.start();
+    }
+
+    println!("Running load for {:?}", options.duration);
+    let start = std::time::Instant::now();
+    while start.elapsed() < options.duration {
+        std::thread::sleep(Duration::from_millis(100));
+        for w in &mut workloads {
+            w.process_messages();
+        }
+    }
+
+    for w in &mut workloads {
+        w.end_and_wait();
+    }
+
+    // A load that failed before it could run has no results, which must not pass unnoticed
+    failures.extend(
+        workloads
+            .iter()
+            .filter_map(|w| match (w.error(), w.result()) {
+                (Some(e), _) => Some(format!("{} load on {} failed: {}", w.kind(), w.target(), e)),
+                (None, None) => Some(format!(
+                    "{} load on {} produced no results",
+                    w.kind(),
+                    w.target()
+                )),
+                _ => None,
+            }),
+    );
+    let result = match RunResult::collect(workloads.iter().map(|w| w.as_ref())) {
+        Some(r) => r,
+        None if !failures.is_empty() => return Err(failures.join("\n")),
+        None => return Err("No load produced any results".to_string()),
+    };
+    let lines = result.summary_lines();
+    for l in &lines {
+        println!("{}", l);
+    }
+    if let Some(path) = &options.output {
+        let mut f = std::fs::File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        for l in &lines {
+            writeln!(f, "{}", l)
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        }
+    }
+    if let Some(path) = &options.json {
+        result
+            .write_json(path)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    if let Some(path) = &options.csv {
+        result
+            .write_csv(path)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+
+    let failed: Vec<&str> = result
+        .workloads
+        .iter()
+        .filter(|w| {
+            w.details.get("corrupt_blocks").copied().unwrap_or(0.0) > 0.0
+                || w.details.get("io_errors").copied().unwrap_or(0.0) > 0.0
+        })
+        .map(|w| w.target.as_str())
+        .collect();
+    if !failed.is_empty() {
+        return Err(format!("Verification failed for {}", failed.join(", ")));
+    }
+    if !failures.is_empty() {
+        return Err(failures.join("\n"));
+    }
+
+    if options.save || options.baseline || options.compare {
+        let dir = options.history.clone().unwrap_or_else(History::default_dir);
+        let history =
+            History::open(dir).map_err(|e| format!("Failed to open the run history: {}", e))?;
+        let fingerprint = history::fingerprint(&result.host);
+        let mut regressions = 0;
+        if options.compare {
+            let baseline = history
+                .baseline(&fingerprint)
+                .map_err(|e| format!("Failed to load the baseline: {}", e))?;
+            if let Some(baseline) = baseline {
+                println!("Compared to the baseline from {}", baseline.start);
+                for c in history::compare(&baseline, &result, options.tolerance) {
+                    if c.change == history::Change::Regression {
+                        regressions += 1;
+                    }
+                    println!("{}", c);
+                }
+            } else {
+                println!("No baseline has been saved for this machine");
+            }
+        }
+        if options.save || options.baseline {
+            let id = history
+                .save(&result)
+                .map_err(|e| format!("Failed to save the run: {}", e))?;
+            println!("Saved run {}", id);
+            if options.baseline {
+                history
+                    .set_baseline(&fingerprint, &id)
+                    .map_err(|e| format!("Failed to set the baseline: {}", e))?;
+                println!("Run {} is now the baseline", id);
+            }
+        }
+        if regressions > 0 {
+            return Err(format!(
+                "{} regressions compared to the baseline",
+                regressions
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,326 @@
+//! A local history of benchmark runs, grouped by the machine they were run on.
+//!
+//! Runs are stored as json files in `<dir>/<host fingerprint>/<run id>.json`.
+//! The id of the run marked as the baseline is stored in
+//! `<dir>/<host fingerprint>/baseline`.
+
+use std::path::PathBuf;
+
+use crate::{
+    results::{HostInfo, RunResult},
+    workload::WorkloadKind,
+};
+
+/// Identifies a machine across runs. Only things that do not change with
+/// firmware, kernel or os updates are included, which leaves out the memory
+/// as the amount the os reports moves with what the firmware and kernel reserve.
+pub fn fingerprint(host: &HostInfo) -> String {
+    // FNV-1a, which unlike the std hasher is stable across builds
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let id = format!("{}|{}|{}", host.hostname, host.cpu, host.cpu_count);
+    for b in id.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// The directory in the user data directory that everything the benchmark keeps is stored in.
+pub fn data_dir() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    let base = std::env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(target_os = "windows"))]
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/share")));
+    base.map(|b| b.join("benchmark"))
+}
+
+pub struct History {
+    dir: PathBuf,
+}
+
+impl History {
+    /// The directory used when none is given, `BENCHMARK_HISTORY` overrides it.
+    pub fn default_dir() -> PathBuf {
+        if let Some(d) = std::env::var_os("BENCHMARK_HISTORY") {
+            return PathBuf::from(d);
+        }
+        match data_dir() {
+            Some(d) => d.join("history"),
+            None => PathBuf::from("benchmark-history"),
+        }
+    }
+
+    pub fn open(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn host_dir(&self, fingerprint: &str) -> PathBuf {
+        self.dir.join(fingerprint)
+    }
+
+    /// Store a run, returning the id it was stored with.
+    pub fn save(&self, run: &RunResult) -> std::io::Result<String> {
+        let dir = self.host_dir(&fingerprint(&run.host));
+        std::fs::create_dir_all(&dir)?;
+        let time = run.start.format("%Y%m%d-%H%M%S-%3f").to_string();
+        // Never replace a stored run, runs started in the same millisecond get a suffix
+        let mut n = 1;
+        loop {
+            let id = match n {
+                1 => time.clone(),
+                n => format!("{}-{}", time, n),
+            };
+            n += 1;
+            let f = match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dir.join(format!("{}.json", id)))
+            {
+                Ok(f) => f,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            serde_json::to_writer_pretty(std::io::BufWriter::new(f), run)?;
+            return Ok(id);
+        }
+    }
+
+    /// The ids of all runs stored for a machine, oldest first.
+    pub fn runs(&self, fingerprint: &str) -> std::io::Result<Vec<String>> {
+        let dir = self.host_dir(fingerprint);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut ids = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == "json").unwrap_or(false) {
+                if let Some(stem) = path.file_stem() {
+                    ids.push(stem.to_string_lossy().to_string());
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn load(&self, fingerprint: &str, id: &str) -> std::io::Result<RunResult> {
+        let path = self.host_dir(fingerprint).join(format!("{}.json", id));
+        let f = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(serde_json::from_reader(f)?)
+    }
+
+    pub fn set_baseline(&self, fingerprint: &str, id: &str) -> std::io::Result<()> {
+        std::fs::write(self.host_dir(fingerprint).join("baseline"), id)
+    }
+
+    /// The run marked as the baseline for a machine, if there is one.
+    pub fn baseline(&self, fingerprint: &str) -> std::io::Result<Option<RunResult>> {
+        let path = self.host_dir(fingerprint).join("baseline");
+        if !path.exists() {
+            return Ok(None);
+        }
+        let id = std::fs::read_to_string(path)?;
+        self.load(fingerprint, id.trim()).map(Some)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Regression,
+    Improvement,
+    Unchanged,
+}
+
+/// How one workload performed compared to the baseline.
+pub struct Comparison {
+    pub kind: WorkloadKind,
+    pub target: String,
+    pub units: String,
+    pub baseline: f64,
+    pub current: f64,
+    /// Percent change from the baseline
+    pub percent: f64,
+    pub change: Change,
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let change = match self.change {
+            Change::Regression => "REGRESSION",
+            Change::Improvement => "improvement",
+            Change::Unchanged => "unchanged",
+        };
+        write!(
+            f,
+            "{} {}: {:.2} -> {:.2} {} ({:+.1}%) {}",
+            self.kind, self.target, self.baseline, self.current, self.units, self.percent, change
+        )
+    }
+}
+
+/// Compare the mean performance of every workload in `run` that is also in
+/// `baseline`. Changes within `tolerance` percent are considered unchanged.
+pub fn compare(baseline: &RunResult, run: &RunResult, tolerance: f64) -> Vec<Comparison> {
+    let mut comparisons = vec![];
+    for w in &run.workloads {
+        let b = baseline
+            .workloads
+            .iter()
+            .find(|b| b.kind == w.kind && b.target == w.target);
+        if let Some(b) = b {
+            let percent = if b.summary.mean != 0.0 {
+                (w.summary.mean - b.summary.mean) / b.summary.mean * 100.0
+            } else {
+                0.0
+            };
+            let change = if percent < -tolerance {
+                Change::Regression
+            } else if percent > tolerance {
+                Change::Improvement
+            } else {
+                Change::Unchanged
+            };
+            comparisons.push(Comparison {
+                kind: w.kind,
+                target: w.target.clone(),
+                units: w.units.clone(),
+                baseline: b.summary.mean,
+                current: w.summary.mean,
+                percent,
+                change,
+            });
+        }
+    }
+    comparisons
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::results::{Summary, WorkloadResult};
+
+    fn workload(kind: WorkloadKind, target: &str, mean: f64) -> WorkloadResult {
+        let now = chrono::Utc::now();
+        WorkloadResult {
+            kind,
+            target: target.to_string(),
+            units: "bytes/s".to_string(),
+            start: now,
+            end: now,
+            samples: vec![],
+            summary: Summary {
+                count: 1,
+                min: mean,
+                mean,
+                max: mean,
+            },
+            details: Default::default(),
+            series: Default::default(),
+        }
+    }
+
+    fn run(workloads: Vec<WorkloadResult>) -> RunResult {
+        let now = chrono::Utc::now();
+        RunResult {
+            host: HostInfo {
+                hostname: "host".to_string(),
+                os: "os".to_string(),
+                kernel: "kernel".to_string(),
+                cpu: "cpu".to_string(),
+                cpu_count: 4,
+                memory: 1 << 30,
+            },
+            start: now,
+            end: now,
+            workloads,
+        }
+    }
+
+    #[test]
+    fn changes_beyond_tolerance() {
+        let baseline = run(vec![
+            workload(WorkloadKind::Disk, "/dev/sda", 100.0),
+            workload(WorkloadKind::Disk, "/dev/sdb", 100.0),
+            workload(WorkloadKind::Disk, "/dev/sdc", 100.0),
+            workload(WorkloadKind::Cpu, "core 0", 100.0),
+        ]);
+        let current = run(vec![
+            workload(WorkloadKind::Disk, "/dev/sda", 90.0),
+            workload(WorkloadKind::Disk, "/dev/sdb", 110.0),
+            workload(WorkloadKind::Disk, "/dev/sdc", 96.0),
+            workload(WorkloadKind::Cpu, "core 0", 105.0),
+        ]);
+        let c = compare(&baseline, &current, 5.0);
+        let changes: Vec<Change> = c.iter().map(|c| c.change).collect();
+        assert_eq!(
+            changes,
+            [
+                Change::Regression,
+                Change::Improvement,
+                Change::Unchanged,
+                Change::Unchanged
+            ]
+        );
+        assert!((c[0].percent + 10.0).abs() < 1e-9);
+        assert_eq!(c[0].baseline, 100.0);
+        assert_eq!(c[0].current, 90.0);
+        assert!((c[1].percent - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn only_matching_workloads_are_compared() {
+        let baseline = run(vec![
+            workload(WorkloadKind::Disk, "/dev/sda", 100.0),
+            workload(WorkloadKind::Network, "eth0", 100.0),
+        ]);
+        let current = run(vec![
+            // Same target, but another kind of load
+            workload(WorkloadKind::Cpu, "/dev/sda", 50.0),
+            workload(WorkloadKind::Network, "eth0", 50.0),
+            workload(WorkloadKind::Network, "eth1", 50.0),
+        ]);
+        let c = compare(&baseline, &current, 5.0);
+        assert_eq!(c.len(), 1);
+        assert_eq!(c[0].kind, WorkloadKind::Network);
+        assert_eq!(c[0].target, "eth0");
+        assert_eq!(c[0].change, Change::Regression);
+    }
+
+    #[test]
+    fn zero_baseline_is_unchanged() {
+        let baseline = run(vec![workload(WorkloadKind::Disk, "/dev/sda", 0.0)]);
+        let current = run(vec![workload(WorkloadKind::Disk, "/dev/sda", 50.0)]);
+        let c = compare(&baseline, &current, 0.0);
+        assert_eq!(c[0].percent, 0.0);
+        assert_eq!(c[0].change, Change::Unchanged);
+    }
+
+    #[test]
+    fn fingerprint_ignores_what_updates_change() {
+        let before = run(vec![]).host;
+        let mut after = before.clone();
+        after.os = "newer os".to_string();
+        after.kernel = "newer kernel".to_string();
+        after.memory -= 64 << 20;
+        assert_eq!(fingerprint(&before), fingerprint(&after));
+        after.cpu_count = 8;
+        assert_ne!(fingerprint(&before), fingerprint(&after));
+    }
+
+    #[test]
+    fn runs_started_together_are_all_kept() {
+        let dir = std::env::temp_dir().join(format!("benchmark-history-{}", std::process::id()));
+        let history = History::open(dir.clone()).unwrap();
+        let run = run(vec![workload(WorkloadKind::Cpu, "core 0", 100.0)]);
+        let first = history.save(&run).unwrap();
+        let second = history.save(&run).unwrap();
+        let runs = history.runs(&fingerprint(&run.host));
+        let _e = std::fs::remove_dir_all(&dir);
+        assert_ne!(first, second);
+        assert_eq!(runs.unwrap(), [first, second]);
+    }
+}
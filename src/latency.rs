@@ -0,0 +1,140 @@
+//! Latency distributions, recorded into HDR histograms in nanoseconds.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use hdrhistogram::Histogram;
+
+/// The longest latency that can be recorded, anything longer is clamped to it.
+const MAX_LATENCY: Duration = Duration::from_secs(3600);
+
+/// A histogram able to hold any latency up to an hour, with 3 significant digits.
+pub fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY.as_nanos() as u64, 3)
+        .expect("Invalid histogram bounds")
+}
+
+/// Record a single latency into a histogram made by [`new_histogram`].
+pub fn record(h: &mut Histogram<u64>, latency: Duration) {
+    h.saturating_record(latency.as_nanos() as u64);
+}
+
+/// The latencies that matter most when looking at tail latency.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencyPercentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+impl LatencyPercentiles {
+    pub fn from_histogram(h: &Histogram<u64>) -> Self {
+        if h.is_empty() {
+            return Self::default();
+        }
+        let at = |q: f64| Duration::from_nanos(h.value_at_quantile(q));
+        Self {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            p999: at(0.999),
+            max: Duration::from_nanos(h.max()),
+        }
+    }
+
+    /// Add the percentiles to the details of a result, in microseconds.
+    pub fn add_details(&self, details: &mut BTreeMap<String, f64>) {
+        self.add_details_as("latency", details);
+    }
+
+    /// Add the percentiles to the details of a result as `<prefix>_<percentile>_us`.
+    pub fn add_details_as(&self, prefix: &str, details: &mut BTreeMap<String, f64>) {
+        for (name, value) in self.named() {
+            details.insert(format!("{}_{}_us", prefix, name), value.as_secs_f64() * 1e6);
+        }
+    }
+
+    fn named(&self) -> [(&'static str, Duration); 5] {
+        [
+            ("p50", self.p50),
+            ("p90", self.p90),
+            ("p99", self.p99),
+            ("p99.9", self.p999),
+            ("max", self.max),
+        ]
+    }
+}
+
+impl std::fmt::Display for LatencyPercentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (name, value)) in self.named().iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{} {:.1} us", name, value.as_secs_f64() * 1e6)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `d` is `us` microseconds, to the 3 significant digits the histogram keeps.
+    fn about(d: Duration, us: u64) -> bool {
+        (d.as_nanos() as f64 / (us * 1000) as f64 - 1.0).abs() < 1e-3
+    }
+
+    #[test]
+    fn percentiles_of_a_uniform_spread() {
+        let mut h = new_histogram();
+        for us in 1..=1000 {
+            record(&mut h, Duration::from_micros(us));
+        }
+        let p = LatencyPercentiles::from_histogram(&h);
+        assert!(about(p.p50, 500), "{:?}", p.p50);
+        assert!(about(p.p90, 900), "{:?}", p.p90);
+        assert!(about(p.p99, 990), "{:?}", p.p99);
+        assert!(about(p.p999, 999), "{:?}", p.p999);
+        assert!(about(p.max, 1000), "{:?}", p.max);
+    }
+
+    #[test]
+    fn tail_is_not_hidden_by_the_median() {
+        let mut h = new_histogram();
+        for _ in 0..999 {
+            record(&mut h, Duration::from_micros(10));
+        }
+        record(&mut h, Duration::from_millis(50));
+        let p = LatencyPercentiles::from_histogram(&h);
+        assert!(about(p.p99, 10));
+        assert!(about(p.max, 50_000));
+    }
+
+    #[test]
+    fn empty_and_clamped() {
+        let h = new_histogram();
+        assert_eq!(LatencyPercentiles::from_histogram(&h), Default::default());
+        let mut h = new_histogram();
+        record(&mut h, Duration::from_secs(7200));
+        let max = LatencyPercentiles::from_histogram(&h).max;
+        assert!((max.as_secs_f64() / 3600.0 - 1.0).abs() < 1e-3, "{:?}", max);
+    }
+
+    #[test]
+    fn details_are_in_microseconds() {
+        let p = LatencyPercentiles {
+            p50: Duration::from_micros(5),
+            p999: Duration::from_millis(2),
+            ..Default::default()
+        };
+        let mut details = BTreeMap::new();
+        p.add_details_as("sync", &mut details);
+        assert_eq!(details.len(), 5);
+        assert_eq!(details["sync_p50_us"], 5.0);
+        assert_eq!(details["sync_p99.9_us"], 2000.0);
+        assert_eq!(details["sync_max_us"], 0.0);
+    }
+}
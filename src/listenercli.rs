@@ -0,0 +1,92 @@
+//! Command line parsing for the network-listener binary.
+
+use std::{path::PathBuf, time::Duration};
+
+use crate::netproto;
+
+pub const USAGE: &str = "Usage: network-listener [options]
+
+Without --headless a window is opened, in which the listeners are started.
+
+Options:
+    --headless             Run without a window, listening on the selected
+                           interfaces until SIGTERM or ctrl-c is received or
+                           the console is closed (linux and windows only)
+    --interface NAME       Listen on the addresses of the given interface
+                           only (may be repeated, default every interface)
+    --port PORT            Port to listen on for udp and tcp, loads must be
+                           told the same port (default 5003)
+    --log FILE             Also append the log of sessions to FILE
+    --stats FILE           With --headless, write the statistics of every
+                           client as json to FILE, replacing it at every
+                           --stats-interval
+    --stats-interval SECONDS
+                           How often the statistics of every client are
+                           logged with --headless (default 10)
+    --help                 Show this message";
+
+pub struct Options {
+    pub headless: bool,
+    pub help: bool,
+    /// Only these interfaces are listened on, or every one when empty
+    pub interfaces: Vec<String>,
+    /// The port bound for udp and tcp
+    pub port: u16,
+    pub log: Option<PathBuf>,
+    pub stats: Option<PathBuf>,
+    pub stats_interval: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            headless: false,
+            help: false,
+            interfaces: vec![],
+            port: netproto::DEFAULT_PORT,
+            log: None,
+            stats: None,
+            stats_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut o = Options::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => o.headless = true,
+                "--help" | "-h" => o.help = true,
+                "--interface" => {
+                    let value = args.next().ok_or("--interface requires a name")?;
+                    o.interfaces.push(value);
+                }
+                "--port" => {
+                    let value = args.next().ok_or("--port requires a value")?;
+                    o.port = match value.parse() {
+                        Ok(p) if p > 0 => p,
+                        _ => return Err(format!("Invalid port {}", value)),
+                    };
+                }
+                "--log" => {
+                    let value = args.next().ok_or("--log requires a filename")?;
+                    o.log = Some(PathBuf::from(value));
+                }
+                "--stats" => {
+                    let value = args.next().ok_or("--stats requires a filename")?;
+                    o.stats = Some(PathBuf::from(value));
+                }
+                "--stats-interval" => {
+                    let value = args.next().ok_or("--stats-interval requires a value")?;
+                    o.stats_interval = match value.parse().map(Duration::try_from_secs_f64) {
+                        Ok(Ok(d)) if !d.is_zero() => d,
+                        _ => return Err(format!("Invalid interval {}", value)),
+                    };
+                }
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+        Ok(o)
+    }
+}
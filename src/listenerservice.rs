@@ -0,0 +1,207 @@
+//! Runs network-listener without a window, as a long-lived service on the
+//! hosts that loads are pointed at.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{listenercli::Options, workload::Workload, ClientStats, NetworkListener};
+
+/// Set once the listener has been asked to shut down.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Where the log is appended, besides stdout.
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Print a line of the log stamped with the local time, appending it to the
+/// log file if there is one.
+pub fn log(message: impl std::fmt::Display) {
+    let line = format!(
+        "{} {}",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        message
+    );
+    println!("{}", line);
+    if let Some(Ok(mut f)) = LOG_FILE.get().map(|f| f.lock()) {
+        let _e = writeln!(f, "{}", line);
+    }
+}
+
+/// Also append the log to `path`.
+pub fn open_log(path: &Path) -> Result<(), String> {
+    let f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let _e = LOG_FILE.set(Mutex::new(f));
+    Ok(())
+}
+
+/// Set once the listener has stopped, so that a console that is being closed
+/// can wait for it.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+#[cfg(target_os = "windows")]
+mod console {
+    pub const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    pub const CTRL_CLOSE_EVENT: u32 = 2;
+
+    pub type HandlerRoutine = unsafe extern "system" fn(ctrl_type: u32) -> i32;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: i32) -> i32;
+        pub fn AttachConsole(process_id: u32) -> i32;
+        pub fn AllocConsole() -> i32;
+    }
+}
+
+#[cfg(target_os = "windows")]
+unsafe extern "system" fn request_shutdown(ctrl_type: u32) -> i32 {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+    // The process is ended as soon as this returns for a closed console, a
+    // logoff or a system shutdown, which give about five seconds to stop
+    if ctrl_type >= console::CTRL_CLOSE_EVENT {
+        let start = Instant::now();
+        while !STOPPED.load(Ordering::Relaxed) && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+    1
+}
+
+/// Release builds on windows have no console of their own, attach to the one
+/// they were started from, or open one, so that the output is not lost.
+pub fn attach_console() {
+    #[cfg(target_os = "windows")]
+    unsafe {
+        if console::AttachConsole(console::ATTACH_PARENT_PROCESS) == 0 {
+            console::AllocConsole();
+        }
+    }
+}
+
+/// Shut down cleanly on SIGTERM and SIGINT, or on ctrl-c and the console
+/// being closed on windows, instead of being killed.
+fn handle_signals() -> Result<(), String> {
+    #[cfg(target_os = "linux")]
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // The handler only stores to an atomic, which is safe at any point
+        unsafe {
+            libc::signal(signal, request_shutdown as *const () as libc::sighandler_t);
+        }
+    }
+    #[cfg(target_os = "windows")]
+    if unsafe { console::SetConsoleCtrlHandler(Some(request_shutdown), 1) } == 0 {
+        return Err(format!(
+            "Failed to handle ctrl-c: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    return Err("Running headless is only supported on linux and windows".to_string());
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    Ok(())
+}
+
+/// The clients of one listener, as written to the statistics file.
+#[derive(Serialize)]
+struct ListenerStats<'a> {
+    addr: std::net::IpAddr,
+    port: u16,
+    clients: &'a [ClientStats],
+}
+
+/// Everything written to the statistics file.
+#[derive(Serialize)]
+struct StatsFile<'a> {
+    time: DateTime<Utc>,
+    listeners: Vec<ListenerStats<'a>>,
+}
+
+/// Replace the statistics file, through a temporary file so that it is never
+/// seen half written.
+fn write_stats(path: &Path, listeners: &[NetworkListener]) -> std::io::Result<()> {
+    let stats = StatsFile {
+        time: Utc::now(),
+        listeners: listeners
+            .iter()
+            .map(|l| ListenerStats {
+                addr: l.addr.ip(),
+                port: l.port,
+                clients: &l.clients,
+            })
+            .collect(),
+    };
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&stats)?)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Listen until asked to shut down, logging the statistics of every client.
+pub fn run(options: &Options, mut listeners: Vec<NetworkListener>) -> Result<(), String> {
+    if listeners.is_empty() {
+        return Err("No addresses to listen on".to_string());
+    }
+    handle_signals()?;
+    for l in &listeners {
+        l.start();
+    }
+    log(format_args!(
+        "Started {} listeners on port {}",
+        listeners.len(),
+        options.port
+    ));
+
+    let mut last_stats = Instant::now();
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_millis(100));
+        for l in &mut listeners {
+            l.process_messages();
+        }
+        if last_stats.elapsed() < options.stats_interval {
+            continue;
+        }
+        last_stats = Instant::now();
+        for l in &listeners {
+            for c in &l.clients {
+                log(format_args!("{} on {}", c, l.addr.ip()));
+            }
+        }
+        if let Some(path) = &options.stats {
+            if let Err(e) = write_stats(path, &listeners) {
+                log(format_args!("Failed to write {}: {}", path.display(), e));
+            }
+        }
+    }
+
+    log("Shutting down");
+    for l in &mut listeners {
+        l.end_and_wait();
+        // Every test has been ended
+        l.clients.clear();
+    }
+    if let Some(path) = &options.stats {
+        write_stats(path, &listeners)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    log("Stopped");
+    STOPPED.store(true, Ordering::Relaxed);
+    Ok(())
+}
@@ -3,16 +3,29 @@
     windows_subsystem = "windows"
 )] // hide console window on Windows in release
 
-use cpu::MessageToCpuLoad;
 use egui_multiwin::multi_window::MultiWindow;
 
+mod cli;
 mod cpu;
 mod disk;
+mod diskjob;
+mod diskstats;
+mod disktarget;
+mod diskverify;
+mod endpoints;
+mod headless;
+mod history;
+mod latency;
 mod netload;
+mod netproto;
+mod nettcp;
+mod results;
 mod windows;
+mod workload;
 
 use network_interface::NetworkInterfaceConfig;
 use windows::root::{self};
+use workload::Workload;
 
 use sysinfo::{DiskExt, NetworkExt, NetworksExt, ProcessExt, System, SystemExt};
 
@@ -26,13 +39,171 @@ pub struct AppCommon {
     #[cfg(feature = "hwlocality")]
     topology: Option<hwlocality::Topology>,
     cpu_threads: Vec<cpu::CpuLoadThread>,
+    /// The instruction set selected for the cpu load
+    cpu_isa: cpu::Isa,
     sysinfo: std::sync::mpsc::Receiver<SysInfoMessage>,
     timer: timer::Timer,
     gui_send: std::sync::mpsc::Sender<MessageToGui>,
     gui_recv: std::sync::mpsc::Receiver<MessageToGui>,
     networks: Vec<network_interface::NetworkInterface>,
     net_threads: Vec<netload::NetworkLoad>,
+    /// How the network interfaces are loaded
+    net_config: netload::NetConfig,
+    /// The port that listeners are bound to
+    listener_port: u16,
+    /// A listener being typed in as `host[:port]`
+    listener_endpoint: String,
+    /// Listeners that were connected to by hand before
+    known_listeners: Option<endpoints::KnownListeners>,
+    /// Why the last listener could not be found
+    net_error: Option<String>,
+    /// Tcp loads to listeners found by the network loads
+    tcp_loads: Vec<nettcp::TcpLoad>,
+    /// How new and existing tcp loads are run
+    tcp_config: nettcp::TcpConfig,
     disks: Vec<disk::DiskLoad>,
+    /// The disks that can be picked for loading
+    disk_targets: Vec<disktarget::BlockDevice>,
+    /// A directory to create a test file in
+    test_dir: String,
+    /// A fio or TOML job file describing disk loads
+    job_file: String,
+    /// Why the last job file could not be loaded
+    job_error: Option<String>,
+    /// How newly found and existing disks are loaded
+    disk_config: disk::DiskConfig,
+    export_status: Option<String>,
+    history: Option<history::History>,
+    /// Percent change allowed before a result counts as a regression or improvement
+    tolerance: f64,
+    comparison: Vec<String>,
+    /// Results of loads that have ended or been removed, kept until they are cleared
+    finished: Vec<results::WorkloadResult>,
+}
+
+/// Drop the loads whose threads have ended, keeping their results in `finished`.
+fn take_done<W: Workload>(loads: &mut Vec<W>, finished: &mut Vec<results::WorkloadResult>) {
+    finished.extend(loads.iter().filter(|w| w.done()).filter_map(|w| w.result()));
+    loads.retain(|w| !w.done());
+}
+
+impl AppCommon {
+    /// Every workload known to the gui.
+    fn workloads(&self) -> Vec<&dyn Workload> {
+        let mut w: Vec<&dyn Workload> = vec![];
+        w.extend(self.cpu_threads.iter().map(|t| t as &dyn Workload));
+        w.extend(self.disks.iter().map(|t| t as &dyn Workload));
+        w.extend(self.net_threads.iter().map(|t| t as &dyn Workload));
+        w.extend(self.tcp_loads.iter().map(|t| t as &dyn Workload));
+        w
+    }
+
+    /// Drop the loads that have ended, without losing their results.
+    fn remove_done(&mut self) {
+        take_done(&mut self.disks, &mut self.finished);
+        take_done(&mut self.net_threads, &mut self.finished);
+        take_done(&mut self.tcp_loads, &mut self.finished);
+    }
+
+    /// The results of everything that has run, including loads that have been removed.
+    fn run_result(&self) -> Option<results::RunResult> {
+        let mut workloads = self.finished.clone();
+        workloads.extend(self.workloads().into_iter().filter_map(|w| w.result()));
+        results::RunResult::new(workloads)
+    }
+
+    /// Look for listeners from every address of every interface that is
+    /// not already loading one.
+    fn discover_listeners(&mut self) {
+        for net in &self.networks {
+            for addr in &net.addr {
+                if self
+                    .net_threads
+                    .iter()
+                    .any(|nt| nt.route.local.ip() == addr.ip())
+                {
+                    continue;
+                }
+                if let Some(route) = netload::Route::discover(
+                    net,
+                    addr,
+                    netload::DEFAULT_LOCAL_PORT,
+                    self.listener_port,
+                ) {
+                    self.net_threads
+                        .push(netload::NetworkLoad::new(route, self.net_config.clone()));
+                }
+            }
+        }
+    }
+
+    /// Load the listener at `endpoint`, remembering it for next time.
+    fn connect_listener(&mut self, endpoint: &str) -> Result<(), String> {
+        let addr = endpoints::resolve(endpoint, self.listener_port)?;
+        // Any free port will do, several listeners may be loaded at once
+        let route = netload::Route::server(addr, 0);
+        self.net_threads
+            .push(netload::NetworkLoad::new(route, self.net_config.clone()));
+        let known = self
+            .known_listeners
+            .as_mut()
+            .ok_or("The known listeners are not available")?;
+        known
+            .add(endpoint)
+            .map_err(|e| format!("Unable to remember {}: {}", endpoint, e))
+    }
+
+    /// Export the results of everything that has run to json and csv files in the current directory.
+    fn export_results(&self) -> Result<String, String> {
+        let result = self.run_result().ok_or("Nothing has been run yet")?;
+        let name = format!("benchmark-{}", result.start.format("%Y%m%d-%H%M%S"));
+        let json = format!("{}.json", name);
+        let csv = format!("{}.csv", name);
+        result
+            .write_json(std::path::Path::new(&json))
+            .map_err(|e| format!("Failed to write {}: {}", json, e))?;
+        result
+            .write_csv(std::path::Path::new(&csv))
+            .map_err(|e| format!("Failed to write {}: {}", csv, e))?;
+        Ok(format!("Exported results to {} and {}", json, csv))
+    }
+
+    /// Save the results of everything that has run to the history, optionally as the new baseline.
+    fn save_results(&self, baseline: bool) -> Result<String, String> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or("The run history is not available")?;
+        let result = self.run_result().ok_or("Nothing has been run yet")?;
+        let id = history
+            .save(&result)
+            .map_err(|e| format!("Failed to save the run: {}", e))?;
+        if baseline {
+            history
+                .set_baseline(&history::fingerprint(&result.host), &id)
+                .map_err(|e| format!("Failed to set the baseline: {}", e))?;
+            Ok(format!("Saved run {} as the baseline", id))
+        } else {
+            Ok(format!("Saved run {}", id))
+        }
+    }
+
+    /// Compare the results of everything that has run against the baseline.
+    fn compare_results(&self) -> Result<Vec<String>, String> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or("The run history is not available")?;
+        let result = self.run_result().ok_or("Nothing has been run yet")?;
+        let baseline = history
+            .baseline(&history::fingerprint(&result.host))
+            .map_err(|e| format!("Failed to load the baseline: {}", e))?
+            .ok_or("No baseline has been saved for this machine")?;
+        Ok(history::compare(&baseline, &result, self.tolerance)
+            .iter()
+            .map(|c| c.to_string())
+            .collect())
+    }
 }
 
 impl egui_multiwin::multi_window::CommonEventHandler<AppCommon, u32> for AppCommon {
@@ -50,40 +221,201 @@ impl egui_multiwin::multi_window::CommonEventHandler<AppCommon, u32> for AppComm
 }
 
 enum SysInfoMessage {
-    DiskThread(disk::DiskLoad),
+    DiskTargets(Vec<disktarget::BlockDevice>),
 }
 
-fn main() {
-    let event_loop = egui_multiwin::winit::event_loop::EventLoopBuilder::with_user_event().build();
-    let mut multi_window: MultiWindow<AppCommon, u32> = MultiWindow::new();
-    let root_window = root::RootWindow::new();
+/// Create a load thread for every cpu in the system, or only for the listed cores.
+#[cfg(feature = "hwlocality")]
+fn create_cpu_threads(
+    topology: &mut Option<hwlocality::Topology>,
+    cores: Option<&[usize]>,
+    isa: cpu::Isa,
+) -> Vec<cpu::CpuLoadThread> {
+    let mut threads = vec![];
+    if let Some(topology) = topology {
+        let root = topology.root_object();
+        let cpuset = root.cpuset();
+        if let Some(cpuset) = cpuset {
+            for index in cpuset.iter_set() {
+                if let Some(cores) = cores {
+                    if !cores.contains(&usize::from(index)) {
+                        continue;
+                    }
+                }
+                let thread = cpu::CpuLoadThread::new(index.into(), isa);
+                thread.associate(topology.clone(), index.into());
+                threads.push(thread);
+            }
+        }
+    }
+    threads
+}
 
-    println!("Starting application");
+/// Print every disk and partition that can be loaded.
+fn list_disks() {
+    for d in disktarget::block_devices() {
+        println!("{}", d);
+        for p in &d.partitions {
+            println!("    {} ({:.1} GB)", p.path.display(), p.size as f64 / 1e9);
+        }
+    }
+}
 
-    #[cfg(target_os = "linux")]
-    let ms = lm_sensors::Initializer::default().initialize();
+fn run_headless(options: cli::Options, mut topology: Option<hwlocality::Topology>) {
+    let mut workloads: Vec<Box<dyn Workload>> = vec![];
+    let threads = match &options.cpu {
+        cli::CpuSelection::None => vec![],
+        cli::CpuSelection::All => create_cpu_threads(&mut topology, None, options.isa),
+        cli::CpuSelection::Cores(c) => create_cpu_threads(&mut topology, Some(c), options.isa),
+    };
+
+    let mut paths = options.disks.clone();
+    if options.all_disks {
+        paths.extend(disktarget::block_devices().into_iter().map(|d| d.path));
+    }
+    for t in threads {
+        workloads.push(Box::new(t));
+    }
+    for p in &paths {
+        // Sync loads write to the disk
+        if options.disk_config.writes() && !p.is_dir() {
+            if let Err(e) = diskverify::check_target(p, options.scratch_device) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        workloads.push(Box::new(disk::DiskLoad::new(
+            p,
+            options.disk_config.clone(),
+        )));
+    }
+    for job in &options.disk_jobs {
+        if job.config.writes() && !job.path.is_dir() {
+            if let Err(e) = diskverify::check_target(&job.path, options.scratch_device) {
+                eprintln!("Job {}: {}", job.name, e);
+                std::process::exit(1);
+            }
+        }
+        workloads.push(Box::new(disk::DiskLoad::new(&job.path, job.config.clone())));
+    }
+    if !options.verify.is_empty() && !diskverify::CAN_DROP_CACHE {
+        eprintln!("--write-verify is only supported on Linux, elsewhere it would not read back from the disk");
+        std::process::exit(1);
+    }
+    for p in &options.verify {
+        if let Err(e) = diskverify::check_target(p, options.scratch_device) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        workloads.push(Box::new(diskverify::DiskVerifyLoad::new(
+            p,
+            options.verify_config.clone(),
+        )));
+    }
+
+    let net_load = |route: netload::Route| -> Box<dyn Workload> {
+        match &options.tcp {
+            Some(tcp) => Box::new(nettcp::TcpLoad::new(route, tcp.clone())),
+            None => Box::new(netload::NetworkLoad::new(route, options.net_config.clone())),
+        }
+    };
+    if options.net != cli::NetSelection::None {
+        if let Ok(networks) = network_interface::NetworkInterface::show() {
+            for net in networks {
+                if let cli::NetSelection::Interfaces(names) = &options.net {
+                    if !names.contains(&net.name) {
+                        continue;
+                    }
+                }
+                for addr in &net.addr {
+                    if let Some(route) =
+                        netload::Route::discover(&net, addr, options.local_port, options.port)
+                    {
+                        workloads.push(net_load(route));
+                    }
+                }
+            }
+        }
+    }
+    let mut known = None;
+    if !options.servers.is_empty() || options.known_servers {
+        known = endpoints::KnownListeners::open(endpoints::KnownListeners::default_path())
+            .map_err(|e| println!("Unable to read the known servers: {}", e))
+            .ok();
+    }
+    let mut servers = options.servers.clone();
+    if let Some(k) = known.as_ref().filter(|_| options.known_servers) {
+        servers.extend(
+            k.endpoints
+                .iter()
+                .filter(|s| !options.servers.contains(s))
+                .cloned(),
+        );
+    }
+    for server in &servers {
+        let given = options.servers.contains(server);
+        match endpoints::resolve(server, options.port) {
+            // Any free port will do, several servers may be loaded at once
+            Ok(addr) => workloads.push(net_load(netload::Route::server(addr, 0))),
+            Err(e) if given => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            Err(e) => println!("{}", e),
+        }
+        if let Some(k) = known.as_mut().filter(|_| given) {
+            if let Err(e) = k.add(server) {
+                println!("Unable to remember server {}: {}", server, e);
+            }
+        }
+    }
+
+    if let Err(e) = headless::run(&options, workloads) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let options = match cli::Options::parse(std::env::args().skip(1)) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}\n{}", e, cli::USAGE);
+            std::process::exit(1);
+        }
+    };
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    if options.list_disks {
+        list_disks();
+        return;
+    }
 
-    let mut threads = vec![];
     #[cfg(feature = "hwlocality")]
     let topology = hwlocality::Topology::new();
     if let Err(e) = &topology {
         println!("Error obtaining topology {}", e);
     }
     let mut topology = topology.ok();
-    if let Some(topology) = &mut topology {
-        let root = topology.root_object();
-        let cpuset = root.cpuset();
-        if let Some(cpuset) = cpuset {
-            for index in cpuset.iter_set() {
-                let thread = cpu::CpuLoadThread::new();
-                thread
-                    .send
-                    .send(MessageToCpuLoad::Associate(topology.clone(), index.into()));
-                threads.push(thread);
-            }
-        }
+
+    if options.headless {
+        run_headless(options, topology);
+        return;
     }
 
+    let event_loop = egui_multiwin::winit::event_loop::EventLoopBuilder::with_user_event().build();
+    let mut multi_window: MultiWindow<AppCommon, u32> = MultiWindow::new();
+    let root_window = root::RootWindow::new();
+
+    println!("Starting application");
+
+    #[cfg(target_os = "linux")]
+    let ms = lm_sensors::Initializer::default().initialize();
+
+    let threads = create_cpu_threads(&mut topology, None, cpu::Isa::Auto);
+
     let (gs, gr) = std::sync::mpsc::channel();
 
     let mut networks = vec![];
@@ -93,39 +425,44 @@ fn main() {
 
     let (s, r) = std::sync::mpsc::channel();
     std::thread::spawn(move || {
-        let mut sinfo: sysinfo::System = sysinfo::System::new_all();
-        sinfo.refresh_disks();
-        for disk in sinfo.disks() {
-            #[cfg(target_os = "windows")]
-            let mp = disk.mount_point().to_path_buf();
-            #[cfg(target_os = "linux")]
-            let mp = std::path::PathBuf::from(disk.name());
-            let dthread = disk::DiskLoad::disk_read_all_files(&mp);
-            s.send(SysInfoMessage::DiskThread(dthread));
-        }
+        let _e = s.send(SysInfoMessage::DiskTargets(disktarget::block_devices()));
     });
 
-    let netlisteners: Vec<netload::NetworkLoad> = networks
-        .iter()
-        .flat_map(|net| net.addr.iter().map(|addr| netload::NetworkLoad::new(addr)))
-        .collect();
-
-    let ac = AppCommon {
+    let mut ac = AppCommon {
         #[cfg(target_os = "linux")]
         sensors: ms.ok(),
         #[cfg(feature = "hwlocality")]
         topology,
         cpu_threads: threads,
+        cpu_isa: cpu::Isa::Auto,
         timer: timer::Timer::new(),
         gui_send: gs,
         gui_recv: gr,
         networks,
-        net_threads: netlisteners,
+        net_threads: vec![],
+        net_config: netload::NetConfig::default(),
+        listener_port: netproto::DEFAULT_PORT,
+        listener_endpoint: String::new(),
+        known_listeners: endpoints::KnownListeners::open(endpoints::KnownListeners::default_path())
+            .ok(),
+        net_error: None,
+        tcp_loads: vec![],
+        tcp_config: nettcp::TcpConfig::default(),
         sysinfo: r,
         disks: vec![],
+        disk_targets: vec![],
+        test_dir: String::new(),
+        job_file: String::new(),
+        job_error: None,
+        disk_config: disk::DiskConfig::default(),
+        export_status: None,
+        history: history::History::open(history::History::default_dir()).ok(),
+        tolerance: 5.0,
+        comparison: vec![],
+        finished: vec![],
     };
 
-    let thread = cpu::CpuLoadThread::new();
+    ac.discover_listeners();
 
     let _e = multi_window.add(root_window, &event_loop);
     multi_window.run(event_loop, ac);
@@ -1,144 +1,883 @@
 use std::{
-    net::{SocketAddr, UdpSocket},
-    time::Duration,
+    collections::{BTreeMap, VecDeque},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
 };
 
+use hdrhistogram::Histogram;
+
+use crate::{
+    latency::{self, LatencyPercentiles},
+    netproto::{self, Capabilities, Control, Probe, Report, Test},
+    workload::{Workload, WorkloadControl, WorkloadKind, WorkloadThread},
+};
+
+/// How often the statistics are reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the echo of a probe may take before the probe counts as lost.
+const LOSS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait for the answer to a control message before sending it again.
+const CONTROL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many times a control message is sent before giving up on the listener.
+const CONTROL_ATTEMPTS: usize = 4;
+
+/// Pacing waits for echoes instead of sending when the next probe is due
+/// at least this far in the future.
+const MIN_WAIT: Duration = Duration::from_micros(50);
+
+/// The port that udp loads send from unless told otherwise.
+pub const DEFAULT_LOCAL_PORT: u16 = 5002;
+
+/// Where a load says hello to find its listener, and where from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    /// The interface that `local` belongs to, when listeners are looked for on its network
+    pub interface: Option<String>,
+    /// The address the load binds
+    pub local: SocketAddr,
+    /// The broadcast address or multicast group to look for listeners on,
+    /// or a listener that is already known
+    pub hello: SocketAddr,
+}
+
+impl Route {
+    /// Look for listeners on `port` of the network that `addr` of `interface`
+    /// is on, sending from `local_port`. Addresses that can not reach other
+    /// hosts have no route.
+    pub fn discover(
+        interface: &network_interface::NetworkInterface,
+        addr: &network_interface::Addr,
+        local_port: u16,
+        port: u16,
+    ) -> Option<Self> {
+        Some(Self {
+            interface: Some(interface.name.clone()),
+            local: netproto::bind_addr(addr.ip(), interface.index, local_port),
+            hello: netproto::hello_addr(addr, interface.index, port)?,
+        })
+    }
+
+    /// Talk to the listener at `server`, such as one behind a router, from
+    /// whichever address the os picks.
+    pub fn server(server: SocketAddr, local_port: u16) -> Self {
+        let local = match server {
+            SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local_port),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), local_port),
+        };
+        Self {
+            interface: None,
+            local,
+            hello: server,
+        }
+    }
+
+    /// Describe a load taking this route, configured as `config`.
+    pub fn target(&self, config: &dyn std::fmt::Display) -> String {
+        match &self.interface {
+            Some(i) => format!("{} ({}, {})", i, self.local.ip(), config),
+            None => format!("{} ({})", self.hello, config),
+        }
+    }
+}
+
+/// How a network interface is loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetConfig {
+    /// The size of every probe sent, in bytes
+    pub packet_size: usize,
+    /// The rate to send at in bits per second, or as fast as possible
+    pub bitrate: Option<u64>,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        Self {
+            packet_size: 1000,
+            bitrate: None,
+        }
+    }
+}
+
+impl std::fmt::Display for NetConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} byte packets", self.packet_size)?;
+        match self.bitrate {
+            Some(b) => write!(f, " at {}", format_bitrate(b as f64)),
+            None => f.write_str(" unpaced"),
+        }
+    }
+}
+
+/// Format a rate in bits per second with a decimal unit.
+pub fn format_bitrate(bits: f64) -> String {
+    for (scale, unit) in [(1e9, "Gbit/s"), (1e6, "Mbit/s"), (1e3, "kbit/s")] {
+        if bits >= scale {
+            return format!("{:.1} {}", bits / scale, unit);
+        }
+    }
+    format!("{:.0} bit/s", bits)
+}
+
+pub enum MessageToNetworkLoad {
+    /// Change how the interface is loaded
+    Configure(NetConfig),
+}
+
+/// What the echoes of the probes showed since the load was started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NetworkStats {
+    /// How long the load has been running
+    pub run_time: Duration,
+    pub sent_packets: u64,
+    pub sent_bytes: u64,
+    /// The number of echoes received
+    pub received_packets: u64,
+    pub echoed_bytes: u64,
+    /// Bytes that the listener says it received
+    pub listener_bytes: u64,
+    /// Bytes per second sent during the last interval
+    pub send_rate: f64,
+    /// Bytes per second echoed back during the last interval
+    pub echo_rate: f64,
+    /// Bytes per second received by the listener, from its latest report
+    pub listener_rate: f64,
+    /// Probes whose echo did not arrive within [`LOSS_TIMEOUT`]
+    pub lost_packets: u64,
+    /// Probes old enough to know whether they were lost, the base of the loss rate
+    pub settled_packets: u64,
+    /// Echoes that arrived after the echo of a later probe
+    pub reordered_packets: u64,
+    /// The smoothed variation of the round trip time, as in RFC 3550
+    pub jitter: Duration,
+    /// The mean round trip time during the last interval
+    pub rtt: Duration,
+    /// The distribution of every round trip time since the load was started
+    pub percentiles: LatencyPercentiles,
+}
+
+impl NetworkStats {
+    pub fn loss_percent(&self) -> f64 {
+        if self.settled_packets == 0 {
+            return 0.0;
+        }
+        self.lost_packets as f64 * 100.0 / self.settled_packets as f64
+    }
+}
+
+impl std::fmt::Display for NetworkStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sent {}, listener received {}, echoed {}, ",
+            format_bitrate(self.send_rate * 8.0),
+            format_bitrate(self.listener_rate * 8.0),
+            format_bitrate(self.echo_rate * 8.0)
+        )?;
+        write!(
+            f,
+            "rtt {:.1} us, jitter {:.1} us, {:.2}% lost, {} reordered",
+            self.rtt.as_secs_f64() * 1e6,
+            self.jitter.as_secs_f64() * 1e6,
+            self.loss_percent(),
+            self.reordered_packets
+        )
+    }
+}
+
+/// The probes sent during one report interval.
+struct Interval {
+    first_seq: u64,
+    sent: u64,
+    received: u64,
+    /// A bit for each probe, set once its echo has arrived
+    echoed: Vec<u64>,
+    /// When the next interval started, no more probes are added after this
+    ended: Option<Instant>,
+}
+
+impl Interval {
+    fn new(first_seq: u64) -> Self {
+        Self {
+            first_seq,
+            sent: 0,
+            received: 0,
+            echoed: vec![],
+            ended: None,
+        }
+    }
+
+    /// Note that the echo of `seq` arrived, returning false for probes that
+    /// were not sent in this interval and for duplicated echoes.
+    fn echoed(&mut self, seq: u64) -> bool {
+        let i = seq - self.first_seq;
+        if i >= self.sent {
+            return false;
+        }
+        let (word, bit) = ((i / 64) as usize, 1 << (i % 64));
+        if self.echoed.len() <= word {
+            self.echoed.resize(word + 1, 0);
+        }
+        if self.echoed[word] & bit != 0 {
+            return false;
+        }
+        self.echoed[word] |= bit;
+        self.received += 1;
+        true
+    }
+}
+
+/// Matches echoes to the probes that were sent, working out loss,
+/// reordering, jitter and the round trip time.
+struct EchoTracker {
+    intervals: VecDeque<Interval>,
+    highest_seq: Option<u64>,
+    last_rtt: Option<Duration>,
+    jitter_ns: f64,
+    interval_rtt: Duration,
+    interval_echoes: u32,
+    interval_sent_bytes: u64,
+    interval_echoed_bytes: u64,
+    /// The latest totals from the listener, and when they arrived
+    listener: (Instant, Report),
+    histogram: Histogram<u64>,
+    stats: NetworkStats,
+}
+
+impl EchoTracker {
+    /// Start tracking from `seq`, the echoes of earlier probes are ignored.
+    fn new(seq: u64) -> Self {
+        Self {
+            intervals: VecDeque::from([Interval::new(seq)]),
+            highest_seq: None,
+            last_rtt: None,
+            jitter_ns: 0.0,
+            interval_rtt: Duration::ZERO,
+            interval_echoes: 0,
+            interval_sent_bytes: 0,
+            interval_echoed_bytes: 0,
+            listener: (Instant::now(), Report::default()),
+            histogram: latency::new_histogram(),
+            stats: NetworkStats::default(),
+        }
+    }
+
+    fn sent(&mut self, bytes: usize) {
+        self.stats.sent_packets += 1;
+        self.stats.sent_bytes += bytes as u64;
+        self.interval_sent_bytes += bytes as u64;
+        if let Some(i) = self.intervals.back_mut() {
+            i.sent += 1;
+        }
+    }
+
+    fn echo(&mut self, probe: &Probe, bytes: usize, rtt: Duration) {
+        let Some(interval) = self
+            .intervals
+            .iter_mut()
+            .rev()
+            .find(|i| i.first_seq <= probe.seq)
+        else {
+            return;
+        };
+        // Duplicated echoes must not hide the loss of other probes
+        if !interval.echoed(probe.seq) {
+            return;
+        }
+        self.stats.received_packets += 1;
+        self.stats.echoed_bytes += bytes as u64;
+        self.interval_echoed_bytes += bytes as u64;
+        match self.highest_seq {
+            Some(h) if probe.seq < h => self.stats.reordered_packets += 1,
+            _ => self.highest_seq = Some(probe.seq),
+        }
+        if let Some(last) = self.last_rtt {
+            let d = if rtt > last { rtt - last } else { last - rtt };
+            self.jitter_ns += (d.as_nanos() as f64 - self.jitter_ns) / 16.0;
+        }
+        self.last_rtt = Some(rtt);
+        self.interval_rtt += rtt;
+        self.interval_echoes += 1;
+        latency::record(&mut self.histogram, rtt);
+    }
+
+    /// The listener reports totals since the test started, the change
+    /// between reports gives the rate.
+    fn listener_report(&mut self, now: Instant, report: Report) {
+        let (then, last) = self.listener;
+        let bytes = report.bytes.saturating_sub(last.bytes);
+        self.stats.listener_bytes += bytes;
+        let seconds = (now - then).as_secs_f64();
+        if seconds > 0.0 {
+            self.stats.listener_rate = bytes as f64 / seconds;
+        }
+        self.listener = (now, report);
+    }
+
+    /// End the current interval that lasted `elapsed`, `next_seq` is the
+    /// first probe of the next one.
+    fn report(&mut self, now: Instant, elapsed: Duration, next_seq: u64) -> NetworkStats {
+        if let Some(i) = self.intervals.back_mut() {
+            i.ended = Some(now);
+        }
+        self.intervals.push_back(Interval::new(next_seq));
+        self.settle(now, LOSS_TIMEOUT);
+        self.stats.jitter = Duration::from_nanos(self.jitter_ns as u64);
+        self.stats.rtt = if self.interval_echoes > 0 {
+            self.interval_rtt / self.interval_echoes
+        } else {
+            Duration::ZERO
+        };
+        self.interval_rtt = Duration::ZERO;
+        self.interval_echoes = 0;
+        let seconds = elapsed.as_secs_f64();
+        self.stats.run_time += elapsed;
+        self.stats.send_rate = self.interval_sent_bytes as f64 / seconds;
+        self.stats.echo_rate = self.interval_echoed_bytes as f64 / seconds;
+        self.interval_sent_bytes = 0;
+        self.interval_echoed_bytes = 0;
+        self.stats.percentiles = LatencyPercentiles::from_histogram(&self.histogram);
+        self.stats
+    }
+
+    /// Count the probes of the intervals that ended at least `timeout` before
+    /// `now` as lost unless their echo arrived.
+    fn settle(&mut self, now: Instant, timeout: Duration) {
+        while let Some(i) = self.intervals.front() {
+            match i.ended {
+                Some(ended) if now - ended >= timeout => {
+                    self.stats.lost_packets += i.sent - i.received;
+                    self.stats.settled_packets += i.sent;
+                    self.intervals.pop_front();
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
 pub struct NetworkLoad {
-    thread: std::thread::JoinHandle<()>,
-    recv: std::sync::mpsc::Receiver<MessageFromNetworkLoad>,
-    pub send: std::sync::mpsc::Sender<MessageToNetworkLoad>,
+    thread: WorkloadThread<MessageToNetworkLoad, MessageFromNetworkLoad>,
     pub ready: bool,
-    pub running: bool,
-    pub done: bool,
-    pub addr: network_interface::Addr,
+    pub route: Route,
     pub server: Option<SocketAddr>,
+    pub config: NetConfig,
+    pub stats: NetworkStats,
 }
 
 pub enum MessageFromNetworkLoad {
     Ready(bool),
-    Running(bool),
     Server(Option<SocketAddr>),
-    Done,
+    Stats(NetworkStats),
 }
 
-pub enum MessageToNetworkLoad {
-    Start,
-    Stop,
-    Exit,
+/// A listener that answered a hello, and the socket used to talk to it.
+pub struct Listener {
+    pub socket: UdpSocket,
+    pub server: SocketAddr,
+    pub capabilities: Capabilities,
 }
 
-impl NetworkLoad {
-    pub fn process_messages(&mut self) {
-        while let Ok(message) = self.recv.try_recv() {
-            match message {
-                MessageFromNetworkLoad::Ready(l) => {
-                    self.ready = l;
-                }
-                MessageFromNetworkLoad::Running(r) => {
-                    self.running = r;
-                }
-                MessageFromNetworkLoad::Done => {
-                    self.done = true;
-                }
-                MessageFromNetworkLoad::Server(s) => {
-                    self.server = s;
-                }
+/// Find a listener speaking the same version of the control protocol by
+/// saying hello along `route`, which may reach several listeners when it is
+/// broadcast or sent to a multicast group. Listeners that can not be used
+/// are explained on stdout.
+pub fn discover(route: &Route) -> Option<Listener> {
+    let socket = UdpSocket::bind(route.local)
+        .map_err(|e| println!("Unable to bind {}: {}", route.local, e))
+        .ok()?;
+    if route.local.is_ipv4() {
+        socket.set_broadcast(true).ok()?;
+    }
+    let to = route.hello;
+    let hello = Control::Hello {
+        version: netproto::VERSION,
+    };
+    if let Err(e) = socket.send_to(&hello.to_bytes(), to) {
+        println!("Error saying hello to {}: {}", to, e);
+        return None;
+    }
+    println!("Said hello to {}", to);
+    // Do not wait forever for a server that may not exist
+    let _e = socket.set_read_timeout(Some(Duration::from_secs(2)));
+    let mut buf = vec![0; netproto::MAX_PACKET];
+    while let Ok((size, from)) = socket.recv_from(&mut buf) {
+        match Control::read(&buf[..size]) {
+            Some(Control::Capabilities(c)) if c.version == netproto::VERSION => {
+                println!("Received a response from {}", from);
+                return Some(Listener {
+                    socket,
+                    server: from,
+                    capabilities: c,
+                });
             }
+            Some(Control::Capabilities(c)) => println!(
+                "Listener {} speaks version {}, not {}",
+                from,
+                c.version,
+                netproto::VERSION
+            ),
+            Some(Control::Error { message }) => println!("Listener {}: {}", from, message),
+            Some(Control::Hello { .. }) => {
+                println!("Listener {} is too old to speak the control protocol", from)
+            }
+            _ => {}
         }
     }
+    None
+}
 
-    pub fn new(addr: &network_interface::Addr) -> Self {
-        let addr = addr.to_owned();
-        let (s, r) = std::sync::mpsc::channel();
-        let (s2, r2) = std::sync::mpsc::channel();
-        let thread = std::thread::spawn(move || {
-            let mut running = false;
-            let mut socket: Option<UdpSocket> = None;
-            let mut buf_broad: [u8; 1000] = [0; 1000];
-            let mut buf: [u8; 10000] = [0; 10000];
-            let mut server_address = None;
+/// Send a control message to `server` and wait for the answer, sending it
+/// again if it seems lost. Other packets received meanwhile are given to
+/// `other`. The socket is left blocking.
+pub fn request(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    message: &Control,
+    mut other: impl FnMut(&[u8], SocketAddr),
+) -> Result<Control, String> {
+    socket
+        .set_nonblocking(false)
+        .and_then(|_| socket.set_read_timeout(Some(CONTROL_TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+    let mut buf = vec![0; netproto::MAX_PACKET];
+    for _ in 0..CONTROL_ATTEMPTS {
+        socket
+            .send_to(&message.to_bytes(), server)
+            .map_err(|e| e.to_string())?;
+        let sent = Instant::now();
+        while sent.elapsed() < CONTROL_TIMEOUT {
+            let Ok((size, from)) = socket.recv_from(&mut buf) else {
+                break;
+            };
+            match Control::read(&buf[..size]) {
+                Some(answer) if from == server => return Ok(answer),
+                _ => other(&buf[..size], from),
+            }
+        }
+    }
+    Err(format!("No answer from {}", server))
+}
 
-            let s = match addr {
-                network_interface::Addr::V4(a) => UdpSocket::bind((a.ip, 5002)),
-                network_interface::Addr::V6(a) => UdpSocket::bind((a.ip, 5002)),
+impl NetworkLoad {
+    pub fn new(route: Route, config: NetConfig) -> Self {
+        let route2 = route.clone();
+        let config2 = config.clone();
+        let thread = WorkloadThread::spawn(move |mut ctx| {
+            let mut config = config;
+            let found = discover(&route);
+            if let Some(l) = &found {
+                ctx.report(MessageFromNetworkLoad::Server(Some(l.server)));
+            }
+            if !ctx.report(MessageFromNetworkLoad::Ready(true)) {
+                return;
+            }
+            let Some(Listener {
+                socket: sock,
+                server,
+                capabilities,
+            }) = found
+            else {
+                return;
             };
-            if let Ok(r) = s {
-                let broad = r.set_broadcast(true);
-                if broad.is_ok() {
-                    buf_broad[0] = b'A';
-                    match addr {
-                        network_interface::Addr::V4(a) => {
-                            if let Some(addr) = a.broadcast {
-                                if let Err(e) = r.send_to(&buf_broad, (addr, 5003)) {
-                                    println!("Error broadcast {}", e);
-                                } else {
-                                    println!("Broadcast to {}", addr);
-                                }
-                            }
-                        }
-                        network_interface::Addr::V6(a) => {
-                            if let Some(addr) = a.broadcast {
-                                if let Err(e) = r.send_to(&buf_broad, (addr, 5003)) {
-                                    println!("Error broadcast {}", e);
-                                } else {
-                                    println!("Broadcast to {}", addr);
-                                }
-                            }
+            let mut buf = vec![0; netproto::MAX_PACKET];
+            let clock = Instant::now();
+            let mut seq = 0;
+            let mut tracker = EchoTracker::new(seq);
+            let mut was_running = false;
+            // The listener accepted the test that is running
+            let mut testing = false;
+            let mut interval_start = Instant::now();
+            let mut packet = vec![];
+            // The time between probes and when the next one is due, when pacing
+            let mut gap = None;
+            let mut next_send = Instant::now();
+            while ctx.poll(|m| match m {
+                MessageToNetworkLoad::Configure(c) => {
+                    config = c;
+                    None
+                }
+            }) {
+                if !ctx.running() {
+                    if was_running && testing {
+                        let stats = finish(&sock, server, clock, &mut tracker, interval_start, seq);
+                        if !ctx.report(MessageFromNetworkLoad::Stats(stats)) {
+                            return;
                         }
+                    }
+                    was_running = false;
+                    ctx.idle();
+                    continue;
+                }
+                if !was_running {
+                    was_running = true;
+                    tracker = EchoTracker::new(seq);
+                    packet = vec![
+                        0;
+                        config
+                            .packet_size
+                            .clamp(netproto::PROBE_HEADER, capabilities.max_packet)
+                    ];
+                    let test = Test::UdpEcho {
+                        packet_size: packet.len(),
+                        bitrate: config.bitrate,
                     };
-                    if let Ok((size, addr)) = r.recv_from(&mut buf) {
-                        println!("Received a response from {}", addr);
-                        server_address = Some(addr);
-                        s2.send(MessageFromNetworkLoad::Server(server_address));
+                    let answer = request(&sock, server, &Control::Start { test }, |_, _| {});
+                    testing = matches!(answer, Ok(Control::Started { .. }));
+                    match answer {
+                        Ok(Control::Started { .. }) => {}
+                        Ok(Control::Error { message }) => {
+                            println!("Listener {} refused the test: {}", server, message)
+                        }
+                        Ok(other) => println!("Listener {} answered {:?}", server, other),
+                        Err(e) => println!("{}", e),
                     }
-                    socket = Some(r);
+                    // Echoes are read between sending probes
+                    testing = testing && sock.set_nonblocking(true).is_ok();
+                    gap = probe_gap(packet.len(), config.bitrate);
+                    interval_start = Instant::now();
+                    next_send = interval_start;
                 }
-            }
-            if s2.send(MessageFromNetworkLoad::Ready(true)).is_err() {
-                socket = None;
-            }
-            if server_address.is_none() {
-                socket = None;
-            }
-            if let Some(sock) = socket {
-                'main: loop {
-                    while let Ok(message) = r.try_recv() {
-                        match message {
-                            MessageToNetworkLoad::Start => {
-                                running = true;
-                                if s2.send(MessageFromNetworkLoad::Running(running)).is_err() {
-                                    break 'main;
-                                }
-                            }
-                            MessageToNetworkLoad::Stop => {
-                                running = false;
-                                if s2.send(MessageFromNetworkLoad::Running(running)).is_err() {
-                                    break 'main;
-                                }
-                            }
-                            MessageToNetworkLoad::Exit => break 'main,
+                if !testing {
+                    ctx.idle();
+                    continue;
+                }
+                let now = Instant::now();
+                if gap.is_none() || now >= next_send {
+                    let probe = Probe {
+                        seq,
+                        sent_ns: clock.elapsed().as_nanos() as u64,
+                    };
+                    probe.write(&mut packet);
+                    // A full send buffer is not an error, the echoes are read meanwhile
+                    if sock.send_to(&packet, server).is_ok() {
+                        seq += 1;
+                        tracker.sent(packet.len());
+                        if let Some(gap) = gap {
+                            next_send = next_probe(next_send, gap, now);
                         }
                     }
-                    if running {
-                        if let Some(a) = server_address {
-                            sock.send_to(&buf_broad, a);
+                } else if next_send - now >= MIN_WAIT {
+                    // Wait for an echo rather than spinning until the next probe is due
+                    if sock.set_nonblocking(false).is_ok() {
+                        let _e = sock.set_read_timeout(Some(next_send - now));
+                        let received = sock.recv_from(&mut buf);
+                        let _e = sock.set_nonblocking(true);
+                        if let Ok((size, from)) = received {
+                            handle_packet(&buf[..size], from, server, clock, &mut tracker);
                         }
-                    } else {
-                        std::thread::sleep(Duration::from_millis(100));
+                    }
+                }
+                while let Ok((size, from)) = sock.recv_from(&mut buf) {
+                    handle_packet(&buf[..size], from, server, clock, &mut tracker);
+                }
+                let now = Instant::now();
+                let elapsed = now - interval_start;
+                if elapsed >= REPORT_INTERVAL {
+                    interval_start = now;
+                    let stats = tracker.report(now, elapsed, seq);
+                    if !ctx.performance(stats.echo_rate)
+                        || !ctx.report(MessageFromNetworkLoad::Stats(stats))
+                    {
+                        return;
                     }
                 }
             }
-            let _e = s2.send(MessageFromNetworkLoad::Done);
+            if was_running && testing {
+                let stats = finish(&sock, server, clock, &mut tracker, interval_start, seq);
+                ctx.report(MessageFromNetworkLoad::Stats(stats));
+            }
         });
         Self {
             thread,
-            recv: r2,
-            send: s,
             ready: false,
-            running: false,
-            done: false,
-            addr,
+            route: route2,
             server: None,
+            config: config2,
+            stats: NetworkStats::default(),
+        }
+    }
+
+    /// Change how the interface is loaded, this takes effect the next time the load is started.
+    pub fn configure(&mut self, config: NetConfig) {
+        self.config = config.clone();
+        self.thread.send(MessageToNetworkLoad::Configure(config));
+    }
+}
+
+/// The time between probes of `packet_size` bytes that sends `bitrate` bits
+/// per second, or `None` to send as fast as possible.
+fn probe_gap(packet_size: usize, bitrate: Option<u64>) -> Option<Duration> {
+    bitrate
+        .filter(|b| *b > 0)
+        .map(|b| Duration::from_secs_f64(packet_size as f64 * 8.0 / b as f64))
+}
+
+/// When the probe after the one that was due at `due` and sent at `now` is due.
+fn next_probe(due: Instant, gap: Duration, now: Instant) -> Instant {
+    // Catch up after a short stall, but do not burst after a long one
+    (due + gap).max(now - REPORT_INTERVAL / 10)
+}
+
+/// End the test with the listener, returning the statistics of the whole
+/// run including the totals of the listener.
+fn finish(
+    sock: &UdpSocket,
+    server: SocketAddr,
+    clock: Instant,
+    tracker: &mut EchoTracker,
+    interval_start: Instant,
+    seq: u64,
+) -> NetworkStats {
+    // Echoes that are still on their way count while waiting for the answer
+    let answer = request(sock, server, &Control::Stop, |buf, from| {
+        handle_packet(buf, from, server, clock, tracker)
+    });
+    match answer {
+        Ok(Control::Results { received, .. }) => tracker.stats.listener_bytes = received.bytes,
+        Ok(other) => println!("Listener {} answered {:?}", server, other),
+        Err(e) => println!("{}", e),
+    }
+    let now = Instant::now();
+    tracker.report(now, now - interval_start, seq);
+    // The listener echoes nothing after the results, so what is missing now was lost
+    tracker.settle(now, Duration::ZERO);
+    tracker.stats
+}
+
+/// Handle a packet received from the listener, either an echoed probe or a report.
+fn handle_packet(
+    buf: &[u8],
+    from: SocketAddr,
+    server: SocketAddr,
+    clock: Instant,
+    tracker: &mut EchoTracker,
+) {
+    if from != server {
+        return;
+    }
+    if let Some(echo) = Probe::read(buf) {
+        let now = clock.elapsed().as_nanos() as u64;
+        let rtt = Duration::from_nanos(now.saturating_sub(echo.sent_ns));
+        tracker.echo(&echo, buf.len(), rtt);
+    } else if let Some(report) = Report::read(buf) {
+        tracker.listener_report(Instant::now(), report);
+    }
+}
+
+impl Workload for NetworkLoad {
+    fn kind(&self) -> WorkloadKind {
+        WorkloadKind::Network
+    }
+
+    fn target(&self) -> String {
+        self.route.target(&self.config)
+    }
+
+    fn units(&self) -> &'static str {
+        "bytes/s"
+    }
+
+    fn control(&self) -> &dyn WorkloadControl {
+        &self.thread
+    }
+
+    fn control_mut(&mut self) -> &mut dyn WorkloadControl {
+        &mut self.thread
+    }
+
+    fn process_messages(&mut self) {
+        for message in self.thread.process_messages() {
+            match message {
+                MessageFromNetworkLoad::Ready(l) => {
+                    self.ready = l;
+                }
+                MessageFromNetworkLoad::Server(s) => {
+                    self.server = s;
+                }
+                MessageFromNetworkLoad::Stats(s) => {
+                    self.stats = s;
+                }
+            }
+        }
+    }
+
+    fn ready(&self) -> bool {
+        self.ready && self.server.is_some()
+    }
+
+    fn details(&self) -> BTreeMap<String, f64> {
+        let s = &self.stats;
+        let mut d = BTreeMap::new();
+        d.insert("sent_packets".to_string(), s.sent_packets as f64);
+        d.insert("sent_bytes".to_string(), s.sent_bytes as f64);
+        d.insert("echoed_bytes".to_string(), s.echoed_bytes as f64);
+        d.insert("listener_bytes".to_string(), s.listener_bytes as f64);
+        let seconds = s.run_time.as_secs_f64();
+        if seconds > 0.0 {
+            d.insert(
+                "send_rate_bytes_s".to_string(),
+                s.sent_bytes as f64 / seconds,
+            );
+            d.insert(
+                "echo_rate_bytes_s".to_string(),
+                s.echoed_bytes as f64 / seconds,
+            );
+            d.insert(
+                "listener_rate_bytes_s".to_string(),
+                s.listener_bytes as f64 / seconds,
+            );
+        }
+        d.insert("received_packets".to_string(), s.received_packets as f64);
+        d.insert("lost_packets".to_string(), s.lost_packets as f64);
+        d.insert("loss_percent".to_string(), s.loss_percent());
+        d.insert("reordered_packets".to_string(), s.reordered_packets as f64);
+        if s.received_packets > 0 {
+            d.insert("jitter_us".to_string(), s.jitter.as_secs_f64() * 1e6);
+            s.percentiles.add_details_as("rtt", &mut d);
+        }
+        d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(tracker: &mut EchoTracker, seq: u64, rtt_ms: u64) {
+        let probe = Probe { seq, sent_ns: 0 };
+        tracker.echo(&probe, 100, Duration::from_millis(rtt_ms));
+    }
+
+    #[test]
+    fn loss_is_counted_once_echoes_are_overdue() {
+        let start = Instant::now();
+        let mut tracker = EchoTracker::new(0);
+        for _ in 0..10 {
+            tracker.sent(100);
+        }
+        for seq in [0, 1, 2, 4, 5, 6, 7, 8] {
+            echo(&mut tracker, seq, 1);
+        }
+        let stats = tracker.report(start, Duration::from_secs(1), 10);
+        assert_eq!((stats.lost_packets, stats.settled_packets), (0, 0));
+        let stats = tracker.report(start + LOSS_TIMEOUT, Duration::from_secs(1), 10);
+        assert_eq!((stats.lost_packets, stats.settled_packets), (2, 10));
+        assert_eq!(stats.loss_percent(), 20.0);
+        assert_eq!(stats.received_packets, 8);
+    }
+
+    #[test]
+    fn late_echoes_count_for_their_own_interval() {
+        let start = Instant::now();
+        let mut tracker = EchoTracker::new(0);
+        tracker.sent(100);
+        tracker.sent(100);
+        tracker.report(start, Duration::from_secs(1), 2);
+        tracker.sent(100);
+        // The echo of a probe from the first interval arrives during the second
+        echo(&mut tracker, 1, 1);
+        echo(&mut tracker, 2, 1);
+        let stats = tracker.report(start + LOSS_TIMEOUT, Duration::from_secs(1), 3);
+        assert_eq!((stats.lost_packets, stats.settled_packets), (1, 2));
+    }
+
+    #[test]
+    fn finishing_settles_everything() {
+        let start = Instant::now();
+        let mut tracker = EchoTracker::new(0);
+        for _ in 0..4 {
+            tracker.sent(100);
+        }
+        echo(&mut tracker, 0, 1);
+        echo(&mut tracker, 1, 1);
+        tracker.report(start, Duration::from_secs(1), 4);
+        // As at the end of a run, nothing more will be echoed
+        tracker.settle(start, Duration::ZERO);
+        assert_eq!(tracker.stats.lost_packets, 2);
+        assert_eq!(tracker.stats.settled_packets, 4);
+    }
+
+    #[test]
+    fn duplicates_do_not_hide_loss() {
+        let start = Instant::now();
+        let mut tracker = EchoTracker::new(0);
+        tracker.sent(100);
+        tracker.sent(100);
+        echo(&mut tracker, 0, 1);
+        echo(&mut tracker, 0, 1);
+        echo(&mut tracker, 0, 1);
+        tracker.report(start, Duration::from_secs(1), 2);
+        tracker.settle(start, Duration::ZERO);
+        assert_eq!(tracker.stats.lost_packets, 1);
+    }
+
+    #[test]
+    fn earlier_tests_are_ignored() {
+        let mut tracker = EchoTracker::new(5);
+        tracker.sent(100);
+        echo(&mut tracker, 3, 1);
+        assert_eq!(tracker.stats.received_packets, 0);
+        echo(&mut tracker, 5, 1);
+        assert_eq!(tracker.stats.received_packets, 1);
+    }
+
+    #[test]
+    fn reordering() {
+        let mut tracker = EchoTracker::new(0);
+        for _ in 0..6 {
+            tracker.sent(100);
+        }
+        for seq in [0, 2, 1, 3, 5, 4] {
+            echo(&mut tracker, seq, 1);
+        }
+        assert_eq!(tracker.stats.reordered_packets, 2);
+    }
+
+    #[test]
+    fn gap_between_probes() {
+        assert_eq!(
+            probe_gap(1000, Some(8_000_000)),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(
+            probe_gap(1250, Some(1_000_000_000)),
+            Some(Duration::from_micros(10))
+        );
+        assert_eq!(probe_gap(1000, Some(0)), None);
+        assert_eq!(probe_gap(1000, None), None);
+    }
+
+    #[test]
+    fn pacing_catches_up_without_bursting() {
+        let gap = Duration::from_millis(1);
+        let now = Instant::now() + Duration::from_secs(10);
+        // On time, the next probe is a gap later
+        assert_eq!(next_probe(now, gap, now), now + gap);
+        // A short stall is made up by sending the missed probes
+        let due = now - Duration::from_millis(50);
+        assert_eq!(next_probe(due, gap, now), due + gap);
+        // After a long one only the last tenth of an interval is made up
+        let due = now - Duration::from_secs(2);
+        assert_eq!(next_probe(due, gap, now), now - REPORT_INTERVAL / 10);
+    }
+
+    #[test]
+    fn jitter_follows_rtt_changes() {
+        let mut tracker = EchoTracker::new(0);
+        for _ in 0..3 {
+            tracker.sent(100);
+        }
+        for (seq, rtt) in [(0, 10), (1, 20), (2, 10)] {
+            echo(&mut tracker, seq, rtt);
         }
+        let stats = tracker.report(Instant::now(), Duration::from_secs(1), 3);
+        // A sixteenth of each change, as in RFC 3550
+        let first = 10e6 / 16.0;
+        let second = first + (10e6 - first) / 16.0;
+        assert_eq!(stats.jitter, Duration::from_nanos(second as u64));
+        assert_eq!(stats.rtt, Duration::from_nanos(40_000_000 / 3));
     }
 }
@@ -0,0 +1,374 @@
+//! The packets exchanged between the network load in `benchmark` and
+//! `network-listener`.
+//!
+//! The first byte of every udp packet says what kind of packet it is.
+//!
+//! Control packets start with [`CONTROL`] followed by a [`Control`] message
+//! encoded as json. A test goes like this:
+//!
+//! 1. The load sends [`Control::Hello`] with its [`VERSION`], either to the
+//!    listener port ([`DEFAULT_PORT`] unless both sides are told otherwise)
+//!    of the broadcast address, or of the link-local multicast group
+//!    [`MULTICAST_V6`] over IPv6, or straight to a known listener. A
+//!    listener speaking the same version answers from its own address with
+//!    [`Control::Capabilities`], any other version gets [`Control::Error`].
+//!    Hello must keep its shape in every version so that this always works.
+//! 2. The load sends [`Control::Start`] with the parameters of the test, the
+//!    listener answers [`Control::Started`] or [`Control::Error`]. The
+//!    listener counts what the load sends from here on.
+//! 3. For a udp test the load sends probes, which the listener echoes back
+//!    unchanged so everything needed to measure the round trip travels in
+//!    the probe itself. Once a second the listener sends a [`Report`] of what
+//!    it has received. For a tcp test the load opens streams to the same
+//!    port, each starting with a [`tcp_header`]: one byte saying which way
+//!    the data flows, then the token from [`Control::Started`] that ties the
+//!    stream to the test.
+//! 4. The load sends [`Control::Stop`] and the listener answers with
+//!    [`Control::Results`], its totals for the whole test.
+//!
+//! A listener that predates this protocol echoes the hello back unchanged,
+//! which is how the load tells it apart.
+
+// Both binaries use this module, but each only needs its own side of it
+#![allow(dead_code)]
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use serde::{Deserialize, Serialize};
+
+/// The version of the control protocol, listeners refuse loads speaking any other.
+pub const VERSION: u32 = 2;
+
+/// The port that listeners bind unless told otherwise, for udp and tcp.
+pub const DEFAULT_PORT: u16 = 5003;
+
+/// The group that hellos are sent to over IPv6, which has no broadcast. It
+/// is link-local, so a hello only reaches the interface it is sent from.
+pub const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x6265, 0x6e63);
+
+/// A control message.
+pub const CONTROL: u8 = b'C';
+/// A packet that the listener echoes back.
+pub const PROBE: u8 = b'P';
+/// Sent by the listener to a load, with the totals received from that load.
+pub const REPORT: u8 = b'R';
+
+/// Opens a tcp stream on which the load sends and the listener reads.
+pub const TCP_UPLOAD: u8 = b'U';
+/// Opens a tcp stream on which the listener sends and the load reads.
+pub const TCP_DOWNLOAD: u8 = b'D';
+
+/// The size of the header at the start of a tcp stream.
+pub const TCP_HEADER: usize = 9;
+
+/// The header that starts a tcp stream going the way `mode` says, for the
+/// test that was given `token`.
+pub fn tcp_header(mode: u8, token: u64) -> [u8; TCP_HEADER] {
+    let mut buf = [0; TCP_HEADER];
+    buf[0] = mode;
+    buf[1..9].copy_from_slice(&token.to_le_bytes());
+    buf
+}
+
+/// The mode and token of a tcp stream, once its whole header has been read.
+pub fn read_tcp_header(buf: &[u8]) -> Option<(u8, u64)> {
+    let token = buf.get(1..TCP_HEADER)?.try_into().ok()?;
+    Some((buf[0], u64::from_le_bytes(token)))
+}
+
+/// The largest packet that is sent or echoed.
+pub const MAX_PACKET: usize = 65507;
+
+/// The size of the header at the start of a probe, the rest is padding.
+pub const PROBE_HEADER: usize = 17;
+
+/// The address to bind `port` on `ip` of the interface numbered `index` to.
+/// Link-local IPv6 addresses are the same on every interface, so they need
+/// the index as their scope.
+pub fn bind_addr(ip: IpAddr, index: u32, port: u16) -> SocketAddr {
+    match ip {
+        IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80 => {
+            SocketAddr::V6(SocketAddrV6::new(v6, port, 0, index))
+        }
+        ip => SocketAddr::new(ip, port),
+    }
+}
+
+/// The number of segments retransmitted on a tcp stream, when the os reports it.
+#[cfg(target_os = "linux")]
+pub fn tcp_retransmits(stream: &std::net::TcpStream) -> Option<u64> {
+    use std::os::fd::AsRawFd;
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (r == 0).then_some(info.tcpi_total_retrans as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_retransmits(_stream: &std::net::TcpStream) -> Option<u64> {
+    None
+}
+
+/// Where hellos from `addr` of the interface numbered `index` are sent to
+/// find listeners on `port` of the same network.
+pub fn hello_addr(addr: &network_interface::Addr, index: u32, port: u16) -> Option<SocketAddr> {
+    match addr {
+        network_interface::Addr::V4(a) => a.broadcast.map(|b| SocketAddr::new(b.into(), port)),
+        network_interface::Addr::V6(_) => Some(SocketAddr::V6(SocketAddrV6::new(
+            MULTICAST_V6,
+            port,
+            0,
+            index,
+        ))),
+    }
+}
+
+/// The header of a probe.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Probe {
+    /// Counts up from zero for every probe sent by a load
+    pub seq: u64,
+    /// When the probe was sent, in nanoseconds on the clock of the sender
+    pub sent_ns: u64,
+}
+
+impl Probe {
+    /// Write the header to the start of `buf`, which must hold at least [`PROBE_HEADER`] bytes.
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0] = PROBE;
+        buf[1..9].copy_from_slice(&self.seq.to_le_bytes());
+        buf[9..17].copy_from_slice(&self.sent_ns.to_le_bytes());
+    }
+
+    /// Read the header of a received packet, if it is a probe.
+    pub fn read(buf: &[u8]) -> Option<Self> {
+        if buf.len() < PROBE_HEADER || buf[0] != PROBE {
+            return None;
+        }
+        Some(Self {
+            seq: u64::from_le_bytes(buf[1..9].try_into().ok()?),
+            sent_ns: u64::from_le_bytes(buf[9..17].try_into().ok()?),
+        })
+    }
+}
+
+/// What the listener has received from one load since its test was started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Report {
+    /// The size of an encoded report.
+    pub const SIZE: usize = 17;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        buf[0] = REPORT;
+        buf[1..9].copy_from_slice(&self.packets.to_le_bytes());
+        buf[9..17].copy_from_slice(&self.bytes.to_le_bytes());
+        buf
+    }
+
+    /// Read a received packet, if it is a report.
+    pub fn read(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::SIZE || buf[0] != REPORT {
+            return None;
+        }
+        Some(Self {
+            packets: u64::from_le_bytes(buf[1..9].try_into().ok()?),
+            bytes: u64::from_le_bytes(buf[9..17].try_into().ok()?),
+        })
+    }
+}
+
+/// What a listener is able to do.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub version: u32,
+    /// Udp probes are echoed
+    pub udp_echo: bool,
+    /// Tcp streams are accepted
+    pub tcp: bool,
+    /// The largest probe that is echoed
+    pub max_packet: usize,
+}
+
+/// A test that a load asks the listener to take part in.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Test {
+    /// Probes of `packet_size` bytes echoed back, sent at `bitrate` bits per second or unpaced
+    UdpEcho {
+        packet_size: usize,
+        bitrate: Option<u64>,
+    },
+    /// Bulk transfers over tcp streams opened by the load
+    Tcp {
+        upload_streams: usize,
+        download_streams: usize,
+    },
+}
+
+/// The messages of the control protocol.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Control {
+    /// Sent by a load to find listeners, or to check one it already knows
+    Hello { version: u32 },
+    /// The answer to a hello
+    Capabilities(Capabilities),
+    /// Begin a test, anything counted for the load before is forgotten
+    Start { test: Test },
+    /// The answer to a start that the listener accepted, the tcp streams of
+    /// the test send `token` to say which test they belong to
+    Started { token: u64 },
+    /// End the current test
+    Stop,
+    /// The answer to a stop, with what the listener counted during the test
+    Results {
+        /// What was received from the load, tcp streams have no packets
+        received: Report,
+        /// Bytes sent to the load, echoes included
+        sent_bytes: u64,
+        /// How long the test ran on the listener
+        seconds: f64,
+        /// Segments the listener retransmitted while sending on tcp streams,
+        /// when its os reports it
+        retransmits: Option<u64>,
+    },
+    /// Something the listener can not do, such as speak another version
+    Error { message: String },
+}
+
+impl Control {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![CONTROL];
+        // Serializing these types can not fail
+        let _e = serde_json::to_writer(&mut buf, self);
+        buf
+    }
+
+    /// Read a received packet, if it is a control message.
+    pub fn read(buf: &[u8]) -> Option<Self> {
+        match buf.split_first() {
+            Some((&CONTROL, json)) => serde_json::from_slice(json).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_round_trip() {
+        let messages = [
+            Control::Hello { version: VERSION },
+            Control::Capabilities(Capabilities {
+                version: VERSION,
+                udp_echo: true,
+                tcp: false,
+                max_packet: MAX_PACKET,
+            }),
+            Control::Start {
+                test: Test::UdpEcho {
+                    packet_size: 1000,
+                    bitrate: Some(5_000_000),
+                },
+            },
+            Control::Start {
+                test: Test::Tcp {
+                    upload_streams: 2,
+                    download_streams: 0,
+                },
+            },
+            Control::Started { token: u64::MAX },
+            Control::Stop,
+            Control::Results {
+                received: Report {
+                    packets: 3,
+                    bytes: 3000,
+                },
+                sent_bytes: 2000,
+                seconds: 1.5,
+                retransmits: Some(7),
+            },
+            Control::Error {
+                message: "No test is running".to_string(),
+            },
+        ];
+        for m in messages {
+            let bytes = m.to_bytes();
+            assert_eq!(bytes[0], CONTROL);
+            assert_eq!(Control::read(&bytes), Some(m));
+        }
+    }
+
+    #[test]
+    fn hello_keeps_its_shape() {
+        // Listeners of every version must be able to read a hello to refuse it
+        let hello = Control::Hello { version: 7 }.to_bytes();
+        assert_eq!(hello, b"C{\"type\":\"hello\",\"version\":7}");
+        assert_eq!(Control::read(&hello), Some(Control::Hello { version: 7 }));
+    }
+
+    #[test]
+    fn control_read_rejects_other_packets() {
+        assert_eq!(Control::read(&[]), None);
+        assert_eq!(Control::read(b"C{\"type\":\"unknown\"}"), None);
+        assert_eq!(Control::read(b"Cnot json"), None);
+        let report = Report::default().to_bytes();
+        assert_eq!(Control::read(&report), None);
+    }
+
+    #[test]
+    fn probe_round_trip() {
+        let probe = Probe {
+            seq: 42,
+            sent_ns: 1_234_567_890,
+        };
+        let mut buf = vec![0; 100];
+        probe.write(&mut buf);
+        assert_eq!(Probe::read(&buf), Some(probe));
+        assert_eq!(Probe::read(&buf[..PROBE_HEADER]), Some(probe));
+        assert_eq!(Probe::read(&buf[..PROBE_HEADER - 1]), None);
+        buf[0] = REPORT;
+        assert_eq!(Probe::read(&buf), None);
+    }
+
+    #[test]
+    fn report_round_trip() {
+        let report = Report {
+            packets: 10,
+            bytes: u64::MAX,
+        };
+        let bytes = report.to_bytes();
+        assert_eq!(Report::read(&bytes), Some(report));
+        assert_eq!(Report::read(&bytes[..Report::SIZE - 1]), None);
+        let mut probe = vec![0; PROBE_HEADER];
+        Probe { seq: 1, sent_ns: 2 }.write(&mut probe);
+        assert_eq!(Report::read(&probe), None);
+    }
+
+    #[test]
+    fn tcp_header_round_trip() {
+        let header = tcp_header(TCP_DOWNLOAD, 0x0102_0304_0506_0708);
+        assert_eq!(
+            read_tcp_header(&header),
+            Some((TCP_DOWNLOAD, 0x0102_0304_0506_0708))
+        );
+        assert_eq!(read_tcp_header(&header[..TCP_HEADER - 1]), None);
+        assert_eq!(read_tcp_header(&[]), None);
+    }
+}
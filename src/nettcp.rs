@@ -0,0 +1,534 @@
+//! Bulk TCP transfers between the load and `network-listener`, which is how
+//! most application traffic moves.
+//!
+//! Every stream starts with a byte saying which way data flows and the token
+//! of the test it belongs to, after that the sending side writes as fast as
+//! it can until the connection is closed.
+
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    netload::{self, format_bitrate, Listener, Route},
+    netproto::{self, Control, Test},
+    workload::{Workload, WorkloadControl, WorkloadKind, WorkloadThread},
+};
+
+/// How often the statistics are reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The size of each read or write on a stream.
+const CHUNK_SIZE: usize = 128 * 1024;
+
+/// How long a stream blocks before checking whether it should stop.
+const STREAM_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Which way data flows between the load and the listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcpDirection {
+    /// The load sends to the listener
+    Upload,
+    /// The listener sends to the load
+    Download,
+    /// Both at once, every stream is opened in each direction
+    Bidirectional,
+}
+
+impl std::fmt::Display for TcpDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            TcpDirection::Upload => "upload",
+            TcpDirection::Download => "download",
+            TcpDirection::Bidirectional => "bidirectional",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::str::FromStr for TcpDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upload" | "up" => Ok(TcpDirection::Upload),
+            "download" | "down" => Ok(TcpDirection::Download),
+            "bidirectional" | "both" => Ok(TcpDirection::Bidirectional),
+            _ => Err(format!("Unknown tcp direction {}", s)),
+        }
+    }
+}
+
+/// How a tcp load is run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TcpConfig {
+    pub direction: TcpDirection,
+    /// Streams opened in each direction
+    pub streams: usize,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            direction: TcpDirection::Upload,
+            streams: 1,
+        }
+    }
+}
+
+impl std::fmt::Display for TcpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tcp {}", self.direction)?;
+        if self.streams > 1 {
+            write!(f, " x{}", self.streams)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the tcp load has transferred since it was started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpStats {
+    /// How long the load has been running
+    pub run_time: Duration,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    /// Bytes per second sent during the last interval
+    pub send_rate: f64,
+    /// Bytes per second received during the last interval
+    pub receive_rate: f64,
+    /// Segments retransmitted by the load, which sends on upload streams,
+    /// when the os reports it
+    pub retransmits: Option<u64>,
+    /// Segments retransmitted by the listener, which sends on download
+    /// streams, once the test has ended
+    pub listener_retransmits: Option<u64>,
+    /// What the listener received and sent, once the test has ended
+    pub listener_received_bytes: Option<u64>,
+    pub listener_sent_bytes: Option<u64>,
+}
+
+impl std::fmt::Display for TcpStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sent {}, received {}",
+            format_bitrate(self.send_rate * 8.0),
+            format_bitrate(self.receive_rate * 8.0)
+        )?;
+        if let Some(r) = self.retransmits {
+            write!(f, ", {} retransmits", r)?;
+        }
+        if let Some(r) = self.listener_retransmits {
+            write!(f, ", {} retransmits by the listener", r)?;
+        }
+        Ok(())
+    }
+}
+
+/// One connection to the listener, moving data in a thread of its own.
+struct Stream {
+    /// A handle to the same socket, used to read its statistics
+    socket: TcpStream,
+    thread: JoinHandle<()>,
+}
+
+impl Stream {
+    /// Connect to `server` for the test given `token`, asking it to send if
+    /// `upload` is false, and start moving data until `stop` is set.
+    fn open(
+        server: SocketAddr,
+        upload: bool,
+        token: u64,
+        stop: Arc<AtomicBool>,
+        bytes: Arc<AtomicU64>,
+        error: Arc<Mutex<Option<String>>>,
+    ) -> std::io::Result<Self> {
+        let mut s = TcpStream::connect_timeout(&server, Duration::from_secs(2))?;
+        let _e = s.set_nodelay(true);
+        s.set_read_timeout(Some(STREAM_TIMEOUT))?;
+        s.set_write_timeout(Some(STREAM_TIMEOUT))?;
+        let mode = if upload {
+            netproto::TCP_UPLOAD
+        } else {
+            netproto::TCP_DOWNLOAD
+        };
+        s.write_all(&netproto::tcp_header(mode, token))?;
+        let socket = s.try_clone()?;
+        let thread = std::thread::spawn(move || {
+            let mut buf = vec![0; CHUNK_SIZE];
+            while !stop.load(Ordering::Relaxed) {
+                let r = if upload {
+                    s.write(&buf)
+                } else {
+                    s.read(&mut buf)
+                };
+                match r {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        bytes.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(e) => {
+                        fail(&error, format!("Tcp stream to {} failed: {}", server, e));
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Self { socket, thread })
+    }
+}
+
+/// Keep the error that ended a stream, unless another stream failed first.
+fn fail(error: &Mutex<Option<String>>, message: String) {
+    if let Ok(mut e) = error.lock() {
+        e.get_or_insert(message);
+    }
+}
+
+/// Every stream of a running load.
+struct Streams {
+    stop: Arc<AtomicBool>,
+    sent: Arc<AtomicU64>,
+    received: Arc<AtomicU64>,
+    /// The first stream that could not be opened or failed, until it is reported
+    error: Arc<Mutex<Option<String>>>,
+    streams: Vec<Stream>,
+}
+
+impl Streams {
+    fn open(server: SocketAddr, config: &TcpConfig, token: u64) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let sent = Arc::new(AtomicU64::new(0));
+        let received = Arc::new(AtomicU64::new(0));
+        let error = Arc::new(Mutex::new(None));
+        let mut streams = vec![];
+        for upload in [true, false] {
+            let wanted = match config.direction {
+                TcpDirection::Upload => upload,
+                TcpDirection::Download => !upload,
+                TcpDirection::Bidirectional => true,
+            };
+            if !wanted {
+                continue;
+            }
+            let bytes = if upload { &sent } else { &received };
+            for _ in 0..config.streams {
+                let opened = Stream::open(
+                    server,
+                    upload,
+                    token,
+                    stop.clone(),
+                    bytes.clone(),
+                    error.clone(),
+                );
+                match opened {
+                    Ok(s) => streams.push(s),
+                    Err(e) => fail(
+                        &error,
+                        format!("Failed to open tcp stream to {}: {}", server, e),
+                    ),
+                }
+            }
+        }
+        Self {
+            stop,
+            sent,
+            received,
+            error,
+            streams,
+        }
+    }
+
+    fn take_error(&self) -> Option<String> {
+        self.error.lock().ok()?.take()
+    }
+
+    /// The retransmits of every stream, if the os reports them.
+    fn retransmits(&self) -> Option<u64> {
+        self.streams
+            .iter()
+            .map(|s| netproto::tcp_retransmits(&s.socket))
+            .sum()
+    }
+
+    fn close(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for s in self.streams {
+            let _e = s.thread.join();
+        }
+    }
+}
+
+/// Close the streams and end the test with the listener, adding its totals to `stats`.
+fn finish(listener: &Listener, streams: Streams, started: Instant, stats: &mut TcpStats) {
+    stats.run_time = started.elapsed();
+    stats.sent_bytes = streams.sent.load(Ordering::Relaxed);
+    stats.received_bytes = streams.received.load(Ordering::Relaxed);
+    stats.retransmits = streams.retransmits();
+    streams.close();
+    match netload::request(&listener.socket, listener.server, &Control::Stop, |_, _| {}) {
+        Ok(Control::Results {
+            received,
+            sent_bytes,
+            retransmits,
+            ..
+        }) => {
+            stats.listener_received_bytes = Some(received.bytes);
+            stats.listener_sent_bytes = Some(sent_bytes);
+            stats.listener_retransmits = retransmits;
+        }
+        Ok(other) => println!("Listener {} answered {:?}", listener.server, other),
+        Err(e) => println!("{}", e),
+    }
+}
+
+pub enum MessageToTcpLoad {
+    /// Change how the load is run, this takes effect the next time it is started
+    Configure(TcpConfig),
+}
+
+pub enum MessageFromTcpLoad {
+    Ready(bool),
+    Server(Option<SocketAddr>),
+    Stats(TcpStats),
+    /// The listener can not run the test, or a stream failed
+    Error(String),
+}
+
+/// Bulk tcp transfers to a listener found along a route.
+pub struct TcpLoad {
+    thread: WorkloadThread<MessageToTcpLoad, MessageFromTcpLoad>,
+    ready: bool,
+    route: Route,
+    pub server: Option<SocketAddr>,
+    pub config: TcpConfig,
+    pub stats: TcpStats,
+    /// Why the last test could not be run, or failed
+    pub error: Option<String>,
+}
+
+impl TcpLoad {
+    /// Load the listener that answers a hello sent along `route`.
+    pub fn new(mut route: Route, config: TcpConfig) -> Self {
+        // Any free port will do, the udp load may be using the usual one
+        route.local.set_port(0);
+        let route2 = route.clone();
+        let config2 = config.clone();
+        let thread = WorkloadThread::spawn(move |mut ctx| {
+            let mut config = config;
+            let Some(listener) = netload::discover(&route) else {
+                return;
+            };
+            ctx.report(MessageFromTcpLoad::Server(Some(listener.server)));
+            // A listener that can not accept streams leaves the load unable to start
+            if !listener.capabilities.tcp {
+                ctx.report(MessageFromTcpLoad::Error(format!(
+                    "Listener {} does not accept tcp streams",
+                    listener.server
+                )));
+                return;
+            }
+            if !ctx.report(MessageFromTcpLoad::Ready(true)) {
+                return;
+            }
+            let server = listener.server;
+            let mut streams: Option<Streams> = None;
+            let mut was_running = false;
+            let mut stats = TcpStats::default();
+            let mut started = Instant::now();
+            let mut interval_start = started;
+            let mut last = (0, 0);
+            while ctx.poll(|m| match m {
+                MessageToTcpLoad::Configure(c) => {
+                    config = c;
+                    None
+                }
+            }) {
+                if !ctx.running() {
+                    if let Some(s) = streams.take() {
+                        if let Some(e) = s.take_error() {
+                            ctx.report(MessageFromTcpLoad::Error(e));
+                        }
+                        finish(&listener, s, started, &mut stats);
+                        if !ctx.report(MessageFromTcpLoad::Stats(stats)) {
+                            return;
+                        }
+                    }
+                    was_running = false;
+                    ctx.idle();
+                    continue;
+                }
+                if !was_running {
+                    was_running = true;
+                    stats = TcpStats::default();
+                    started = Instant::now();
+                    interval_start = started;
+                    last = (0, 0);
+                    let streams_each = |wanted: bool| if wanted { config.streams } else { 0 };
+                    let test = Test::Tcp {
+                        upload_streams: streams_each(config.direction != TcpDirection::Download),
+                        download_streams: streams_each(config.direction != TcpDirection::Upload),
+                    };
+                    let refused = match netload::request(
+                        &listener.socket,
+                        server,
+                        &Control::Start { test },
+                        |_, _| {},
+                    ) {
+                        Ok(Control::Started { token }) => {
+                            streams = Some(Streams::open(server, &config, token));
+                            None
+                        }
+                        Ok(Control::Error { message }) => {
+                            Some(format!("Listener {} refused the test: {}", server, message))
+                        }
+                        Ok(other) => Some(format!("Listener {} answered {:?}", server, other)),
+                        Err(e) => Some(e),
+                    };
+                    if let Some(e) = refused {
+                        if !ctx.report(MessageFromTcpLoad::Error(e)) {
+                            return;
+                        }
+                    }
+                }
+                let Some(s) = &streams else {
+                    ctx.idle();
+                    continue;
+                };
+                std::thread::sleep(Duration::from_millis(50));
+                let elapsed = interval_start.elapsed();
+                if elapsed < REPORT_INTERVAL {
+                    continue;
+                }
+                interval_start = Instant::now();
+                let sent = s.sent.load(Ordering::Relaxed);
+                let received = s.received.load(Ordering::Relaxed);
+                let seconds = elapsed.as_secs_f64();
+                stats.run_time = started.elapsed();
+                stats.sent_bytes = sent;
+                stats.received_bytes = received;
+                stats.send_rate = (sent - last.0) as f64 / seconds;
+                stats.receive_rate = (received - last.1) as f64 / seconds;
+                stats.retransmits = s.retransmits();
+                last = (sent, received);
+                if let Some(e) = s.take_error() {
+                    if !ctx.report(MessageFromTcpLoad::Error(e)) {
+                        return;
+                    }
+                }
+                if !ctx.performance(stats.send_rate + stats.receive_rate)
+                    || !ctx.report(MessageFromTcpLoad::Stats(stats))
+                {
+                    return;
+                }
+            }
+            if let Some(s) = streams {
+                if let Some(e) = s.take_error() {
+                    ctx.report(MessageFromTcpLoad::Error(e));
+                }
+                finish(&listener, s, started, &mut stats);
+                ctx.report(MessageFromTcpLoad::Stats(stats));
+            }
+        });
+        Self {
+            thread,
+            ready: false,
+            route: route2,
+            server: None,
+            config: config2,
+            stats: TcpStats::default(),
+            error: None,
+        }
+    }
+
+    /// Change how the load is run, this takes effect the next time it is started.
+    pub fn configure(&mut self, config: TcpConfig) {
+        self.config = config.clone();
+        self.thread.send(MessageToTcpLoad::Configure(config));
+    }
+}
+
+impl Workload for TcpLoad {
+    fn kind(&self) -> WorkloadKind {
+        WorkloadKind::Network
+    }
+
+    fn target(&self) -> String {
+        self.route.target(&self.config)
+    }
+
+    fn units(&self) -> &'static str {
+        "bytes/s"
+    }
+
+    fn control(&self) -> &dyn WorkloadControl {
+        &self.thread
+    }
+
+    fn control_mut(&mut self) -> &mut dyn WorkloadControl {
+        &mut self.thread
+    }
+
+    fn process_messages(&mut self) {
+        for message in self.thread.process_messages() {
+            match message {
+                MessageFromTcpLoad::Ready(r) => self.ready = r,
+                MessageFromTcpLoad::Server(s) => self.server = s,
+                MessageFromTcpLoad::Stats(s) => self.stats = s,
+                MessageFromTcpLoad::Error(e) => {
+                    println!("{}", e);
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+
+    fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+
+    fn ready(&self) -> bool {
+        self.ready && self.server.is_some()
+    }
+
+    fn details(&self) -> BTreeMap<String, f64> {
+        let s = &self.stats;
+        let mut d = BTreeMap::new();
+        d.insert("sent_bytes".to_string(), s.sent_bytes as f64);
+        d.insert("received_bytes".to_string(), s.received_bytes as f64);
+        let seconds = s.run_time.as_secs_f64();
+        if seconds > 0.0 {
+            d.insert(
+                "goodput_sent_bytes_s".to_string(),
+                s.sent_bytes as f64 / seconds,
+            );
+            d.insert(
+                "goodput_received_bytes_s".to_string(),
+                s.received_bytes as f64 / seconds,
+            );
+        }
+        if let Some(r) = s.retransmits {
+            d.insert("retransmits".to_string(), r as f64);
+        }
+        if let Some(r) = s.listener_retransmits {
+            d.insert("listener_retransmits".to_string(), r as f64);
+        }
+        if let Some(b) = s.listener_received_bytes {
+            d.insert("listener_received_bytes".to_string(), b as f64);
+        }
+        if let Some(b) = s.listener_sent_bytes {
+            d.insert("listener_sent_bytes".to_string(), b as f64);
+        }
+        d
+    }
+}
@@ -3,149 +3,610 @@
     windows_subsystem = "windows"
 )] // hide console window on Windows in release
 
-use std::{net::UdpSocket, time::Duration};
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
-use cpu::MessageToCpuLoad;
 use egui_multiwin::multi_window::MultiWindow;
+use serde::Serialize;
 
-mod cpu;
+mod listenercli;
+mod listenerservice;
+mod netproto;
+mod results;
 mod windows_network;
+mod workload;
 
+use listenerservice::log;
+use netproto::Control;
 use network_interface::NetworkInterfaceConfig;
 use windows_network::root::{self};
+use workload::{Workload, WorkloadControl, WorkloadKind, WorkloadThread};
 
 pub enum MessageToGui {
     StopAllCpu,
 }
 
-enum MessageFromNetworkListener {
-    Listening(bool),
-    Done,
+/// How often every load is told how much was received from it.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What has been received from one load.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientStats {
+    pub addr: SocketAddr,
+    /// udp or tcp
+    pub protocol: &'static str,
+    /// How long the test has been running
+    pub seconds: f64,
+    /// Totals received since the test was started
+    pub report: netproto::Report,
+    /// Bytes per second received during the last interval
+    pub rate: f64,
+    /// Bytes sent back, echoes for udp
+    pub sent_bytes: u64,
+    /// Bytes per second sent during the last interval
+    pub send_rate: f64,
+}
+
+impl std::fmt::Display for ClientStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}: {:.1} Mbit/s received, {:.1} Mbit/s sent, {} packets, {} bytes received, {} bytes sent in {:.0} s",
+            self.protocol,
+            self.addr,
+            self.rate * 8.0 / 1e6,
+            self.send_rate * 8.0 / 1e6,
+            self.report.packets,
+            self.report.bytes,
+            self.sent_bytes,
+            self.seconds
+        )
+    }
+}
+
+/// A tcp stream opened by a load, served by a thread of its own.
+struct TcpClient {
+    /// The token of the test the stream belongs to
+    token: u64,
+    /// A handle to the same socket, used to read its statistics
+    socket: TcpStream,
+    received: Arc<AtomicU64>,
+    sent: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl TcpClient {
+    /// Read what the load sends, or send to it, as `mode` from the header of the stream asks.
+    fn serve(mut s: TcpStream, addr: SocketAddr, token: u64, mode: u8) -> std::io::Result<Self> {
+        let socket = s.try_clone()?;
+        let received = Arc::new(AtomicU64::new(0));
+        let sent = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (r, w, stop2) = (received.clone(), sent.clone(), stop.clone());
+        let thread = std::thread::spawn(move || {
+            let mut buf = vec![0; 128 * 1024];
+            let _e = s.set_nonblocking(false);
+            let _e = s.set_read_timeout(Some(Duration::from_millis(100)));
+            let _e = s.set_write_timeout(Some(Duration::from_millis(100)));
+            while !stop2.load(Ordering::Relaxed) {
+                let result = if mode == netproto::TCP_DOWNLOAD {
+                    s.write(&buf).map(|n| {
+                        w.fetch_add(n as u64, Ordering::Relaxed);
+                        n
+                    })
+                } else {
+                    s.read(&mut buf).map(|n| {
+                        r.fetch_add(n as u64, Ordering::Relaxed);
+                        n
+                    })
+                };
+                match result {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(_) => break,
+                }
+            }
+            log(format_args!("Tcp stream from {} closed", addr));
+        });
+        Ok(Self {
+            token,
+            socket,
+            received,
+            sent,
+            stop,
+            thread,
+        })
+    }
+}
+
+/// Stop the tcp streams of tests that have ended, so that a test started next
+/// by the same load only counts its own streams.
+fn end_streams(sessions: &HashMap<SocketAddr, Session>, tcp_clients: &mut Vec<TcpClient>) {
+    tcp_clients.retain(|c| {
+        let keep = sessions.values().any(|s| s.token == c.token);
+        if !keep {
+            // The thread notices within its socket timeout, there is no need to wait for it
+            c.stop.store(true, Ordering::Relaxed);
+        }
+        keep
+    });
+}
+
+/// A tcp stream that has been accepted, but has not sent its whole header yet.
+struct PendingStream {
+    stream: TcpStream,
+    from: SocketAddr,
+    accepted: Instant,
+    header: Vec<u8>,
+}
+
+impl PendingStream {
+    /// Read what has arrived of the header, giving the mode and token once all of it has.
+    fn read_header(&mut self) -> std::io::Result<Option<(u8, u64)>> {
+        let mut buf = [0; netproto::TCP_HEADER];
+        let wanted = netproto::TCP_HEADER - self.header.len();
+        match self.stream.read(&mut buf[..wanted]) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => self.header.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        Ok(netproto::read_tcp_header(&self.header))
+    }
 }
 
-enum MessageToNetworkListener {
-    Start,
-    Stop,
-    Exit,
+/// How long a tcp stream may take to send its header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub enum MessageToListener {
+    /// Bind another port, this takes effect the next time the listener is started
+    Port(u16),
 }
 
 struct NetworkListener {
-    thread: std::thread::JoinHandle<()>,
-    recv: std::sync::mpsc::Receiver<MessageFromNetworkListener>,
-    pub send: std::sync::mpsc::Sender<MessageToNetworkListener>,
-    listening: bool,
-    done: bool,
+    thread: WorkloadThread<MessageToListener, Vec<ClientStats>>,
     pub addr: network_interface::Addr,
+    /// The port bound for udp and tcp
+    pub port: u16,
+    pub clients: Vec<ClientStats>,
 }
 
-impl NetworkListener {
-    pub fn process_messages(&mut self) {
-        while let Ok(message) = self.recv.try_recv() {
-            match message {
-                MessageFromNetworkListener::Listening(l) => {
-                    self.listening = l;
-                }
-                MessageFromNetworkListener::Done => {
-                    self.done = true;
+/// A test that a load started.
+struct Session {
+    test: netproto::Test,
+    /// Given to the load, which sends it on every tcp stream of the test
+    token: u64,
+    started: Instant,
+    /// When anything was last heard from the load
+    active: Instant,
+    /// Received from the load over udp
+    received: netproto::Report,
+    /// Sent back to the load over udp
+    sent_bytes: u64,
+    /// The received and sent bytes at the end of the last interval
+    last: (u64, u64),
+}
+
+impl Session {
+    fn new(test: netproto::Test) -> Self {
+        use std::hash::{BuildHasher, Hasher};
+        Self {
+            test,
+            // Randomly keyed, so that other hosts can not guess it
+            token: std::collections::hash_map::RandomState::new()
+                .build_hasher()
+                .finish(),
+            started: Instant::now(),
+            active: Instant::now(),
+            received: Default::default(),
+            sent_bytes: 0,
+            last: (0, 0),
+        }
+    }
+
+    fn is_tcp(&self) -> bool {
+        matches!(self.test, netproto::Test::Tcp { .. })
+    }
+
+    /// What was received and how many bytes were sent during the test.
+    fn totals(&self, tcp_clients: &[TcpClient]) -> (netproto::Report, u64) {
+        let mut received = self.received;
+        let mut sent = self.sent_bytes;
+        for c in tcp_clients.iter().filter(|c| c.token == self.token) {
+            received.bytes += c.received.load(Ordering::Relaxed);
+            sent += c.sent.load(Ordering::Relaxed);
+        }
+        (received, sent)
+    }
+
+    /// Segments retransmitted on the tcp streams of the test, if the os reports them.
+    fn retransmits(&self, tcp_clients: &[TcpClient]) -> Option<u64> {
+        if !self.is_tcp() {
+            return None;
+        }
+        tcp_clients
+            .iter()
+            .filter(|c| c.token == self.token)
+            .map(|c| netproto::tcp_retransmits(&c.socket))
+            .sum()
+    }
+
+    /// Describe what was exchanged during the test, for the log.
+    fn summary(&self, tcp_clients: &[TcpClient]) -> String {
+        let (received, sent) = self.totals(tcp_clients);
+        format!(
+            "{} packets and {} bytes received, {} bytes sent in {:.1} s",
+            received.packets,
+            received.bytes,
+            sent,
+            self.started.elapsed().as_secs_f64()
+        )
+    }
+}
+
+/// End every test because the listener is stopping, closing their tcp streams.
+fn end_sessions(sessions: &mut HashMap<SocketAddr, Session>, tcp_clients: &mut Vec<TcpClient>) {
+    for (addr, session) in sessions.drain() {
+        log(format_args!(
+            "Load {} ended by the listener stopping, {}",
+            addr,
+            session.summary(tcp_clients)
+        ));
+    }
+    for c in &tcp_clients[..] {
+        c.stop.store(true, Ordering::Relaxed);
+    }
+    for c in tcp_clients.drain(..) {
+        let _e = c.thread.join();
+    }
+}
+
+/// Loads that have not been heard from for this long are forgotten.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before binding again when an address could not be bound.
+const BIND_RETRY: Duration = Duration::from_secs(5);
+
+/// The sockets of a running listener.
+struct Sockets {
+    /// Probes, control messages and hellos sent straight to the listener
+    udp: UdpSocket,
+    /// Hellos sent to the broadcast address, or to the multicast group over IPv6
+    hellos: Option<UdpSocket>,
+    tcp: Option<TcpListener>,
+}
+
+impl Sockets {
+    /// Bind `port` on `addr` of the interface numbered `index`.
+    fn bind(addr: &network_interface::Addr, index: u32, port: u16) -> Option<Self> {
+        let ip = addr.ip();
+        // Loopback reports its own address as the broadcast address, where the
+        // socket below answers hellos along with everything else
+        let hellos = netproto::hello_addr(addr, index, port).filter(|h| h.ip() != ip);
+        let hellos = hellos.and_then(|h| {
+            let s = UdpSocket::bind(h)
+                .map_err(|e| log(format_args!("Unable to listen for hellos on {}: {}", h, e)))
+                .ok()?;
+            if let network_interface::Addr::V6(_) = addr {
+                s.join_multicast_v6(&netproto::MULTICAST_V6, index)
+                    .map_err(|e| log(format_args!("Unable to join {}: {}", h, e)))
+                    .ok()?;
+            }
+            s.set_nonblocking(true).ok()?;
+            Some(s)
+        });
+        let bind = netproto::bind_addr(ip, index, port);
+        // Waiting a little for probes keeps the loop from spinning while idle
+        let udp = UdpSocket::bind(bind)
+            .and_then(|s| {
+                s.set_read_timeout(Some(Duration::from_millis(10)))
+                    .map(|_| s)
+            })
+            .map_err(|e| log(format_args!("Unable to listen on {}: {}", bind, e)))
+            .ok()?;
+        let tcp = match TcpListener::bind(bind) {
+            Ok(t) if t.set_nonblocking(true).is_ok() => Some(t),
+            Ok(_) => None,
+            Err(e) => {
+                log(format_args!("Unable to listen for tcp on {}: {}", bind, e));
+                None
+            }
+        };
+        log(format_args!("Listening on {}", bind));
+        Some(Self { udp, hellos, tcp })
+    }
+
+    /// The answer to a control message from the load at `from`.
+    fn answer(
+        &self,
+        message: Control,
+        from: SocketAddr,
+        sessions: &mut HashMap<SocketAddr, Session>,
+        tcp_clients: &[TcpClient],
+    ) -> Control {
+        let error = |message: &str| Control::Error {
+            message: message.to_string(),
+        };
+        match message {
+            Control::Hello { version } if version == netproto::VERSION => {
+                Control::Capabilities(netproto::Capabilities {
+                    version,
+                    udp_echo: true,
+                    tcp: self.tcp.is_some(),
+                    max_packet: netproto::MAX_PACKET,
+                })
+            }
+            Control::Hello { version } => error(&format!(
+                "The listener speaks version {}, not {}",
+                netproto::VERSION,
+                version
+            )),
+            Control::Start { test } => {
+                match test {
+                    netproto::Test::UdpEcho { packet_size, .. }
+                        if !(netproto::PROBE_HEADER..=netproto::MAX_PACKET)
+                            .contains(&packet_size) =>
+                    {
+                        return error(&format!("Packets of {} bytes are not echoed", packet_size))
+                    }
+                    netproto::Test::Tcp { .. } if self.tcp.is_none() => {
+                        return error("Tcp is not available")
+                    }
+                    netproto::Test::Tcp {
+                        upload_streams: 0,
+                        download_streams: 0,
+                    } => return error("A tcp test needs at least one stream"),
+                    _ => {}
                 }
+                log(format_args!("Load {} started {:?}", from, test));
+                let session = Session::new(test);
+                let token = session.token;
+                sessions.insert(from, session);
+                Control::Started { token }
             }
+            Control::Stop => match sessions.remove(&from) {
+                Some(session) => {
+                    let (received, sent_bytes) = session.totals(tcp_clients);
+                    log(format_args!(
+                        "Load {} stopped, {}",
+                        from,
+                        session.summary(tcp_clients)
+                    ));
+                    Control::Results {
+                        received,
+                        sent_bytes,
+                        seconds: session.started.elapsed().as_secs_f64(),
+                        retransmits: session.retransmits(tcp_clients),
+                    }
+                }
+                None => error("No test is running"),
+            },
+            _ => error("Unexpected message"),
         }
     }
+}
 
-    fn new(addr: &network_interface::Addr) -> Self {
+impl NetworkListener {
+    fn new(addr: &network_interface::Addr, index: u32, port: u16) -> Self {
         let addr = addr.to_owned();
-        let (s, r) = std::sync::mpsc::channel();
-        let (s2, r2) = std::sync::mpsc::channel();
-        let thread = std::thread::spawn(move || {
-            let mut running = false;
-            let mut socket: Option<UdpSocket> = None;
-            let mut broadcast_socket: Option<UdpSocket> = None;
-            let mut buf: [u8; 10000] = [0; 10000];
-            'main: loop {
-                while let Ok(message) = r.try_recv() {
-                    match message {
-                        MessageToNetworkListener::Start => {
-                            running = true;
-                            if s2
-                                .send(MessageFromNetworkListener::Listening(running))
-                                .is_err()
-                            {
-                                break 'main;
-                            }
+        let thread = WorkloadThread::spawn(move |mut ctx| {
+            let mut port = port;
+            let mut sockets: Option<Sockets> = None;
+            let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+            let mut tcp_clients: Vec<TcpClient> = vec![];
+            let mut pending: Vec<PendingStream> = vec![];
+            let mut buf = vec![0; netproto::MAX_PACKET];
+            let mut interval_start = Instant::now();
+            let mut bind_failed: Option<Instant> = None;
+            while ctx.poll(|m| match m {
+                MessageToListener::Port(p) => {
+                    port = p;
+                    None
+                }
+            }) {
+                if !ctx.running() {
+                    sockets = None;
+                    pending.clear();
+                    bind_failed = None;
+                    end_sessions(&mut sessions, &mut tcp_clients);
+                    ctx.idle();
+                    continue;
+                }
+                let Some(s) = &sockets else {
+                    // An address that can not be bound yet may become usable later
+                    if bind_failed.is_some_and(|t| t.elapsed() < BIND_RETRY) {
+                        ctx.idle();
+                        continue;
+                    }
+                    sockets = Sockets::bind(&addr, index, port);
+                    interval_start = Instant::now();
+                    bind_failed = sockets.is_none().then(Instant::now);
+                    continue;
+                };
+                if let Some(b) = &s.hellos {
+                    if let Ok((size, from)) = b.recv_from(&mut buf) {
+                        // Answered from the listener's own address, which the load talks to from then on
+                        if let Some(hello @ Control::Hello { .. }) = Control::read(&buf[..size]) {
+                            log(format_args!("Received hello from {}", from));
+                            let answer = s.answer(hello, from, &mut sessions, &tcp_clients);
+                            let _e = s.udp.send_to(&answer.to_bytes(), from);
                         }
-                        MessageToNetworkListener::Stop => {
-                            running = false;
-                            socket = None;
-                            if s2
-                                .send(MessageFromNetworkListener::Listening(running))
-                                .is_err()
-                            {
-                                break 'main;
-                            }
+                    }
+                }
+                if let Some(t) = &s.tcp {
+                    while let Ok((stream, from)) = t.accept() {
+                        // The header is read without holding up everything else
+                        if stream.set_nonblocking(true).is_ok() {
+                            pending.push(PendingStream {
+                                stream,
+                                from,
+                                accepted: Instant::now(),
+                                header: vec![],
+                            });
                         }
-                        MessageToNetworkListener::Exit => break 'main,
                     }
                 }
-                if running {
-                    if socket.is_none() {
-                        let s = match addr {
-                            network_interface::Addr::V4(a) => UdpSocket::bind((a.ip, 5003)),
-                            network_interface::Addr::V6(a) => UdpSocket::bind((a.ip, 5003)),
-                        };
-                        let broad = match addr {
-                            network_interface::Addr::V4(a) => {
-                                if let Some(b) = a.broadcast {
-                                    Some(UdpSocket::bind((b, 5003)))
-                                } else {
-                                    None
-                                }
-                            }
-                            network_interface::Addr::V6(a) => {
-                                if let Some(b) = a.broadcast {
-                                    Some(UdpSocket::bind((b, 5003)))
-                                } else {
-                                    None
-                                }
-                            }
-                        };
-                        if let Some(Ok(broad)) = broad {
-                            if broad.set_nonblocking(true).is_ok() {
-                                broadcast_socket = Some(broad);
-                            }
+                for mut p in std::mem::take(&mut pending) {
+                    let (mode, token) = match p.read_header() {
+                        Ok(Some(header)) => header,
+                        Ok(None) if p.accepted.elapsed() < HEADER_TIMEOUT => {
+                            pending.push(p);
+                            continue;
                         }
-                        if let Ok(r) = s {
-                            if r.set_nonblocking(true).is_ok() {
-                                socket = Some(r);
-                            }
+                        Ok(None) => {
+                            log(format_args!(
+                                "Refused tcp stream from {}, no header",
+                                p.from
+                            ));
+                            continue;
                         }
-                    } else {
-                        if let Some(s) = &mut broadcast_socket {
-                            if let Ok((_size, addr)) = s.recv_from(&mut buf[..]) {
-                                println!("Received broadcast from {:?} {}", addr, buf[0]);
-                                if buf[0] == b'A' {
-                                    s.send_to(&buf[..], addr);
-                                }
-                            }
+                        Err(e) => {
+                            log(format_args!("Tcp stream from {} failed: {}", p.from, e));
+                            continue;
                         }
-                        if let Some(s) = &mut socket {
-                            if let Ok((_size, addr)) = s.recv_from(&mut buf[..]) {
-                                s.send_to(&buf[..], addr);
-                            }
+                    };
+                    // Streams belong to the tcp test that handed out their token
+                    if !sessions.values().any(|s| s.is_tcp() && s.token == token) {
+                        log(format_args!(
+                            "Refused tcp stream from {}, no test has its token",
+                            p.from
+                        ));
+                        continue;
+                    }
+                    log(format_args!("Tcp stream from {}", p.from));
+                    match TcpClient::serve(p.stream, p.from, token, mode) {
+                        Ok(c) => tcp_clients.push(c),
+                        Err(e) => log(format_args!(
+                            "Unable to serve tcp stream from {}: {}",
+                            p.from, e
+                        )),
+                    }
+                }
+                if let Ok((size, from)) = s.udp.recv_from(&mut buf) {
+                    if let Some(message) = Control::read(&buf[..size]) {
+                        let answer = s.answer(message, from, &mut sessions, &tcp_clients);
+                        let _e = s.udp.send_to(&answer.to_bytes(), from);
+                        end_streams(&sessions, &mut tcp_clients);
+                    } else if let Some(session) = sessions.get_mut(&from) {
+                        // Probes are echoed back unchanged, the load measures the round trip
+                        if buf[0] == netproto::PROBE && !session.is_tcp() {
+                            let _e = s.udp.send_to(&buf[..size], from);
+                            session.received.packets += 1;
+                            session.received.bytes += size as u64;
+                            session.sent_bytes += size as u64;
                         }
+                        session.active = Instant::now();
                     }
-                } else {
-                    std::thread::sleep(Duration::from_millis(100));
+                }
+                let elapsed = interval_start.elapsed();
+                if elapsed < REPORT_INTERVAL {
+                    continue;
+                }
+                interval_start = Instant::now();
+                let seconds = elapsed.as_secs_f64();
+                let mut total = 0.0;
+                let mut stats = vec![];
+                for (addr, session) in &mut sessions {
+                    let (received, sent_bytes) = session.totals(&tcp_clients);
+                    let rate = (received.bytes - session.last.0) as f64 / seconds;
+                    let send_rate = (sent_bytes - session.last.1) as f64 / seconds;
+                    session.last = (received.bytes, sent_bytes);
+                    if session.is_tcp() {
+                        total += rate + send_rate;
+                        if tcp_clients
+                            .iter()
+                            .any(|c| c.token == session.token && !c.thread.is_finished())
+                        {
+                            session.active = Instant::now();
+                        }
+                    } else {
+                        total += rate;
+                        let report = netproto::Report {
+                            packets: received.packets,
+                            bytes: received.bytes,
+                        };
+                        let _e = s.udp.send_to(&report.to_bytes(), addr);
+                    }
+                    stats.push(ClientStats {
+                        addr: *addr,
+                        protocol: if session.is_tcp() { "tcp" } else { "udp" },
+                        seconds: session.started.elapsed().as_secs_f64(),
+                        report: received,
+                        rate,
+                        sent_bytes,
+                        send_rate,
+                    });
+                }
+                // Loads that went away without stopping their test are forgotten
+                sessions.retain(|addr, s| {
+                    let keep = s.active.elapsed() < SESSION_TIMEOUT;
+                    if !keep {
+                        log(format_args!(
+                            "Load {} timed out, {}",
+                            addr,
+                            s.summary(&tcp_clients)
+                        ));
+                    }
+                    keep
+                });
+                end_streams(&sessions, &mut tcp_clients);
+                stats.sort_by_key(|c| c.addr);
+                if !ctx.performance(total) || !ctx.report(stats) {
+                    break;
                 }
             }
-            let _e = s2.send(MessageFromNetworkListener::Done);
+            end_sessions(&mut sessions, &mut tcp_clients);
         });
         Self {
             thread,
-            recv: r2,
-            send: s,
-            listening: false,
-            done: false,
             addr,
+            port,
+            clients: vec![],
+        }
+    }
+
+    /// Change the port that is bound, this takes effect the next time the listener is started.
+    fn set_port(&mut self, port: u16) {
+        self.port = port;
+        self.thread.send(MessageToListener::Port(port));
+    }
+}
+
+impl Workload for NetworkListener {
+    fn kind(&self) -> WorkloadKind {
+        WorkloadKind::NetworkListener
+    }
+
+    fn target(&self) -> String {
+        self.addr.ip().to_string()
+    }
+
+    fn units(&self) -> &'static str {
+        "bytes/s"
+    }
+
+    fn control(&self) -> &dyn WorkloadControl {
+        &self.thread
+    }
+
+    fn control_mut(&mut self) -> &mut dyn WorkloadControl {
+        &mut self.thread
+    }
+
+    fn process_messages(&mut self) {
+        if let Some(clients) = self.thread.process_messages().pop() {
+            self.clients = clients;
         }
     }
 }
@@ -153,6 +614,8 @@ impl NetworkListener {
 pub struct AppCommon {
     networks: Vec<network_interface::NetworkInterface>,
     netlisteners: Vec<NetworkListener>,
+    /// The port that listeners bind when they are next started
+    port: u16,
 }
 
 impl egui_multiwin::multi_window::CommonEventHandler<AppCommon, u32> for AppCommon {
@@ -170,33 +633,26 @@ impl egui_multiwin::multi_window::CommonEventHandler<AppCommon, u32> for AppComm
 }
 
 fn main() {
-    let event_loop = egui_multiwin::winit::event_loop::EventLoopBuilder::with_user_event().build();
-    let mut multi_window: MultiWindow<AppCommon, u32> = MultiWindow::new();
-    let root_window = root::RootWindow::new();
-
-    println!("Starting application");
+    let options = listenercli::Options::parse(std::env::args().skip(1));
+    if options.as_ref().map_or(true, |o| o.headless || o.help) {
+        listenerservice::attach_console();
+    }
+    let options = match options {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}\n{}", e, listenercli::USAGE);
+            std::process::exit(1);
+        }
+    };
+    if options.help {
+        println!("{}", listenercli::USAGE);
+        return;
+    }
 
-    #[cfg(target_os = "linux")]
-    let ms = lm_sensors::Initializer::default().initialize();
-
-    let mut threads = vec![];
-    #[cfg(feature = "hwlocality")]
-    let topology = hwlocality::Topology::new();
-    if let Err(e) = &topology {
-        println!("Error obtaining topology {}", e);
-    }
-    let mut topology = topology.ok();
-    if let Some(topology) = &mut topology {
-        let root = topology.root_object();
-        let cpuset = root.cpuset();
-        if let Some(cpuset) = cpuset {
-            for index in cpuset.iter_set() {
-                let thread = cpu::CpuLoadThread::new();
-                thread
-                    .send
-                    .send(MessageToCpuLoad::Associate(topology.clone(), index.into()));
-                threads.push(thread);
-            }
+    if let Some(path) = &options.log {
+        if let Err(e) = listenerservice::open_log(path) {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     }
 
@@ -204,17 +660,182 @@ fn main() {
     if let Ok(mut n) = network_interface::NetworkInterface::show() {
         networks.append(&mut n);
     }
+    if let Some(name) = options
+        .interfaces
+        .iter()
+        .find(|name| !networks.iter().any(|n| &n.name == *name))
+    {
+        eprintln!("No interface named {}", name);
+        std::process::exit(1);
+    }
+    if !options.interfaces.is_empty() {
+        networks.retain(|n| options.interfaces.contains(&n.name));
+    }
 
     let netlisteners: Vec<NetworkListener> = networks
         .iter()
-        .flat_map(|net| net.addr.iter().map(|addr| NetworkListener::new(addr)))
+        .flat_map(|net| {
+            net.addr
+                .iter()
+                .map(|addr| NetworkListener::new(addr, net.index, options.port))
+        })
         .collect();
 
+    if options.headless {
+        if let Err(e) = listenerservice::run(&options, netlisteners) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let event_loop = egui_multiwin::winit::event_loop::EventLoopBuilder::with_user_event().build();
+    let mut multi_window: MultiWindow<AppCommon, u32> = MultiWindow::new();
+    let root_window = root::RootWindow::new();
+
+    println!("Starting application");
+
     let ac = AppCommon {
         networks,
         netlisteners,
+        port: options.port,
     };
 
     let _e = multi_window.add(root_window, &event_loop);
     multi_window.run(event_loop, ac);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sockets() -> Sockets {
+        Sockets {
+            udp: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            hellos: None,
+            tcp: None,
+        }
+    }
+
+    fn from() -> SocketAddr {
+        "127.0.0.1:5002".parse().unwrap()
+    }
+
+    #[test]
+    fn answers_hello_of_same_version() {
+        let answer = sockets().answer(
+            Control::Hello {
+                version: netproto::VERSION,
+            },
+            from(),
+            &mut HashMap::new(),
+            &[],
+        );
+        match answer {
+            Control::Capabilities(c) => {
+                assert_eq!(c.version, netproto::VERSION);
+                assert!(!c.tcp);
+            }
+            other => panic!("Unexpected answer {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_other_versions() {
+        for version in [0, netproto::VERSION + 1] {
+            let answer =
+                sockets().answer(Control::Hello { version }, from(), &mut HashMap::new(), &[]);
+            assert!(matches!(answer, Control::Error { .. }), "{:?}", answer);
+        }
+    }
+
+    #[test]
+    fn start_and_stop() {
+        let s = sockets();
+        let mut sessions = HashMap::new();
+        let start = Control::Start {
+            test: netproto::Test::UdpEcho {
+                packet_size: 1000,
+                bitrate: None,
+            },
+        };
+        let answer = s.answer(start, from(), &mut sessions, &[]);
+        assert!(matches!(answer, Control::Started { .. }), "{:?}", answer);
+        assert!(sessions.contains_key(&from()));
+        match s.answer(Control::Stop, from(), &mut sessions, &[]) {
+            Control::Results {
+                received,
+                sent_bytes,
+                retransmits,
+                ..
+            } => {
+                assert_eq!(received, netproto::Report::default());
+                assert_eq!(sent_bytes, 0);
+                assert_eq!(retransmits, None);
+            }
+            other => panic!("Unexpected answer {:?}", other),
+        }
+        assert!(sessions.is_empty());
+        let answer = s.answer(Control::Stop, from(), &mut sessions, &[]);
+        assert!(matches!(answer, Control::Error { .. }), "{:?}", answer);
+    }
+
+    #[test]
+    fn refuses_tests_it_can_not_run() {
+        let s = sockets();
+        let mut sessions = HashMap::new();
+        let tests = [
+            netproto::Test::UdpEcho {
+                packet_size: netproto::PROBE_HEADER - 1,
+                bitrate: None,
+            },
+            netproto::Test::UdpEcho {
+                packet_size: netproto::MAX_PACKET + 1,
+                bitrate: None,
+            },
+            // The listener under test has no tcp socket
+            netproto::Test::Tcp {
+                upload_streams: 1,
+                download_streams: 0,
+            },
+        ];
+        for test in tests {
+            let answer = s.answer(Control::Start { test }, from(), &mut sessions, &[]);
+            assert!(matches!(answer, Control::Error { .. }), "{:?}", answer);
+        }
+        assert!(sessions.is_empty());
+    }
+
+    #[test]
+    fn sessions_get_different_tokens() {
+        let test = netproto::Test::Tcp {
+            upload_streams: 1,
+            download_streams: 1,
+        };
+        assert_ne!(Session::new(test.clone()).token, Session::new(test).token);
+    }
+
+    #[test]
+    fn stopping_a_test_stops_its_streams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let load = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let mut sessions = HashMap::new();
+        let session = Session::new(netproto::Test::Tcp {
+            upload_streams: 1,
+            download_streams: 0,
+        });
+        let token = session.token;
+        sessions.insert(from(), session);
+        let mut tcp_clients =
+            vec![TcpClient::serve(stream, addr, token, netproto::TCP_UPLOAD).unwrap()];
+        let stop = tcp_clients[0].stop.clone();
+        end_streams(&sessions, &mut tcp_clients);
+        assert_eq!(tcp_clients.len(), 1);
+        sessions.clear();
+        end_streams(&sessions, &mut tcp_clients);
+        assert!(tcp_clients.is_empty());
+        assert!(stop.load(Ordering::Relaxed));
+        drop(load);
+    }
+}
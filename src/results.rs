@@ -0,0 +1,315 @@
+//! Structured results of a benchmark run, with export to json and csv.
+
+use std::{collections::BTreeMap, io::Write, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::workload::{Workload, WorkloadKind};
+
+/// Information about the machine the benchmark was run on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HostInfo {
+    pub hostname: String,
+    pub os: String,
+    pub kernel: String,
+    pub cpu: String,
+    pub cpu_count: usize,
+    /// Total memory in bytes
+    pub memory: u64,
+}
+
+impl HostInfo {
+    pub fn gather() -> Self {
+        use sysinfo::{CpuExt, SystemExt};
+        let mut sys = sysinfo::System::new();
+        sys.refresh_cpu();
+        sys.refresh_memory();
+        Self {
+            hostname: sys.host_name().unwrap_or_default(),
+            os: sys.long_os_version().unwrap_or_default(),
+            kernel: sys.kernel_version().unwrap_or_default(),
+            cpu: sys
+                .cpus()
+                .first()
+                .map(|c| c.brand().trim().to_string())
+                .unwrap_or_default(),
+            cpu_count: sys.cpus().len(),
+            memory: sys.total_memory(),
+        }
+    }
+}
+
+/// A single performance measurement.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Sample {
+    /// Seconds since the workload was started
+    pub time: f64,
+    pub value: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    pub fn from_samples(samples: &[Sample]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        let mut min = f64::MAX;
+        let mut max = f64::MIN;
+        let mut sum = 0.0;
+        for s in samples {
+            min = min.min(s.value);
+            max = max.max(s.value);
+            sum += s.value;
+        }
+        Self {
+            count: samples.len(),
+            min,
+            mean: sum / samples.len() as f64,
+            max,
+        }
+    }
+}
+
+/// The result of running a single workload.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkloadResult {
+    pub kind: WorkloadKind,
+    pub target: String,
+    pub units: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub samples: Vec<Sample>,
+    pub summary: Summary,
+    /// Figures specific to the kind of workload
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub details: BTreeMap<String, f64>,
+    /// Other measurements sampled over time while the workload ran
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub series: BTreeMap<String, Vec<Sample>>,
+}
+
+/// The results of every workload that ran as part of one benchmark run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunResult {
+    pub host: HostInfo,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub workloads: Vec<WorkloadResult>,
+}
+
+/// Quote a field for a csv file if required.
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+impl RunResult {
+    /// Collect the results of all workloads that have been run.
+    pub fn collect<'a, I>(workloads: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = &'a dyn Workload>,
+    {
+        Self::new(workloads.into_iter().filter_map(|w| w.result()).collect())
+    }
+
+    /// A run made of the given results, if there are any.
+    pub fn new(workloads: Vec<WorkloadResult>) -> Option<Self> {
+        let start = workloads.iter().map(|w| w.start).min()?;
+        let end = workloads.iter().map(|w| w.end).max()?;
+        Some(Self {
+            host: HostInfo::gather(),
+            start,
+            end,
+            workloads,
+        })
+    }
+
+    pub fn write_json(&self, path: &Path) -> std::io::Result<()> {
+        let f = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+
+    /// Write every sample as one row of a csv file.
+    pub fn write_csv(&self, path: &Path) -> std::io::Result<()> {
+        let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(f, "hostname,kind,target,units,start,time,value")?;
+        for w in &self.workloads {
+            for s in &w.samples {
+                writeln!(
+                    f,
+                    "{},{},{},{},{},{},{}",
+                    csv_field(&self.host.hostname),
+                    w.kind,
+                    csv_field(&w.target),
+                    w.units,
+                    w.start.to_rfc3339(),
+                    s.time,
+                    s.value
+                )?;
+            }
+        }
+        f.flush()
+    }
+
+    /// A line of text summarizing each workload.
+    pub fn summary_lines(&self) -> Vec<String> {
+        self.workloads
+            .iter()
+            .map(|w| {
+                let mut line = format!(
+                    "{} {}: min {:.2} mean {:.2} max {:.2} {}",
+                    w.kind, w.target, w.summary.min, w.summary.mean, w.summary.max, w.units
+                );
+                for (name, value) in &w.details {
+                    if value.fract() == 0.0 {
+                        line.push_str(&format!(", {} {}", name, value));
+                    } else {
+                        line.push_str(&format!(", {} {:.2}", name, value));
+                    }
+                }
+                line
+            })
+            .chain(self.total_line(WorkloadKind::Cpu))
+            .collect()
+    }
+
+    /// The sum of the mean performance of every workload of a kind, such as
+    /// the aggregate GFLOPS of all cores.
+    pub fn total(&self, kind: WorkloadKind) -> Option<f64> {
+        let mut found = false;
+        let mut total = 0.0;
+        for w in self.workloads.iter().filter(|w| w.kind == kind) {
+            found = true;
+            total += w.summary.mean;
+        }
+        found.then_some(total)
+    }
+
+    fn total_line(&self, kind: WorkloadKind) -> Option<String> {
+        let units = &self.workloads.iter().find(|w| w.kind == kind)?.units;
+        let total = self.total(kind)?;
+        Some(format!("{} total: {:.2} {}", kind, total, units))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run() -> RunResult {
+        let start = "2026-01-02T03:04:05Z".parse::<DateTime<Utc>>().unwrap();
+        let samples = vec![
+            Sample {
+                time: 1.0,
+                value: 10.0,
+            },
+            Sample {
+                time: 2.0,
+                value: 30.0,
+            },
+        ];
+        let workload = |kind, target: &str| WorkloadResult {
+            kind,
+            target: target.to_string(),
+            units: "GFLOPS".to_string(),
+            start,
+            end: start,
+            summary: Summary::from_samples(&samples),
+            samples: samples.clone(),
+            details: [("passes".to_string(), 2.0)].into_iter().collect(),
+            series: Default::default(),
+        };
+        RunResult {
+            host: HostInfo {
+                hostname: "host, \"lab\"".to_string(),
+                os: "os".to_string(),
+                kernel: "kernel".to_string(),
+                cpu: "cpu".to_string(),
+                cpu_count: 2,
+                memory: 1 << 30,
+            },
+            start,
+            end: start,
+            workloads: vec![
+                workload(WorkloadKind::Cpu, "core 0"),
+                workload(WorkloadKind::Cpu, "core 1"),
+            ],
+        }
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("benchmark-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        assert_eq!(csv_field("core 0"), "core 0");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn csv_has_a_row_per_sample() {
+        let path = temp_path("results.csv");
+        run().write_csv(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _e = std::fs::remove_file(&path);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "hostname,kind,target,units,start,time,value");
+        assert_eq!(
+            lines[1],
+            "\"host, \"\"lab\"\"\",cpu,core 0,GFLOPS,2026-01-02T03:04:05+00:00,1,10"
+        );
+        assert!(lines[4].contains(",core 1,"));
+    }
+
+    #[test]
+    fn json_reads_back() {
+        let path = temp_path("results.json");
+        let written = run();
+        written.write_json(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _e = std::fs::remove_file(&path);
+        let read: RunResult = serde_json::from_str(&text).unwrap();
+        assert_eq!(read.host.hostname, written.host.hostname);
+        assert_eq!(read.start, written.start);
+        assert_eq!(read.workloads.len(), 2);
+        let w = &read.workloads[1];
+        assert_eq!(w.kind, WorkloadKind::Cpu);
+        assert_eq!(w.target, "core 1");
+        assert_eq!(w.samples.len(), 2);
+        assert_eq!(w.summary.mean, 20.0);
+        assert_eq!(w.details.get("passes"), Some(&2.0));
+        // Empty maps are left out of the file
+        assert!(!text.contains("series"));
+    }
+
+    #[test]
+    fn summary_and_total() {
+        let r = run();
+        let s = &r.workloads[0].summary;
+        assert_eq!((s.count, s.min, s.mean, s.max), (2, 10.0, 20.0, 30.0));
+        assert_eq!(r.total(WorkloadKind::Cpu), Some(40.0));
+        assert_eq!(r.total(WorkloadKind::Disk), None);
+        let lines = r.summary_lines();
+        assert_eq!(
+            lines[0],
+            "cpu core 0: min 10.00 mean 20.00 max 30.00 GFLOPS, passes 2"
+        );
+        assert_eq!(lines[2], "cpu total: 40.00 GFLOPS");
+    }
+}
@@ -7,12 +7,28 @@ use egui_multiwin::{
 #[cfg(target_os = "linux")]
 use lm_sensors::prelude::*;
 
+use crate::disk::{DiskConfig, DiskEngine, DiskPattern, DiskSync};
+use crate::nettcp::TcpDirection;
+use crate::results::Summary;
+use crate::workload::Workload;
 use crate::{AppCommon, MessageToGui};
 
 use sysinfo::{DiskExt, NetworkExt, NetworksExt, ProcessExt, System, SystemExt};
 
 pub struct RootWindow {}
 
+/// Sync loads write to their target, which is only allowed from the gui
+/// for new or test files and never for a disk.
+fn safe_config(path: &std::path::Path, mut config: DiskConfig) -> DiskConfig {
+    if config.sync.is_some()
+        && !path.is_dir()
+        && crate::diskverify::check_target(path, false).is_err()
+    {
+        config.sync = None;
+    }
+    config
+}
+
 impl RootWindow {
     pub fn new() -> NewWindowRequest<AppCommon> {
         NewWindowRequest {
@@ -57,8 +73,8 @@ impl TrackedWindow<AppCommon> for RootWindow {
         }
         while let Ok(m) = c.sysinfo.try_recv() {
             match m {
-                crate::SysInfoMessage::DiskThread(d) => {
-                    c.disks.push(d);
+                crate::SysInfoMessage::DiskTargets(t) => {
+                    c.disk_targets = t;
                 }
             }
         }
@@ -68,14 +84,16 @@ impl TrackedWindow<AppCommon> for RootWindow {
         for nt in &mut c.net_threads {
             nt.process_messages();
         }
-
-        c.net_threads = c.net_threads.drain(..).filter(|i| !i.done).collect();
+        for tl in &mut c.tcp_loads {
+            tl.process_messages();
+        }
+        c.remove_done();
 
         while let Ok(message) = c.gui_recv.try_recv() {
             match message {
                 MessageToGui::StopAllCpu => {
-                    for t in &mut c.cpu_threads {
-                        let _e = t.send.send(crate::cpu::MessageToCpuLoad::Stop);
+                    for t in &c.cpu_threads {
+                        t.stop();
                     }
                 }
             }
@@ -83,31 +101,374 @@ impl TrackedWindow<AppCommon> for RootWindow {
 
         egui_multiwin::egui::CentralPanel::default().show(&egui.egui_ctx, |ui| {
             ui.label("I am groot".to_string());
+            ui.horizontal(|ui| {
+                if ui.button("Export results").clicked() {
+                    c.export_status = Some(match c.export_results() {
+                        Ok(m) => m,
+                        Err(e) => e,
+                    });
+                }
+                if ui.button("Save run").clicked() {
+                    c.export_status = Some(match c.save_results(false) {
+                        Ok(m) => m,
+                        Err(e) => e,
+                    });
+                }
+                if ui.button("Save as baseline").clicked() {
+                    c.export_status = Some(match c.save_results(true) {
+                        Ok(m) => m,
+                        Err(e) => e,
+                    });
+                }
+                if ui.button("Clear removed results").clicked() {
+                    c.export_status = Some(format!(
+                        "Cleared the results of {} removed loads",
+                        c.finished.len()
+                    ));
+                    c.finished.clear();
+                }
+                if ui.button("Compare with baseline").clicked() {
+                    match c.compare_results() {
+                        Ok(lines) => c.comparison = lines,
+                        Err(e) => c.export_status = Some(e),
+                    }
+                }
+                ui.label("Tolerance %");
+                ui.add(
+                    egui_multiwin::egui::DragValue::new(&mut c.tolerance)
+                        .clamp_range(0.0..=100.0)
+                        .speed(0.5),
+                );
+            });
+            if let Some(status) = &c.export_status {
+                ui.label(status);
+            }
+            for line in &c.comparison {
+                ui.label(line);
+            }
             egui_multiwin::egui::ScrollArea::vertical().show(ui, |ui| {
+                let previous_net = c.net_config.clone();
+                ui.horizontal(|ui| {
+                    let mut mbits = c.net_config.bitrate.unwrap_or(0) as f64 / 1e6;
+                    ui.label("Network target Mbit/s (0 is unlimited)");
+                    ui.add(
+                        egui_multiwin::egui::DragValue::new(&mut mbits)
+                            .clamp_range(0.0..=100_000.0)
+                            .speed(1.0),
+                    );
+                    c.net_config.bitrate = Some((mbits * 1e6) as u64).filter(|b| *b > 0);
+                    ui.label("Packet size");
+                    ui.add(
+                        egui_multiwin::egui::DragValue::new(&mut c.net_config.packet_size)
+                            .clamp_range(
+                                crate::netproto::PROBE_HEADER..=crate::netproto::MAX_PACKET,
+                            ),
+                    );
+                });
+                if c.net_config != previous_net {
+                    for nt in &mut c.net_threads {
+                        nt.configure(c.net_config.clone());
+                    }
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Listener port");
+                    ui.add(
+                        egui_multiwin::egui::DragValue::new(&mut c.listener_port)
+                            .clamp_range(1..=u16::MAX),
+                    );
+                    if ui.button("Discover listeners").clicked() {
+                        c.discover_listeners();
+                    }
+                    ui.label("Listener host:port");
+                    ui.text_edit_singleline(&mut c.listener_endpoint);
+                    if ui.button("Connect").clicked() {
+                        let endpoint = c.listener_endpoint.clone();
+                        c.net_error = c.connect_listener(&endpoint).err();
+                    }
+                });
+                if let Some(e) = &c.net_error {
+                    ui.label(e);
+                }
+                let known = c
+                    .known_listeners
+                    .as_ref()
+                    .map(|k| k.endpoints.clone())
+                    .unwrap_or_default();
+                for endpoint in known {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Known listener {}", endpoint));
+                        if ui.button("Connect").clicked() {
+                            c.net_error = c.connect_listener(&endpoint).err();
+                        }
+                        if ui.button("Forget").clicked() {
+                            if let Some(k) = &mut c.known_listeners {
+                                c.net_error = k
+                                    .remove(&endpoint)
+                                    .map_err(|e| format!("Unable to forget {}: {}", endpoint, e))
+                                    .err();
+                            }
+                        }
+                    });
+                }
                 for nt in &mut c.net_threads {
                     if let Some(server) = nt.server {
-                        ui.label(format!("Network load: {:?} {}", server.ip(), nt.done));
+                        ui.label(format!(
+                            "Network load on {} to {:?} {}",
+                            nt.target(),
+                            server.ip(),
+                            nt.done()
+                        ));
+                        ui.label(format!(
+                            "{} packets sent, {} echoed, {}",
+                            nt.stats.sent_packets, nt.stats.received_packets, nt.stats,
+                        ));
+                        ui.label(format!("rtt {}", nt.stats.percentiles));
+                        if ui.button("Start").clicked() {
+                            nt.start();
+                        }
+                        if ui.button("Stop").clicked() {
+                            nt.stop();
+                        }
+                        if ui.button("Add tcp load").clicked() {
+                            let route = crate::netload::Route {
+                                hello: server,
+                                ..nt.route.clone()
+                            };
+                            c.tcp_loads
+                                .push(crate::nettcp::TcpLoad::new(route, c.tcp_config.clone()));
+                        }
+                    }
+                }
+                let previous_tcp = c.tcp_config.clone();
+                ui.horizontal(|ui| {
+                    egui_multiwin::egui::ComboBox::from_label("Tcp direction")
+                        .selected_text(c.tcp_config.direction.to_string())
+                        .show_ui(ui, |ui| {
+                            for direction in [
+                                TcpDirection::Upload,
+                                TcpDirection::Download,
+                                TcpDirection::Bidirectional,
+                            ] {
+                                ui.selectable_value(
+                                    &mut c.tcp_config.direction,
+                                    direction,
+                                    direction.to_string(),
+                                );
+                            }
+                        });
+                    ui.label("Streams");
+                    ui.add(
+                        egui_multiwin::egui::DragValue::new(&mut c.tcp_config.streams)
+                            .clamp_range(1..=64),
+                    );
+                });
+                if c.tcp_config != previous_tcp {
+                    for tl in &mut c.tcp_loads {
+                        tl.configure(c.tcp_config.clone());
+                    }
+                }
+                for tl in &mut c.tcp_loads {
+                    ui.label(format!("Tcp load on {}: {}", tl.target(), tl.stats));
+                    ui.horizontal(|ui| {
                         if ui.button("Start").clicked() {
-                            nt.send.send(crate::netload::MessageToNetworkLoad::Start);
+                            tl.start();
                         }
                         if ui.button("Stop").clicked() {
-                            nt.send.send(crate::netload::MessageToNetworkLoad::Stop);
+                            tl.stop();
+                        }
+                    });
+                }
+                let previous_config = c.disk_config.clone();
+                egui_multiwin::egui::ComboBox::from_label("Disk access pattern")
+                    .selected_text(c.disk_config.pattern.to_string())
+                    .show_ui(ui, |ui| {
+                        for pattern in [DiskPattern::Sequential, DiskPattern::Random] {
+                            ui.selectable_value(
+                                &mut c.disk_config.pattern,
+                                pattern,
+                                pattern.to_string(),
+                            );
+                        }
+                    });
+                egui_multiwin::egui::ComboBox::from_label("Sync after each write")
+                    .selected_text(match c.disk_config.sync {
+                        Some(sync) => sync.to_string(),
+                        None => "none, measure reads".to_string(),
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut c.disk_config.sync, None, "none, measure reads");
+                        for sync in [DiskSync::Fsync, DiskSync::Fdatasync] {
+                            ui.selectable_value(
+                                &mut c.disk_config.sync,
+                                Some(sync),
+                                sync.to_string(),
+                            );
+                        }
+                    });
+                egui_multiwin::egui::ComboBox::from_label("Disk block size")
+                    .selected_text(crate::disk::format_size(c.disk_config.block_size as u64))
+                    .show_ui(ui, |ui| {
+                        for shift in [9, 12, 16, 20, 22] {
+                            let size = 1usize << shift;
+                            ui.selectable_value(
+                                &mut c.disk_config.block_size,
+                                size,
+                                crate::disk::format_size(size as u64),
+                            );
+                        }
+                    });
+                #[cfg(target_os = "linux")]
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut c.disk_config.direct, "Direct disk I/O");
+                    egui_multiwin::egui::ComboBox::from_label("Disk engine")
+                        .selected_text(c.disk_config.engine.to_string())
+                        .show_ui(ui, |ui| {
+                            for engine in [DiskEngine::Sync, DiskEngine::IoUring] {
+                                ui.selectable_value(
+                                    &mut c.disk_config.engine,
+                                    engine,
+                                    engine.to_string(),
+                                );
+                            }
+                        });
+                    if c.disk_config.engine == DiskEngine::IoUring {
+                        ui.label("Queue depth");
+                        ui.add(
+                            egui_multiwin::egui::DragValue::new(&mut c.disk_config.queue_depth)
+                                .clamp_range(1..=256),
+                        );
+                    }
+                    ui.label("Jobs");
+                    ui.add(
+                        egui_multiwin::egui::DragValue::new(&mut c.disk_config.jobs)
+                            .clamp_range(1..=64),
+                    );
+                });
+                if c.disk_config != previous_config {
+                    for dt in &mut c.disks {
+                        // Keep what a job file set that can not be changed here
+                        let config = DiskConfig {
+                            read_percent: dt.config.read_percent,
+                            size: dt.config.size,
+                            runtime: dt.config.runtime,
+                            time_based: dt.config.time_based,
+                            ..c.disk_config.clone()
+                        };
+                        dt.configure(safe_config(&dt.path, config));
+                    }
+                }
+                let mut add_disk = None;
+                let mut add_jobs = vec![];
+                egui_multiwin::egui::CollapsingHeader::new("Disk targets").show(ui, |ui| {
+                    let loaded = |p: &std::path::PathBuf| c.disks.iter().any(|d| &d.path == p);
+                    for d in &c.disk_targets {
+                        ui.horizontal(|ui| {
+                            ui.label(d.to_string());
+                            if ui
+                                .add_enabled(
+                                    !loaded(&d.path),
+                                    egui_multiwin::egui::Button::new("Load"),
+                                )
+                                .clicked()
+                            {
+                                add_disk = Some(d.path.clone());
+                            }
+                        });
+                        for p in &d.partitions {
+                            ui.horizontal(|ui| {
+                                ui.label(format!(
+                                    "    {} ({:.1} GB)",
+                                    p.path.display(),
+                                    p.size as f64 / 1e9
+                                ));
+                                if ui
+                                    .add_enabled(
+                                        !loaded(&p.path),
+                                        egui_multiwin::egui::Button::new("Load"),
+                                    )
+                                    .clicked()
+                                {
+                                    add_disk = Some(p.path.clone());
+                                }
+                            });
                         }
                     }
+                    ui.horizontal(|ui| {
+                        ui.label("Test file in directory");
+                        ui.text_edit_singleline(&mut c.test_dir);
+                        if ui.button("Load").clicked() && !c.test_dir.is_empty() {
+                            add_disk = Some(std::path::PathBuf::from(&c.test_dir));
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Job file");
+                        ui.text_edit_singleline(&mut c.job_file);
+                        if ui.button("Load").clicked() && !c.job_file.is_empty() {
+                            c.job_error = None;
+                            match crate::diskjob::load(std::path::Path::new(&c.job_file)) {
+                                Ok(jobs) => add_jobs = jobs,
+                                Err(e) => c.job_error = Some(e),
+                            }
+                        }
+                    });
+                    if let Some(e) = &c.job_error {
+                        ui.label(e);
+                    }
+                });
+                if let Some(p) = add_disk {
+                    let config = safe_config(&p, c.disk_config.clone());
+                    c.disks.push(crate::disk::DiskLoad::new(&p, config));
+                }
+                for job in add_jobs {
+                    // Only new or test files may be written from the gui, never a disk
+                    if job.config.writes() && !job.path.is_dir() {
+                        if let Err(e) = crate::diskverify::check_target(&job.path, false) {
+                            c.job_error = Some(format!("Job {}: {}", job.name, e));
+                            continue;
+                        }
+                    }
+                    c.disks
+                        .push(crate::disk::DiskLoad::new(&job.path, job.config));
                 }
                 for dt in &c.disks {
-                    if !dt.done {
+                    if !dt.done() {
                         ui.label(format!("There is a disk thread on {}", dt.path.display()));
                         ui.horizontal(|ui| {
                             if ui.button("Start").clicked() {
-                                dt.send.send(crate::disk::MessageToDiskLoad::Start);
+                                dt.start();
                             }
                             if ui.button("Stop").clicked() {
-                                dt.send.send(crate::disk::MessageToDiskLoad::Stop);
+                                dt.stop();
+                            }
+                            if ui.button("Remove").clicked() {
+                                dt.control().exit();
                             }
-                            ui.label(format!("Running {}", dt.running));
+                            ui.label(format!("Running {}", dt.running()));
                         });
-                        ui.label(format!("Performance: {}", dt.performance));
+                        let summary = Summary::from_samples(dt.control().samples());
+                        ui.label(format!(
+                            "Performance: {:.1} {} (average {:.1} peak {:.1}), {:.1} MB total",
+                            dt.performance(),
+                            dt.units(),
+                            summary.mean,
+                            summary.max,
+                            dt.stats.total_bytes as f64 / 1e6
+                        ));
+                        ui.label(format!(
+                            "{:.0} {}, mean latency {:.1} us, {}",
+                            dt.stats.iops,
+                            if dt.config.sync.is_some() {
+                                "syncs/s"
+                            } else {
+                                "IOPS"
+                            },
+                            dt.stats.latency.as_secs_f64() * 1e6,
+                            if dt.direct { "direct" } else { "buffered" }
+                        ));
+                        ui.label(format!("Latency: {}", dt.stats.percentiles));
+                        if let (Some(dev), Some(k)) = (&dt.kernel_device, dt.kernel.last()) {
+                            ui.label(format!("Kernel ({}): {}", dev, k));
+                        }
                     }
                 }
                 #[cfg(target_os = "linux")]
@@ -134,25 +495,58 @@ impl TrackedWindow<AppCommon> for RootWindow {
                         }
                     }
                 }
-                for thread in &mut c.cpu_threads {
+                let previous_isa = c.cpu_isa;
+                egui_multiwin::egui::ComboBox::from_label("CPU instruction set")
+                    .selected_text(c.cpu_isa.to_string())
+                    .show_ui(ui, |ui| {
+                        for isa in crate::cpu::Isa::ALL {
+                            if isa.supported() {
+                                ui.selectable_value(&mut c.cpu_isa, isa, isa.to_string());
+                            }
+                        }
+                    });
+                if c.cpu_isa != previous_isa {
+                    for t in &c.cpu_threads {
+                        t.set_isa(c.cpu_isa);
+                    }
+                }
+                let total: f64 = c
+                    .cpu_threads
+                    .iter()
+                    .filter(|t| t.running())
+                    .map(|t| t.performance())
+                    .sum();
+                ui.label(format!("Total CPU performance: {:.2} GFLOPS", total));
+                for thread in &c.cpu_threads {
+                    ui.label(format!(
+                        "CPU {} running {} {} {}",
+                        thread.core,
+                        thread.running(),
+                        thread.associated,
+                        thread.isa
+                    ));
+                    let summary = Summary::from_samples(thread.control().samples());
                     ui.label(format!(
-                        "CPU running {} {}",
-                        thread.running, thread.associated
+                        "Performance: {:.2} {} (min {:.2} mean {:.2} max {:.2})",
+                        thread.performance(),
+                        thread.units(),
+                        summary.min,
+                        summary.mean,
+                        summary.max
                     ));
-                    ui.label(format!("Performance: {}", thread.performance));
                     ui.horizontal(|ui| {
                         if ui.button("Start").clicked() {
-                            thread.send.send(crate::cpu::MessageToCpuLoad::Start);
+                            thread.start();
                         }
                         if ui.button("Stop").clicked() {
-                            thread.send.send(crate::cpu::MessageToCpuLoad::Stop);
+                            thread.stop();
                         }
                     });
                 }
                 if ui.button("Timed cpu load").clicked() {
                     let send = c.gui_send.clone();
-                    for t in &mut c.cpu_threads {
-                        let _e = t.send.send(crate::cpu::MessageToCpuLoad::Start);
+                    for t in &c.cpu_threads {
+                        t.start();
                     }
                     c.timer
                         .schedule_with_delay(chrono::Duration::milliseconds(5000), move || {
@@ -4,6 +4,7 @@ use egui_multiwin::{
     tracked_window::{RedrawResponse, TrackedWindow},
 };
 
+use crate::workload::Workload;
 use crate::AppCommon;
 
 pub struct RootWindow {}
@@ -54,17 +55,36 @@ impl TrackedWindow<AppCommon> for RootWindow {
 
         egui_multiwin::egui::CentralPanel::default().show(&egui.egui_ctx, |ui| {
             egui_multiwin::egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Port");
+                    ui.add(
+                        egui_multiwin::egui::DragValue::new(&mut c.port).clamp_range(1..=u16::MAX),
+                    );
+                });
                 for listener in &mut c.netlisteners {
-                    ui.label(format!("Listener {:?}", listener.addr));
+                    if listener.port != c.port {
+                        listener.set_port(c.port);
+                    }
+                    ui.label(format!(
+                        "Listener {:?} port {}",
+                        listener.addr, listener.port
+                    ));
                     ui.horizontal(|ui| {
                         if ui.button("Start").clicked() {
-                            listener.send.send(crate::MessageToNetworkListener::Start);
+                            listener.start();
                         }
                         if ui.button("Stop").clicked() {
-                            listener.send.send(crate::MessageToNetworkListener::Stop);
+                            listener.stop();
                         }
-                        ui.label(format!("Status: {} {}", listener.listening, listener.done));
+                        ui.label(format!(
+                            "Status: {} {}",
+                            listener.running(),
+                            listener.done()
+                        ));
                     });
+                    for client in &listener.clients {
+                        ui.label(client.to_string());
+                    }
                 }
                 for net in &c.networks {
                     for addr in &net.addr {
@@ -0,0 +1,358 @@
+//! The thread and message handling shared by all of the load generators.
+//!
+//! Every load runs in its own thread that is controlled with a pair of mpsc
+//! channels. [`WorkloadThread`] is the side owned by whoever drives the load
+//! (the gui or the headless runner), [`WorkerContext`] is the side owned by
+//! the load thread itself.
+
+use std::{
+    collections::BTreeMap,
+    sync::mpsc::{Receiver, Sender, TryRecvError},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::results::{Sample, Summary, WorkloadResult};
+
+/// Messages sent to a workload thread, `C` is for workload specific commands.
+pub enum MessageToWorkload<C> {
+    Start,
+    Stop,
+    Exit,
+    Custom(C),
+}
+
+/// Messages sent from a workload thread, `R` is for workload specific reports.
+pub enum MessageFromWorkload<R> {
+    Running(bool),
+    Performance(f64),
+    Custom(R),
+    Done,
+}
+
+/// The kind of resource that a workload exercises.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkloadKind {
+    Cpu,
+    Disk,
+    Network,
+    NetworkListener,
+}
+
+impl std::fmt::Display for WorkloadKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            WorkloadKind::Cpu => "cpu",
+            WorkloadKind::Disk => "disk",
+            WorkloadKind::Network => "network",
+            WorkloadKind::NetworkListener => "network listener",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The controlling side of a workload thread.
+pub struct WorkloadThread<C, R> {
+    thread: Option<JoinHandle<()>>,
+    recv: Receiver<MessageFromWorkload<R>>,
+    send: Sender<MessageToWorkload<C>>,
+    pub running: bool,
+    pub done: bool,
+    pub performance: f64,
+    /// Every performance report since the load was last started
+    pub samples: Vec<Sample>,
+    pub started: Option<DateTime<Utc>>,
+    pub stopped: Option<DateTime<Utc>>,
+}
+
+impl<C: Send + 'static, R: Send + 'static> WorkloadThread<C, R> {
+    /// Spawn a thread running `f`, which is given the other end of the channels.
+    pub fn spawn<F>(f: F) -> Self
+    where
+        F: FnOnce(WorkerContext<C, R>) + Send + 'static,
+    {
+        let (s, r) = std::sync::mpsc::channel();
+        let (s2, r2) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            f(WorkerContext {
+                recv: r,
+                send: s2,
+                running: false,
+            })
+        });
+        Self {
+            thread: Some(thread),
+            recv: r2,
+            send: s,
+            running: false,
+            done: false,
+            performance: 0.0,
+            samples: vec![],
+            started: None,
+            stopped: None,
+        }
+    }
+
+    /// Handle all pending messages from the thread, returning the workload specific ones.
+    pub fn process_messages(&mut self) -> Vec<R> {
+        let mut custom = vec![];
+        while let Ok(message) = self.recv.try_recv() {
+            match message {
+                MessageFromWorkload::Running(r) => {
+                    if r && !self.running {
+                        self.started = Some(Utc::now());
+                        self.stopped = None;
+                        self.samples.clear();
+                    } else if !r && self.running {
+                        self.stopped = Some(Utc::now());
+                    }
+                    self.running = r;
+                }
+                MessageFromWorkload::Performance(p) => {
+                    self.performance = p;
+                    if let Some(start) = self.started {
+                        let time = (Utc::now() - start).num_milliseconds() as f64 / 1000.0;
+                        self.samples.push(Sample { time, value: p });
+                    }
+                }
+                MessageFromWorkload::Custom(c) => {
+                    custom.push(c);
+                }
+                MessageFromWorkload::Done => {
+                    if self.running {
+                        self.stopped = Some(Utc::now());
+                    }
+                    self.done = true;
+                    self.running = false;
+                }
+            }
+        }
+        custom
+    }
+
+    /// Send a workload specific command to the thread.
+    pub fn send(&self, command: C) {
+        let _e = self.send.send(MessageToWorkload::Custom(command));
+    }
+}
+
+/// The operations that every workload thread supports, regardless of its messages.
+pub trait WorkloadControl {
+    fn start(&self);
+    fn stop(&self);
+    fn exit(&self);
+    fn running(&self) -> bool;
+    fn done(&self) -> bool;
+    fn performance(&self) -> f64;
+    fn samples(&self) -> &[Sample];
+    fn started(&self) -> Option<DateTime<Utc>>;
+    fn stopped(&self) -> Option<DateTime<Utc>>;
+    /// Stop the thread and wait for it to finish. What it sent on the way out
+    /// is left for [`WorkloadThread::process_messages`].
+    fn end_and_wait(&mut self);
+}
+
+impl<C: Send + 'static, R: Send + 'static> WorkloadControl for WorkloadThread<C, R> {
+    fn start(&self) {
+        let _e = self.send.send(MessageToWorkload::Start);
+    }
+
+    fn stop(&self) {
+        let _e = self.send.send(MessageToWorkload::Stop);
+    }
+
+    fn exit(&self) {
+        let _e = self.send.send(MessageToWorkload::Exit);
+    }
+
+    fn running(&self) -> bool {
+        self.running
+    }
+
+    fn done(&self) -> bool {
+        self.done
+    }
+
+    fn performance(&self) -> f64 {
+        self.performance
+    }
+
+    fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    fn started(&self) -> Option<DateTime<Utc>> {
+        self.started
+    }
+
+    fn stopped(&self) -> Option<DateTime<Utc>> {
+        self.stopped
+    }
+
+    fn end_and_wait(&mut self) {
+        self.stop();
+        self.exit();
+        if let Some(thread) = self.thread.take() {
+            let _e = thread.join();
+        }
+        self.done = true;
+    }
+}
+
+/// The load thread side of a [`WorkloadThread`].
+pub struct WorkerContext<C, R> {
+    recv: Receiver<MessageToWorkload<C>>,
+    send: Sender<MessageFromWorkload<R>>,
+    running: bool,
+}
+
+impl<C, R> WorkerContext<C, R> {
+    /// Handle all pending messages, passing workload specific commands to
+    /// `custom`. Any report returned by `custom` is sent back. Returns false
+    /// when the thread should exit.
+    pub fn poll<F: FnMut(C) -> Option<R>>(&mut self, mut custom: F) -> bool {
+        loop {
+            let message = match self.recv.try_recv() {
+                Ok(m) => m,
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            };
+            match message {
+                MessageToWorkload::Start => {
+                    self.running = true;
+                    if !self.message(MessageFromWorkload::Running(true)) {
+                        return false;
+                    }
+                }
+                MessageToWorkload::Stop => {
+                    self.running = false;
+                    if !self.message(MessageFromWorkload::Running(false)) {
+                        return false;
+                    }
+                }
+                MessageToWorkload::Exit => return false,
+                MessageToWorkload::Custom(c) => {
+                    if let Some(r) = custom(c) {
+                        if !self.report(r) {
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Is the load supposed to be running right now.
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// Report the current performance. Returns false if nobody is listening anymore.
+    pub fn performance(&self, performance: f64) -> bool {
+        self.message(MessageFromWorkload::Performance(performance))
+    }
+
+    /// Send a workload specific report. Returns false if nobody is listening anymore.
+    pub fn report(&self, report: R) -> bool {
+        self.message(MessageFromWorkload::Custom(report))
+    }
+
+    /// Sleep while the load is not running.
+    pub fn idle(&self) {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    fn message(&self, m: MessageFromWorkload<R>) -> bool {
+        self.send.send(m).is_ok()
+    }
+}
+
+impl<C, R> Drop for WorkerContext<C, R> {
+    fn drop(&mut self) {
+        let _e = self.send.send(MessageFromWorkload::Done);
+    }
+}
+
+/// Implemented by every load generator so that the gui, the headless runner
+/// and tests can drive any of them the same way.
+pub trait Workload {
+    fn kind(&self) -> WorkloadKind;
+    /// What is being loaded, such as the core, disk or interface.
+    fn target(&self) -> String;
+    /// The units that [`Workload::performance`] is measured in.
+    fn units(&self) -> &'static str;
+    fn control(&self) -> &dyn WorkloadControl;
+    fn control_mut(&mut self) -> &mut dyn WorkloadControl;
+    /// Handle messages from the load thread, this must be called periodically.
+    fn process_messages(&mut self);
+
+    /// Is the workload able to start, some workloads have to find a peer first.
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn start(&self) {
+        self.control().start();
+    }
+
+    fn stop(&self) {
+        self.control().stop();
+    }
+
+    fn running(&self) -> bool {
+        self.control().running()
+    }
+
+    fn done(&self) -> bool {
+        self.control().done()
+    }
+
+    fn performance(&self) -> f64 {
+        self.control().performance()
+    }
+
+    /// Stop the load and wait for its thread to finish, handling anything it
+    /// reported on the way out such as final totals.
+    fn end_and_wait(&mut self) {
+        self.control_mut().end_and_wait();
+        self.process_messages();
+    }
+
+    /// Why the load failed, if it did.
+    fn error(&self) -> Option<String> {
+        None
+    }
+
+    /// Figures specific to the kind of workload, such as totals, that are
+    /// included in the results.
+    fn details(&self) -> BTreeMap<String, f64> {
+        BTreeMap::new()
+    }
+
+    /// Other measurements sampled over time alongside the performance, such
+    /// as what the kernel saw on a disk, that are included in the results.
+    fn series(&self) -> BTreeMap<String, Vec<Sample>> {
+        BTreeMap::new()
+    }
+
+    /// The results of the most recent run, if the workload has been started.
+    fn result(&self) -> Option<WorkloadResult> {
+        let c = self.control();
+        let start = c.started()?;
+        Some(WorkloadResult {
+            kind: self.kind(),
+            target: self.target(),
+            units: self.units().to_string(),
+            start,
+            end: c.stopped().unwrap_or_else(Utc::now),
+            samples: c.samples().to_vec(),
+            summary: Summary::from_samples(c.samples()),
+            details: self.details(),
+            series: self.series(),
+        })
+    }
+}